@@ -20,7 +20,7 @@ pub trait FromValue {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     BString(Vec<u8>),
-    Integer(i32),
+    Integer(i64),
     List(Vec<Value>),
     Dict(HashMap<Vec<u8>, Value>),
 }
@@ -52,6 +52,14 @@ impl Value {
         Ok(val)
     }
 
+    /// Decodes the value at the start of bytes, for when it is followed by other data.  Returns the
+    /// value and how many bytes it took up
+    pub fn decode_prefix(bytes: &[u8]) -> Result<(Value, usize), DecodeError> {
+        let mut rest: Vec<u8> = Vec::from(bytes);
+        let val = parse_val(&mut rest)?;
+        Ok((val, bytes.len() - rest.len()))
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         match self {
             Value::BString(bytes) => {
@@ -87,7 +95,7 @@ impl Value {
         }
     }
 
-    pub fn integer(&self) -> Option<&i32> {
+    pub fn integer(&self) -> Option<&i64> {
         if let Value::Integer(i) = self {
             return Some(i);
         }
@@ -2,6 +2,7 @@
 use crate::boostencode::compare_bytes_slice;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::str::FromStr;
 use super::Value;
 use super::DecodeError;
@@ -10,11 +11,11 @@ use super::DecodeError;
 mod test;
 
 pub fn parse_val(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    match bytes[0] as char {
-        'i' => parse_integer(bytes),
-        'l' => parse_list(bytes),
-        'd' => parse_dict(bytes),
-        '0'...'9' => parse_bstring(bytes),
+    match bytes.first().map(|b| *b as char) {
+        Some('i') => parse_integer(bytes),
+        Some('l') => parse_list(bytes),
+        Some('d') => parse_dict(bytes),
+        Some('0'...'9') => parse_bstring(bytes),
         _ => return Err(DecodeError::InvalidValue)
     }
 }
@@ -22,9 +23,10 @@ pub fn parse_val(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
 // assured of a bstring, we take it off the stack of bytes and return it
 fn parse_bstring(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
     let len = parse_integer_literal(bytes)?;
-    if bytes.remove(0) as char != ':' {
+    if bytes.first() != Some(&b':') || bytes.len() - 1 < len {
         return Err(DecodeError::InvalidString);
     }
+    bytes.remove(0);
 
     let bstring: Vec<u8> = bytes.drain(0..len).collect();
 
@@ -36,13 +38,13 @@ fn parse_integer(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
         return Err(DecodeError::InvalidInteger);
     }
 
-    let is_negative = bytes[0] as char == '-';
+    let is_negative = bytes.first() == Some(&b'-');
     if is_negative {
         bytes.remove(0);
     }
 
-    if bytes[0] as char == '0' {
-        if is_negative || bytes[1] as char != 'e' {
+    if bytes.first() == Some(&b'0') {
+        if is_negative || bytes.get(1) != Some(&b'e') {
             return Err(DecodeError::InvalidInteger);
         }
 
@@ -51,11 +53,14 @@ fn parse_integer(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
         return Ok(Value::Integer(0));
     }
 
-    let num = parse_integer_literal(bytes)? as i32;
+    // Wide enough for sizes past 4 GiB, but anything bigger than an i64 is an error rather than
+    // wrapping around
+    let num = i64::try_from(parse_integer_literal(bytes)?).map_err(|_| DecodeError::InvalidInteger)?;
 
-    if bytes.remove(0) as char != 'e' {
+    if bytes.first() != Some(&b'e') {
         return Err(DecodeError::InvalidInteger);
     }
+    bytes.remove(0);
 
     Ok(Value::Integer(if is_negative { -num } else { num }))
 }
@@ -66,13 +71,13 @@ fn parse_list(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
         return Err(DecodeError::InvalidList);
     }
 
-    while bytes[0] as char != 'e' {
+    while bytes.first() != Some(&b'e') {
+        if bytes.is_empty() {
+            return Err(DecodeError::InvalidList);
+        }
         list.push(parse_val(bytes)?)
     }
-
-    if bytes.remove(0) as char != 'e' {
-        return Err(DecodeError::InvalidList);
-    }
+    bytes.remove(0);
 
     Ok(Value::List(list))
 }
@@ -86,7 +91,10 @@ fn parse_dict(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
 
     let mut last_key: Option<Vec<u8>> = None;
 
-    while bytes[0] as char != 'e' {
+    while bytes.first() != Some(&b'e') {
+        if bytes.is_empty() {
+            return Err(DecodeError::InvalidDict);
+        }
         let key = parse_bstring(bytes)?;
         let val = parse_val(bytes)?;
 
@@ -104,9 +112,7 @@ fn parse_dict(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
         }
     }
 
-    if bytes.remove(0) as char != 'e' {
-        return Err(DecodeError::InvalidDict);
-    }
+    bytes.remove(0);
 
     Ok(Value::Dict(map))
 }
@@ -35,6 +35,17 @@ fn test_parse_integer() {
     assert_eq!(0, s3.len());
 }
 
+#[test]
+fn test_parse_integer_wide() {
+    let mut big = "i6000000000e".to_string().into_bytes();
+    let mut negative = "i-9223372036854775807e".to_string().into_bytes();
+    let mut too_big = "i9223372036854775808e".to_string().into_bytes();
+
+    assert_eq!(parse_integer(big.as_mut()), Ok(Value::Integer(6_000_000_000)));
+    assert_eq!(parse_integer(negative.as_mut()), Ok(Value::Integer(-i64::MAX)));
+    assert_eq!(parse_integer(too_big.as_mut()), Err(DecodeError::InvalidInteger));
+}
+
 #[test]
 fn test_parse_integer_negative_zero() {
     let mut s1 = "i-0e".to_string().into_bytes();
@@ -72,4 +83,12 @@ fn test_parses_dict() {
 fn test_parse_dict_not_ascending() {
     let mut s1 = "d5:worldi1e5:helloi2ee".to_string().into_bytes();
     assert_eq!(parse_dict(s1.as_mut()), Err(DecodeError::InvalidDict));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_truncated() {
+    for truncated in &["", "i12", "i-", "5:abc", "5", "l4:spam", "d5:hello", "d5:helloi1e"] {
+        let mut bytes = truncated.to_string().into_bytes();
+        assert!(parse_val(&mut bytes).is_err(), "{} should not parse", truncated);
+    }
+}
@@ -1,3 +1,4 @@
+use maplit::hashmap;
 use std::str;
 use super::*;
 
@@ -22,6 +23,15 @@ fn test_encode() {
     assert_eq!("d5:hellol4:spami100ee4:spami100ee", str::from_utf8(v4.encode().as_ref()).unwrap());
 }
 
+#[test]
+fn test_integer_round_trip() {
+    // file sizes and transfer totals past 2 GiB, as in resume files and large torrents
+    for &i in &[i64::from(i32::MAX) + 1, 6_000_000_000, -6_000_000_000, i64::MAX] {
+        let value = Value::Integer(i);
+        assert_eq!(Value::decode(&value.encode()), Ok(value));
+    }
+}
+
 #[test]
 fn test_compare_bstring() {
     let v1 = vec![0, 1, 2, 3];
@@ -38,4 +48,14 @@ fn test_compare_bstring() {
     assert_eq!(Ordering::Greater, compare_bytes_slice(v4.as_ref(), v3.as_ref()));
     assert_eq!(Ordering::Less, compare_bytes_slice(vs.as_ref(), vl.as_ref()));
     assert_eq!(Ordering::Greater, compare_bytes_slice(vl.as_ref(), vs.as_ref()));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_decode_prefix() {
+    let bytes = b"d1:ai1eeextra data";
+    let (val, length) = Value::decode_prefix(bytes).unwrap();
+    assert_eq!(val, Value::Dict(hashmap! { Vec::from("a") => Value::Integer(1) }));
+    assert_eq!(length, 8);
+    assert_eq!(Value::decode(bytes), Err(DecodeError::InvalidValue));
+    assert!(Value::decode_prefix(b"d1:ai1e").is_err());
+}
@@ -0,0 +1,70 @@
+//! choker decides which peers we upload to, using the tit-for-tat algorithm from BEP 3
+use rand::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+#[cfg(test)]
+mod test;
+
+/// The optimistic unchoke moves to a new peer every this many rounds
+const OPTIMISTIC_ROUNDS: usize = 3;
+
+/// What the choker needs to know about a peer for one round
+pub struct PeerStats {
+    pub address: SocketAddr,
+    // Whether the peer wants to download from us
+    pub interested: bool,
+    // Bytes transferred since the last round.  Downloaded from the peer while we are leeching,
+    // uploaded to the peer once we are seeding
+    pub rate: u64,
+}
+
+pub struct Choker {
+    // How many peers are unchoked for their rate, not counting the optimistic unchoke
+    slots: usize,
+    round: usize,
+    // A peer unchoked regardless of its rate, so new peers get a chance to prove themselves
+    optimistic: Option<SocketAddr>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            round: 0,
+            optimistic: None,
+        }
+    }
+
+    /// Runs a round of the choking algorithm, returning the peers that should be unchoked.  Every
+    /// other peer should be choked
+    pub fn rechoke(&mut self, peers: &[PeerStats]) -> HashSet<SocketAddr> {
+        let mut interested: Vec<&PeerStats> = peers.iter().filter(|p| p.interested).collect();
+        interested.sort_by_key(|p| Reverse(p.rate));
+        let mut unchoked: HashSet<SocketAddr> = interested.iter()
+            .take(self.slots)
+            .map(|p| p.address)
+            .collect();
+
+        let optimistic_valid = self.optimistic
+            .is_some_and(|o| interested.iter().any(|p| p.address == o) && !unchoked.contains(&o));
+        if self.round.is_multiple_of(OPTIMISTIC_ROUNDS) || !optimistic_valid {
+            let candidates: Vec<SocketAddr> = interested.iter()
+                .map(|p| p.address)
+                .filter(|a| !unchoked.contains(a))
+                .collect();
+            self.optimistic = if candidates.is_empty() {
+                None
+            } else {
+                Some(candidates[thread_rng().gen_range(0, candidates.len())])
+            };
+        }
+        if let Some(optimistic) = self.optimistic {
+            unchoked.insert(optimistic);
+        }
+
+        self.round += 1;
+        unchoked
+    }
+}
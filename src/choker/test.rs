@@ -0,0 +1,59 @@
+use super::*;
+
+fn peer(port: u16, interested: bool, rate: u64) -> PeerStats {
+    PeerStats {
+        address: ([127, 0, 0, 1], port).into(),
+        interested,
+        rate,
+    }
+}
+
+fn addr(port: u16) -> SocketAddr {
+    ([127, 0, 0, 1], port).into()
+}
+
+#[test]
+fn test_unchokes_fastest_interested_peers() {
+    let mut choker = Choker::new(2);
+    let peers = vec![
+        peer(1, true, 10),
+        peer(2, true, 30),
+        peer(3, false, 100),
+        peer(4, true, 20),
+    ];
+
+    // 2 and 4 are fastest, 1 is the only one left for the optimistic unchoke
+    let unchoked = choker.rechoke(&peers);
+    assert_eq!(unchoked, [addr(1), addr(2), addr(4)].iter().cloned().collect());
+}
+
+#[test]
+fn test_optimistic_unchoke_rotates() {
+    let mut choker = Choker::new(1);
+    let peers: Vec<PeerStats> = (1..10).map(|port| peer(port, true, if port == 1 { 100 } else { 0 })).collect();
+
+    let first = choker.rechoke(&peers);
+    assert_eq!(first.len(), 2);
+    assert!(first.contains(&addr(1)));
+    let optimistic = choker.optimistic.unwrap();
+
+    // the optimistic unchoke stays put between rotations
+    for _ in 1..OPTIMISTIC_ROUNDS {
+        assert!(choker.rechoke(&peers).contains(&optimistic));
+    }
+
+    // but moves if the peer loses interest
+    let peers: Vec<PeerStats> = (1..10).map(|port| {
+        peer(port, addr(port) != optimistic, if port == 1 { 100 } else { 0 })
+    }).collect();
+    let unchoked = choker.rechoke(&peers);
+    assert_eq!(unchoked.len(), 2);
+    assert!(!unchoked.contains(&optimistic));
+}
+
+#[test]
+fn test_nobody_interested() {
+    let mut choker = Choker::new(4);
+    assert!(choker.rechoke(&[peer(1, false, 10)]).is_empty());
+    assert_eq!(choker.optimistic, None);
+}
@@ -0,0 +1,245 @@
+//! create builds .torrent files out of files on disk
+use crate::metainfo::{
+    FileInfo,
+    InfoDict,
+    MetaInfo,
+    MultiFile,
+    sha1_hash,
+    SingleFile,
+};
+use derive_error::Error;
+use log::warn;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    Read,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+#[cfg(test)]
+mod test;
+
+/// The smallest piece length allowed, one block
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+/// The largest piece length we will pick on our own
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Pieces are made bigger until there are about this many, to keep the .torrent file small
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Debug, Error)]
+pub enum CreateError {
+    /// Could not read the files
+    Io(io::Error),
+    /// A file name can't be stored in a torrent
+    #[error(msg_embedded, non_std, no_from)]
+    InvalidPath(String),
+    /// There is nothing to share
+    NoFiles,
+    /// The piece length has to be a power of two, and at least 16 KiB
+    InvalidPieceLength,
+}
+
+/// Everything about a new torrent other than its contents
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Tiers of tracker uris.  The first one is also the torrent's announce.  With none, peers are
+    // found through the DHT
+    pub trackers: Vec<Vec<String>>,
+    // The number of bytes in each piece.  Picked from the total size if None
+    pub piece_length: Option<usize>,
+    // Free-form textual comments of the author
+    pub comment: Option<String>,
+    // Name and version of the program creating the torrent
+    pub created_by: Option<String>,
+    // If true, peers may only be found through the trackers
+    pub private: bool,
+    // Whether to record when the torrent was created
+    pub creation_date: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            trackers: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: Some(format!("boosttorrent2/{}", env!("CARGO_PKG_VERSION"))),
+            private: false,
+            creation_date: true,
+        }
+    }
+}
+
+/// A file to be hashed, and its name in the torrent
+struct SourceFile {
+    name: String,
+    path: PathBuf,
+    length: u64,
+}
+
+/// Builds the metainfo for the file or directory at path, hashing everything in it.  Files in a
+/// directory are added in order of their paths
+pub fn create(path: &Path, options: &Options) -> Result<MetaInfo, CreateError> {
+    let tiers: Vec<Vec<String>> = options.trackers.iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+    let announce = tiers.first().map(|tier| tier[0].clone());
+
+    let path = fs::canonicalize(path)?;
+    let name = utf8_name(&path)?;
+    let (file_info, sources) = if fs::metadata(&path)?.is_dir() {
+        let mut sources = Vec::new();
+        walk(&path, "", &mut sources)?;
+        let files = sources.iter()
+            .map(|source| SingleFile {
+                file_name: source.name.clone(),
+                length: source.length as usize,
+                md5sum: None,
+            })
+            .collect();
+        (FileInfo::Multi(MultiFile { root_dir_name: name, files }), sources)
+    } else {
+        let length = fs::metadata(&path)?.len();
+        let file = SingleFile { file_name: name.clone(), length: length as usize, md5sum: None };
+        (FileInfo::Single(file), vec![SourceFile { name, path, length }])
+    };
+    if file_info.size() == 0 {
+        return Err(CreateError::NoFiles);
+    }
+
+    let piece_length = match options.piece_length {
+        Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => {
+            return Err(CreateError::InvalidPieceLength);
+        }
+        Some(length) => length,
+        None => pick_piece_length(file_info.size()),
+    };
+    let info = InfoDict {
+        piece_length,
+        pieces: hash_pieces(&sources, piece_length)?,
+        private: options.private,
+        file_info,
+    };
+
+    // announce-list is only needed when there is more than the one tracker
+    let announce_list = if tiers.len() > 1 || tiers.first().is_some_and(|tier| tier.len() > 1) {
+        Some(tiers.iter()
+            .enumerate()
+            .flat_map(|(i, tier)| tier.iter().map(move |uri| (i, uri.clone())))
+            .collect())
+    } else {
+        None
+    };
+    let creation_date = if options.creation_date {
+        SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|time| time.as_secs())
+    } else {
+        None
+    };
+
+    let info_bytes = info.to_value().encode();
+    Ok(MetaInfo {
+        info_hash: sha1_hash(&info_bytes),
+        info_bytes,
+        info,
+        announce,
+        announce_list,
+        nodes: Vec::new(),
+        creation_date,
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        encoding: None,
+    })
+}
+
+/// Picks the smallest power of two piece length that keeps the number of pieces near the target
+pub fn pick_piece_length(total: usize) -> usize {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total / piece_length > TARGET_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// The last component of a path, which has to be UTF-8 to be put in a torrent
+fn utf8_name(path: &Path) -> Result<String, CreateError> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| CreateError::InvalidPath(format!("{} has no UTF-8 name", path.display())))
+}
+
+/// Adds every file under dir to sources, named by their path from the torrent's root
+fn walk(dir: &Path, prefix: &str, sources: &mut Vec<SourceFile>) -> Result<(), CreateError> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let name = format!("{}{}", prefix, utf8_name(&path)?);
+        let mut metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            // Links to files are fine, but following one to a directory could loop forever
+            match fs::metadata(&path) {
+                Ok(target) if target.is_file() => metadata = target,
+                _ => {
+                    warn!("Skipping symbolic link {}", path.display());
+                    continue;
+                }
+            }
+        }
+        if metadata.is_dir() {
+            walk(&path, &format!("{}/", name), sources)?;
+        } else if metadata.is_file() {
+            sources.push(SourceFile { name, path, length: metadata.len() });
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the files as one contiguous stream, so pieces can span file boundaries
+fn hash_pieces(sources: &[SourceFile], piece_length: usize) -> Result<Vec<String>, CreateError> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for source in sources {
+        // Only read as much as we put in the torrent, in case the file is growing
+        let mut file = File::open(&source.path)?.take(source.length);
+        let mut read_total = 0;
+        loop {
+            let filled = piece.len();
+            piece.resize(piece_length, 0);
+            let read = file.read(&mut piece[filled..])?;
+            piece.truncate(filled + read);
+            if read == 0 {
+                break;
+            }
+            read_total += read as u64;
+            if piece.len() == piece_length {
+                pieces.push(hex(&sha1_hash(&piece)));
+                piece.clear();
+            }
+        }
+        if read_total != source.length {
+            let msg = format!("{} changed while it was being hashed", source.path.display());
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg).into());
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(hex(&sha1_hash(&piece)));
+    }
+    Ok(pieces)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
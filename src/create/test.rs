@@ -0,0 +1,109 @@
+use crate::boostencode::{FromValue, Value};
+use crate::testutil::temp_dir;
+use super::*;
+
+fn options() -> Options {
+    Options {
+        trackers: vec![vec!["http://example.com/announce".to_string()]],
+        piece_length: Some(MIN_PIECE_LENGTH),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn test_create_multi_file() {
+    let dir = temp_dir("create");
+    let root = dir.join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let first: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+    let second: Vec<u8> = (0..30_000).map(|i| (i / 7) as u8).collect();
+    fs::write(root.join("sub").join("b"), &second).unwrap();
+    fs::write(root.join("a"), &first).unwrap();
+
+    let mut options = options();
+    options.trackers.push(vec!["udp://example.com:80".to_string(), "udp://example.org:80".to_string()]);
+    options.private = true;
+    let meta = create(&root, &options).unwrap();
+
+    // pieces run straight across the boundary between the files
+    let data = [first, second].concat();
+    let pieces: Vec<String> = data.chunks(MIN_PIECE_LENGTH).map(|piece| hex(&sha1_hash(piece))).collect();
+    assert_eq!(meta.info.pieces.len(), 4);
+    assert_eq!(meta.info.pieces, pieces);
+    assert_eq!(meta.info.file_info, FileInfo::Multi(MultiFile {
+        root_dir_name: "root".to_string(),
+        files: vec![
+            SingleFile { file_name: "a".to_string(), length: 20_000, md5sum: None },
+            SingleFile { file_name: "sub/b".to_string(), length: 30_000, md5sum: None },
+        ],
+    }));
+    assert_eq!(meta.announce, Some("http://example.com/announce".to_string()));
+    assert_eq!(meta.tiers().len(), 2);
+
+    // reading the encoded file back gives the same torrent
+    let decoded = Value::decode(&meta.to_value().encode()).unwrap();
+    assert_eq!(MetaInfo::from_value(&decoded), Ok(meta));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_create_single_file() {
+    let dir = temp_dir("create");
+    let path = dir.join("file.bin");
+    fs::write(&path, vec![7; 100]).unwrap();
+
+    let meta = create(&path, &options()).unwrap();
+    assert_eq!(meta.info.pieces, vec![hex(&sha1_hash(&[7; 100]))]);
+    assert_eq!(meta.info.file_info, FileInfo::Single(SingleFile {
+        file_name: "file.bin".to_string(),
+        length: 100,
+        md5sum: None,
+    }));
+    assert_eq!(meta.announce_list, None);
+
+    // without trackers the torrent is left to the DHT
+    let trackerless = create(&path, &Options { trackers: vec![], ..options() }).unwrap();
+    assert_eq!(trackerless.announce, None);
+    assert!(trackerless.tiers().is_empty());
+    let decoded = Value::decode(&trackerless.to_value().encode()).unwrap();
+    assert_eq!(MetaInfo::from_value(&decoded), Ok(trackerless));
+    let odd_length = Options { piece_length: Some(20_000), ..options() };
+    match create(&path, &odd_length) {
+        Err(CreateError::InvalidPieceLength) => (),
+        other => panic!("expected InvalidPieceLength, got {:?}", other),
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_pick_piece_length() {
+    assert_eq!(pick_piece_length(1000), MIN_PIECE_LENGTH);
+    assert_eq!(pick_piece_length(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+    assert_eq!(pick_piece_length(usize::MAX), MAX_PIECE_LENGTH);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_create_skips_directory_links() {
+    use std::os::unix::fs::symlink;
+    let dir = temp_dir("create");
+    let root = dir.join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub").join("a"), vec![1; 100]).unwrap();
+    // a link back up the tree would have us walking in circles
+    symlink(&root, root.join("sub").join("loop")).unwrap();
+    symlink(root.join("sub").join("a"), root.join("b")).unwrap();
+
+    let meta = create(&root, &options()).unwrap();
+    assert_eq!(meta.info.file_info, FileInfo::Multi(MultiFile {
+        root_dir_name: "root".to_string(),
+        files: vec![
+            SingleFile { file_name: "b".to_string(), length: 100, md5sum: None },
+            SingleFile { file_name: "sub/a".to_string(), length: 100, md5sum: None },
+        ],
+    }));
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,243 @@
+//! The KRPC protocol DHT nodes talk to each other with: bencoded queries, responses and errors
+//! sent over UDP
+use crate::boostencode::Value;
+use crate::tracker::{
+    compact_peer,
+    parse_compact_peers,
+    parse_compact_peers6,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Node ids and info hashes share the same 160 bit space
+pub type NodeId = [u8; 20];
+
+/// Something went wrong that doesn't fit the other codes
+pub const GENERIC_ERROR: i64 = 201;
+/// The query was malformed, or its token was wrong
+pub const PROTOCOL_ERROR: i64 = 203;
+
+/// A node and where to reach it
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+/// A request for another node to do something
+#[derive(Debug, PartialEq, Clone)]
+pub enum Query {
+    /// Asks whether the node is still there
+    Ping,
+    /// Asks for the nodes closest to a target id
+    FindNode(NodeId),
+    /// Asks for peers of the torrent with the info hash, or the nodes closest to it
+    GetPeers([u8; 20]),
+    /// Tells the node we are a peer of the torrent, using the token it gave us in get_peers.  If
+    /// implied_port is set, the port the query was sent from is used instead of port
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+/// The answer to a query.  Which keys are filled in depends on the query, which isn't repeated in
+/// the response
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Response {
+    pub id: NodeId,
+    // The nodes closest to the target, from find_node and get_peers
+    pub nodes: Vec<NodeInfo>,
+    // Peers of the torrent, from get_peers
+    pub values: Vec<SocketAddr>,
+    // What to announce_peer with, from get_peers
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Body {
+    /// A query, with the id of the node sending it
+    Query(NodeId, Query),
+    Response(Response),
+    /// The query failed, with an error code and message
+    Error(i64, String),
+}
+
+/// A single KRPC message.  Responses carry the transaction id of the query they answer
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+fn bytes(s: &str) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
+
+/// Reads a 20 byte id
+fn node_id(val: Option<&Value>, key: &str) -> Result<NodeId, String> {
+    let bytes = val.and_then(Value::bstring)
+        .filter(|bytes| bytes.len() == 20)
+        .ok_or(format!("Missing key: {}", key))?;
+    let mut id = [0; 20];
+    id.copy_from_slice(bytes);
+    Ok(id)
+}
+
+/// Writes nodes in the compact format: each id followed by its compact address
+pub fn compact_nodes<'a, I: IntoIterator<Item=&'a NodeInfo>>(nodes: I) -> Vec<u8> {
+    nodes.into_iter()
+        .flat_map(|node| node.id.iter().cloned().chain(compact_peer(&node.address)))
+        .collect()
+}
+
+/// Parses compact nodes with addresses of ip_length bytes
+pub fn parse_compact_nodes(nodes: &[u8], ip_length: usize) -> Result<Vec<NodeInfo>, String> {
+    let chunks = nodes.chunks_exact(20 + ip_length + 2);
+    if !chunks.remainder().is_empty() {
+        return Err(format!("Compact node list length {} is not a multiple of {}", nodes.len(), 20 + ip_length + 2));
+    }
+    chunks
+        .map(|chunk| {
+            let peers = if ip_length == 4 {
+                parse_compact_peers(&chunk[20..])?
+            } else {
+                parse_compact_peers6(&chunk[20..])?
+            };
+            let mut id = [0; 20];
+            id.copy_from_slice(&chunk[..20]);
+            Ok(NodeInfo { id, address: peers[0].address })
+        })
+        .collect()
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut map = HashMap::new();
+        map.insert(bytes("t"), Value::BString(self.transaction.clone()));
+        match &self.body {
+            Body::Query(id, query) => {
+                let mut args = HashMap::new();
+                args.insert(bytes("id"), Value::BString(id.to_vec()));
+                let method = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode(target) => {
+                        args.insert(bytes("target"), Value::BString(target.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers(info_hash) => {
+                        args.insert(bytes("info_hash"), Value::BString(info_hash.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                        args.insert(bytes("info_hash"), Value::BString(info_hash.to_vec()));
+                        args.insert(bytes("port"), Value::Integer(i64::from(*port)));
+                        args.insert(bytes("implied_port"), Value::Integer(if *implied_port { 1 } else { 0 }));
+                        args.insert(bytes("token"), Value::BString(token.clone()));
+                        "announce_peer"
+                    }
+                };
+                map.insert(bytes("y"), Value::BString(bytes("q")));
+                map.insert(bytes("q"), Value::BString(bytes(method)));
+                map.insert(bytes("a"), Value::Dict(args));
+            }
+            Body::Response(response) => {
+                let mut values = HashMap::new();
+                values.insert(bytes("id"), Value::BString(response.id.to_vec()));
+                let v4 = response.nodes.iter().filter(|node| node.address.is_ipv4());
+                let v6 = response.nodes.iter().filter(|node| node.address.is_ipv6());
+                if v4.clone().next().is_some() {
+                    values.insert(bytes("nodes"), Value::BString(compact_nodes(v4)));
+                }
+                if v6.clone().next().is_some() {
+                    values.insert(bytes("nodes6"), Value::BString(compact_nodes(v6)));
+                }
+                if !response.values.is_empty() {
+                    let peers = response.values.iter().map(|peer| Value::BString(compact_peer(peer))).collect();
+                    values.insert(bytes("values"), Value::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(bytes("token"), Value::BString(token.clone()));
+                }
+                map.insert(bytes("y"), Value::BString(bytes("r")));
+                map.insert(bytes("r"), Value::Dict(values));
+            }
+            Body::Error(code, msg) => {
+                map.insert(bytes("y"), Value::BString(bytes("e")));
+                map.insert(bytes("e"), Value::List(vec![Value::Integer(*code), Value::BString(bytes(msg))]));
+            }
+        }
+        Value::Dict(map).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("Invalid KRPC message: {}", e))?;
+        let map = val.dict().ok_or("KRPC message not a dictionary".to_string())?;
+        let transaction = map.get("t".as_bytes()).and_then(Value::bstring)
+            .ok_or("Missing key: t".to_string())?
+            .clone();
+
+        let body = match map.get("y".as_bytes()).and_then(Value::bstring).map(Vec::as_slice) {
+            Some(b"q") => {
+                let args = map.get("a".as_bytes()).and_then(Value::dict)
+                    .ok_or("Missing key: a".to_string())?;
+                let id = node_id(args.get("id".as_bytes()), "id")?;
+                let query = match map.get("q".as_bytes()).and_then(Value::bstring).map(Vec::as_slice) {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode(node_id(args.get("target".as_bytes()), "target")?),
+                    Some(b"get_peers") => Query::GetPeers(node_id(args.get("info_hash".as_bytes()), "info_hash")?),
+                    Some(b"announce_peer") => Query::AnnouncePeer {
+                        info_hash: node_id(args.get("info_hash".as_bytes()), "info_hash")?,
+                        port: args.get("port".as_bytes()).and_then(Value::integer)
+                            .filter(|port| **port > 0 && **port <= 65535)
+                            .map(|port| *port as u16)
+                            .ok_or("Missing key: port".to_string())?,
+                        implied_port: args.get("implied_port".as_bytes()).and_then(Value::integer)
+                            .filter(|implied| **implied != 0)
+                            .is_some(),
+                        token: args.get("token".as_bytes()).and_then(Value::bstring)
+                            .ok_or("Missing key: token".to_string())?
+                            .clone(),
+                    },
+                    Some(method) => return Err(format!("Unknown method: {}", String::from_utf8_lossy(method))),
+                    None => return Err("Missing key: q".to_string()),
+                };
+                Body::Query(id, query)
+            }
+            Some(b"r") => {
+                let values = map.get("r".as_bytes()).and_then(Value::dict)
+                    .ok_or("Missing key: r".to_string())?;
+                let id = node_id(values.get("id".as_bytes()), "id")?;
+                let mut nodes = Vec::new();
+                if let Some(compact) = values.get("nodes".as_bytes()).and_then(Value::bstring) {
+                    nodes.extend(parse_compact_nodes(compact, 4)?);
+                }
+                if let Some(compact) = values.get("nodes6".as_bytes()).and_then(Value::bstring) {
+                    nodes.extend(parse_compact_nodes(compact, 16)?);
+                }
+                let peers = values.get("values".as_bytes()).and_then(Value::list).map_or(&[][..], Vec::as_slice);
+                let mut addresses = Vec::new();
+                for peer in peers {
+                    let peer = peer.bstring().ok_or("Peer not a string".to_string())?;
+                    let parsed = if peer.len() == 6 { parse_compact_peers(peer)? } else { parse_compact_peers6(peer)? };
+                    addresses.extend(parsed.into_iter().map(|peer| peer.address));
+                }
+                let token = values.get("token".as_bytes()).and_then(Value::bstring).cloned();
+                Body::Response(Response { id, nodes, values: addresses, token })
+            }
+            Some(b"e") => {
+                let error = map.get("e".as_bytes()).and_then(Value::list)
+                    .ok_or("Missing key: e".to_string())?;
+                let code = error.first().and_then(Value::integer).cloned().unwrap_or(GENERIC_ERROR);
+                let msg = error.get(1).and_then(Value::bstring)
+                    .map(|msg| String::from_utf8_lossy(msg).into_owned())
+                    .unwrap_or_default();
+                Body::Error(code, msg)
+            }
+            _ => return Err("Missing key: y".to_string()),
+        };
+        Ok(Message { transaction, body })
+    }
+}
@@ -0,0 +1,650 @@
+//! dht finds peers without a tracker, by running a node of the mainline DHT (BEP 5).  The node is
+//! a task of its own, which the rest of the client asks for peers through a DhtHandle
+use crate::boostencode::Value;
+use crate::metainfo::sha1_hash;
+use futures::sync::mpsc::{
+    unbounded,
+    UnboundedReceiver,
+    UnboundedSender,
+};
+use log::{
+    debug,
+    trace,
+    warn,
+};
+use maplit::hashmap;
+use rand::prelude::*;
+use self::krpc::{
+    Body,
+    compact_nodes,
+    Message,
+    NodeId,
+    NodeInfo,
+    parse_compact_nodes,
+    PROTOCOL_ERROR,
+    Query,
+    Response,
+};
+use self::routing::{
+    distance,
+    K,
+    RoutingTable,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use std::fs;
+use std::io;
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    SocketAddr,
+    ToSocketAddrs,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::net::UdpSocket;
+use tokio::prelude::{
+    Async,
+    Future,
+    Stream,
+};
+use tokio::timer::Interval;
+
+mod krpc;
+mod routing;
+#[cfg(test)]
+mod test;
+
+/// How many queries a lookup has in flight at once
+const ALPHA: usize = 3;
+/// How many of the nodes closest to its target a lookup remembers
+const MAX_LOOKUP_NODES: usize = K * 8;
+/// How often query timeouts and maintenance are checked
+const TICK: Duration = Duration::from_millis(250);
+/// How often questionable nodes are pinged, the table is refreshed and saved
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often the secret tokens are made from changes.  Tokens from the one before are still
+/// accepted, so a token works for at least this long
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// How long an announced peer is handed out for, unless it announces again
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+/// The most peers put in a single get_peers response, to keep it to one datagram
+const MAX_VALUES: usize = 50;
+/// The most peers we store for one info hash
+const MAX_PEERS_PER_HASH: usize = 200;
+/// The most peers we store altogether, so other nodes can't fill our memory
+const MAX_PEERS: usize = 10_000;
+/// Well known nodes to join the DHT through
+const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+/// Tunable settings for a DHT node
+pub struct DhtConfig {
+    // The address to listen for other nodes on
+    pub address: SocketAddr,
+    // Nodes to join through when we don't know any others, as host:port
+    pub bootstrap: Vec<String>,
+    // Where our id and routing table are kept between runs
+    pub state_path: Option<PathBuf>,
+    // How long a node gets to answer a query
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            address: (Ipv4Addr::UNSPECIFIED, 6888).into(),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|node| node.to_string()).collect(),
+            state_path: None,
+            query_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What the rest of the client asks the node to do
+enum Command {
+    /// Find peers of a torrent, and announce that we are one on the port if there is one
+    GetPeers([u8; 20], Option<u16>, UnboundedSender<SocketAddr>),
+}
+
+/// Lets other tasks use a running node.  The node stops once every handle is gone
+#[derive(Clone)]
+pub struct DhtHandle {
+    commands: UnboundedSender<Command>,
+}
+
+impl DhtHandle {
+    /// Looks for peers of the torrent with the info hash, announcing us as one on port if it is
+    /// given.  Peers come out of the stream as they are found, and it ends when the lookup is over
+    pub fn get_peers(&self, info_hash: [u8; 20], port: Option<u16>) -> UnboundedReceiver<SocketAddr> {
+        let (sender, receiver) = unbounded();
+        // If the node has stopped, dropping the sender ends the stream straight away
+        let _ = self.commands.unbounded_send(Command::GetPeers(info_hash, port, sender));
+        receiver
+    }
+}
+
+/// How asking a node in a lookup went
+#[derive(Debug, PartialEq)]
+enum Asked {
+    NotYet,
+    Waiting,
+    /// It answered, with a token if it was get_peers
+    Answered(Option<Vec<u8>>),
+    Failed,
+}
+
+enum LookupKind {
+    FindNode,
+    GetPeers {
+        peers: UnboundedSender<SocketAddr>,
+        // The port to announce once the closest nodes are found
+        port: Option<u16>,
+        // Peers already passed on
+        found: HashSet<SocketAddr>,
+    },
+}
+
+/// An iterative search for the nodes closest to a target
+struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    // The nodes closest to the target that we know of, nearest first, and how asking them went
+    nodes: Vec<(NodeInfo, Asked)>,
+    // Queries sent that haven't been answered or given up on
+    in_flight: usize,
+}
+
+impl Lookup {
+    fn add_node(&mut self, node: NodeInfo) {
+        if self.nodes.iter().any(|(known, _)| known.id == node.id || known.address == node.address) {
+            return;
+        }
+        let target = self.target;
+        let position = self.nodes
+            .binary_search_by_key(&distance(&node.id, &target), |(known, _)| distance(&known.id, &target))
+            .unwrap_or_else(|i| i);
+        if position < MAX_LOOKUP_NODES {
+            self.nodes.insert(position, (node, Asked::NotYet));
+            self.nodes.truncate(MAX_LOOKUP_NODES);
+        }
+    }
+
+    /// Records how asking the node at the address went.  Nodes we asked without knowing their id
+    /// aren't in the list, and are only counted in in_flight
+    fn asked(&mut self, address: SocketAddr, asked: Asked) {
+        self.in_flight -= 1;
+        if let Some((_, state)) = self.nodes.iter_mut().find(|(node, _)| node.address == address) {
+            *state = asked;
+        }
+    }
+
+    /// The nodes to ask next.  Only the K closest nodes that haven't failed are asked, so the
+    /// lookup is over once they have all answered
+    fn next(&mut self) -> Vec<NodeInfo> {
+        let mut res = Vec::new();
+        for (node, asked) in self.nodes.iter_mut().filter(|(_, asked)| *asked != Asked::Failed).take(K) {
+            if self.in_flight + res.len() >= ALPHA {
+                break;
+            }
+            if *asked == Asked::NotYet {
+                *asked = Asked::Waiting;
+                res.push(*node);
+            }
+        }
+        self.in_flight += res.len();
+        res
+    }
+
+    fn query(&self) -> Query {
+        match self.kind {
+            LookupKind::FindNode => Query::FindNode(self.target),
+            LookupKind::GetPeers { .. } => Query::GetPeers(self.target),
+        }
+    }
+}
+
+/// A query we are waiting on an answer to
+struct Pending {
+    address: SocketAddr,
+    // The node's id, if we know it
+    id: Option<NodeId>,
+    // The lookup the query is part of
+    lookup: Option<usize>,
+    deadline: Instant,
+}
+
+/// A node of the DHT
+pub struct Dht {
+    socket: UdpSocket,
+    local_address: SocketAddr,
+    routing: RoutingTable,
+    commands: UnboundedReceiver<Command>,
+    // Where to join from when we run out of nodes
+    bootstrap: Vec<SocketAddr>,
+    state_path: Option<PathBuf>,
+    query_timeout: Duration,
+    // Queries waiting on an answer, by transaction id
+    pending: HashMap<Vec<u8>, Pending>,
+    next_transaction: u16,
+    lookups: HashMap<usize, Lookup>,
+    next_lookup: usize,
+    // Peers that announced to us, by info hash, with when they announced
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    // What tokens are made from, and the one before it
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_changed: Instant,
+    last_refresh: Instant,
+    // Datagrams waiting for room in the socket's send buffer
+    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+    timer: Interval,
+}
+
+impl Dht {
+    /// Starts a node, picking up the id and routing table from the last run if they were saved,
+    /// and starts joining the DHT
+    pub fn new(config: DhtConfig) -> io::Result<(Self, DhtHandle)> {
+        let socket = UdpSocket::bind(&config.address)?;
+        let local_address = socket.local_addr()?;
+        let (id, nodes) = match config.state_path.as_ref().and_then(|path| load_state(path)) {
+            Some(state) => state,
+            None => (thread_rng().gen(), Vec::new()),
+        };
+        let bootstrap = config.bootstrap.iter()
+            .flat_map(|node| match node.to_socket_addrs() {
+                Ok(addresses) => addresses.collect(),
+                Err(e) => {
+                    warn!("Could not resolve DHT bootstrap node {}: {}", node, e);
+                    Vec::new()
+                }
+            })
+            .filter(|address: &SocketAddr| address.is_ipv4() == local_address.is_ipv4())
+            .collect();
+        let (command_sender, command_receiver) = unbounded();
+        let mut dht = Dht {
+            socket,
+            local_address,
+            routing: RoutingTable::new(id),
+            commands: command_receiver,
+            bootstrap,
+            state_path: config.state_path,
+            query_timeout: config.query_timeout,
+            pending: HashMap::new(),
+            next_transaction: 0,
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            peers: HashMap::new(),
+            secret: thread_rng().gen(),
+            previous_secret: thread_rng().gen(),
+            secret_changed: Instant::now(),
+            last_refresh: Instant::now(),
+            outgoing: VecDeque::new(),
+            timer: Interval::new_interval(TICK),
+        };
+        for node in nodes {
+            dht.routing.insert(node);
+        }
+        debug!("Starting DHT node with {} known nodes", dht.routing.len());
+        // Looking ourselves up fills the table with our neighbours, and tells them about us
+        dht.start_lookup(id, LookupKind::FindNode);
+        Ok((dht, DhtHandle { commands: command_sender }))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Writes our id and routing table, so the next run can rejoin without bootstrapping
+    fn save(&self) {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return,
+        };
+        let nodes = self.routing.nodes();
+        let state = Value::Dict(hashmap! {
+            b"id".to_vec() => Value::BString(self.routing.id().to_vec()),
+            b"nodes".to_vec() => Value::BString(compact_nodes(nodes.iter().filter(|node| node.address.is_ipv4()))),
+            b"nodes6".to_vec() => Value::BString(compact_nodes(nodes.iter().filter(|node| node.address.is_ipv6()))),
+        });
+        let temp = path.with_extension("tmp");
+        if let Err(e) = fs::write(&temp, state.encode()).and_then(|_| fs::rename(&temp, path)) {
+            warn!("Failed to save the DHT routing table: {}", e);
+        }
+    }
+
+    fn send(&mut self, message: &Message, address: SocketAddr) {
+        self.outgoing.push_back((message.encode(), address));
+    }
+
+    /// Sends a query, to be answered within the query timeout
+    fn query(&mut self, address: SocketAddr, id: Option<NodeId>, query: Query, lookup: Option<usize>) {
+        let transaction = self.next_transaction.to_be_bytes().to_vec();
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let message = Message {
+            transaction: transaction.clone(),
+            body: Body::Query(*self.routing.id(), query),
+        };
+        self.send(&message, address);
+        self.pending.insert(transaction, Pending {
+            address,
+            id,
+            lookup,
+            deadline: Instant::now() + self.query_timeout,
+        });
+    }
+
+    /// The token a node at the ip needs to announce to us
+    fn token(ip: IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut input = secret.to_vec();
+        match ip {
+            IpAddr::V4(ip) => input.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => input.extend_from_slice(&ip.octets()),
+        }
+        sha1_hash(&input)[..8].to_vec()
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind) {
+        let mut lookup = Lookup {
+            target,
+            kind,
+            nodes: Vec::new(),
+            in_flight: 0,
+        };
+        for node in self.routing.closest(&target, K) {
+            lookup.add_node(node);
+        }
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        // With nobody to ask, start from the bootstrap nodes.  We don't know their ids, so they
+        // are asked directly instead of joining the lookup's list
+        if lookup.nodes.is_empty() {
+            let query = lookup.query();
+            for address in self.bootstrap.clone() {
+                lookup.in_flight += 1;
+                self.query(address, None, query.clone(), Some(id));
+            }
+        }
+        self.lookups.insert(id, lookup);
+        self.advance(id);
+    }
+
+    /// Asks the next nodes in a lookup, or finishes it if there are none left to ask
+    fn advance(&mut self, lookup_id: usize) {
+        let lookup = match self.lookups.get_mut(&lookup_id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        let query = lookup.query();
+        let next = lookup.next();
+        let finished = lookup.in_flight == 0;
+        for node in next {
+            self.query(node.address, Some(node.id), query.clone(), Some(lookup_id));
+        }
+        if !finished {
+            return;
+        }
+        let lookup = self.lookups.remove(&lookup_id).unwrap();
+        trace!("DHT lookup for {:?} finished", lookup.target);
+        // Dropping the peer sender ends the stream of peers
+        if let LookupKind::GetPeers { port: Some(port), .. } = lookup.kind {
+            let announces: Vec<(NodeInfo, Vec<u8>)> = lookup.nodes.into_iter()
+                .filter_map(|(node, asked)| match asked {
+                    Asked::Answered(Some(token)) => Some((node, token)),
+                    _ => None,
+                })
+                .take(K)
+                .collect();
+            for (node, token) in announces {
+                let query = Query::AnnouncePeer { info_hash: lookup.target, port, implied_port: false, token };
+                self.query(node.address, Some(node.id), query, None);
+            }
+        }
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8], from: SocketAddr) {
+        let message = match Message::decode(datagram) {
+            Ok(message) => message,
+            Err(e) => {
+                trace!("Ignoring a bad DHT message from {}: {}", from, e);
+                return;
+            }
+        };
+        match message.body {
+            Body::Query(id, query) => {
+                self.routing.insert(NodeInfo { id, address: from });
+                let body = self.handle_query(query, from);
+                self.send(&Message { transaction: message.transaction, body }, from);
+            }
+            Body::Response(response) => {
+                let pending = match self.pending.remove(&message.transaction) {
+                    Some(pending) if pending.address == from => pending,
+                    Some(pending) => {
+                        // Not from the node we asked, so leave the query waiting on the real answer
+                        self.pending.insert(message.transaction, pending);
+                        return;
+                    }
+                    None => return,
+                };
+                if pending.id.is_some() && pending.id != Some(response.id) {
+                    trace!("DHT node {} answered with a different id", from);
+                }
+                self.routing.insert(NodeInfo { id: response.id, address: from });
+                if let Some(lookup_id) = pending.lookup {
+                    self.handle_lookup_response(lookup_id, from, response);
+                }
+            }
+            Body::Error(code, msg) => {
+                let pending = match self.pending.remove(&message.transaction) {
+                    Some(pending) if pending.address == from => pending,
+                    Some(pending) => {
+                        // Not from the node we asked, so it can't fail the query for it
+                        self.pending.insert(message.transaction, pending);
+                        return;
+                    }
+                    None => return,
+                };
+                debug!("DHT node {} refused our query: {} {}", from, code, msg);
+                if let Some(lookup_id) = pending.lookup {
+                    if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                        lookup.asked(from, Asked::Failed);
+                    }
+                    self.advance(lookup_id);
+                }
+            }
+        }
+    }
+
+    /// Answers a query from another node
+    fn handle_query(&mut self, query: Query, from: SocketAddr) -> Body {
+        let id = *self.routing.id();
+        match query {
+            Query::Ping => Body::Response(Response { id, ..Response::default() }),
+            Query::FindNode(target) => Body::Response(Response {
+                id,
+                nodes: self.routing.closest(&target, K),
+                ..Response::default()
+            }),
+            Query::GetPeers(info_hash) => {
+                let values = self.peers.get(&info_hash)
+                    .map(|peers| peers.keys().take(MAX_VALUES).cloned().collect())
+                    .unwrap_or_default();
+                Body::Response(Response {
+                    id,
+                    nodes: self.routing.closest(&info_hash, K),
+                    values,
+                    token: Some(Dht::token(from.ip(), &self.secret)),
+                })
+            }
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if token != Dht::token(from.ip(), &self.secret) && token != Dht::token(from.ip(), &self.previous_secret) {
+                    return Body::Error(PROTOCOL_ERROR, "Bad token".to_string());
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Body::Error(PROTOCOL_ERROR, "Bad port".to_string());
+                }
+                let address = SocketAddr::new(from.ip(), port);
+                let stored: usize = self.peers.values().map(HashMap::len).sum();
+                let peers = self.peers.entry(info_hash).or_default();
+                // Peers already stored can always refresh their announce
+                if peers.contains_key(&address) || (peers.len() < MAX_PEERS_PER_HASH && stored < MAX_PEERS) {
+                    peers.insert(address, Instant::now());
+                } else {
+                    trace!("Not storing peer {} announced by {}, we have too many", address, from);
+                }
+                Body::Response(Response { id, ..Response::default() })
+            }
+        }
+    }
+
+    fn handle_lookup_response(&mut self, lookup_id: usize, from: SocketAddr, response: Response) {
+        let own_id = *self.routing.id();
+        let v4 = self.local_address.is_ipv4();
+        let lookup = match self.lookups.get_mut(&lookup_id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        lookup.asked(from, Asked::Answered(response.token));
+        for node in response.nodes {
+            // Nodes we couldn't reach from our socket are no use
+            if node.id != own_id && node.address.is_ipv4() == v4 {
+                lookup.add_node(node);
+            }
+        }
+        if let LookupKind::GetPeers { peers, found, .. } = &mut lookup.kind {
+            for peer in response.values {
+                if found.insert(peer) {
+                    // The receiver may be gone, but the lookup still needs to finish to announce
+                    let _ = peers.unbounded_send(peer);
+                }
+            }
+        }
+        self.advance(lookup_id);
+    }
+
+    /// Gives up on queries that weren't answered in time, and does periodic upkeep
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Vec<u8>> = self.pending.iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(transaction, _)| transaction.clone())
+            .collect();
+        for transaction in expired {
+            let pending = self.pending.remove(&transaction).unwrap();
+            if let Some(id) = pending.id {
+                self.routing.failed(&id);
+            }
+            if let Some(lookup_id) = pending.lookup {
+                if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                    lookup.asked(pending.address, Asked::Failed);
+                }
+                self.advance(lookup_id);
+            }
+        }
+
+        if now.duration_since(self.secret_changed) >= TOKEN_LIFETIME {
+            self.previous_secret = self.secret;
+            self.secret = thread_rng().gen();
+            self.secret_changed = now;
+            for peers in self.peers.values_mut() {
+                peers.retain(|_, announced| now.duration_since(*announced) < PEER_LIFETIME);
+            }
+            self.peers.retain(|_, peers| !peers.is_empty());
+        }
+
+        if now.duration_since(self.last_refresh) >= REFRESH_INTERVAL {
+            self.last_refresh = now;
+            for node in self.routing.questionable() {
+                self.query(node.address, Some(node.id), Query::Ping, None);
+            }
+            let own_id = *self.routing.id();
+            self.start_lookup(own_id, LookupKind::FindNode);
+            self.start_lookup(thread_rng().gen(), LookupKind::FindNode);
+            self.save();
+        }
+    }
+}
+
+/// Reads the id and nodes saved by an earlier run
+fn load_state(path: &Path) -> Option<(NodeId, Vec<NodeInfo>)> {
+    let val = Value::decode(&fs::read(path).ok()?).ok()?;
+    let map = val.dict()?;
+    let id = map.get("id".as_bytes()).and_then(Value::bstring).filter(|id| id.len() == 20)?;
+    let mut nodes = parse_compact_nodes(map.get("nodes".as_bytes()).and_then(Value::bstring)?, 4).ok()?;
+    nodes.extend(parse_compact_nodes(map.get("nodes6".as_bytes()).and_then(Value::bstring)?, 16).ok()?);
+    let mut res = [0; 20];
+    res.copy_from_slice(id);
+    Some((res, nodes))
+}
+
+impl Future for Dht {
+    type Item = ();
+    type Error = ();
+
+    /// Runs until every handle is dropped, saving the routing table on the way out
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(Command::GetPeers(info_hash, port, peers)))) => {
+                    let kind = LookupKind::GetPeers { peers, port, found: HashSet::new() };
+                    self.start_lookup(info_hash, kind);
+                }
+                Ok(Async::Ready(None)) => {
+                    debug!("Stopping DHT node");
+                    self.save();
+                    return Ok(Async::Ready(()));
+                }
+                _ => break,
+            }
+        }
+
+        let mut buf = [0; 2048];
+        loop {
+            match self.socket.poll_recv_from(&mut buf) {
+                Ok(Async::Ready((length, from))) => self.handle_datagram(&buf[..length], from),
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    debug!("DHT socket error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        loop {
+            match self.timer.poll() {
+                Ok(Async::Ready(Some(_))) => self.tick(),
+                Err(e) => {
+                    warn!("DHT timer failed: {}", e);
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        while let Some((datagram, address)) = self.outgoing.pop_front() {
+            match self.socket.poll_send_to(&datagram, &address) {
+                Ok(Async::Ready(_)) => (),
+                Ok(Async::NotReady) => {
+                    self.outgoing.push_front((datagram, address));
+                    break;
+                }
+                // Nothing to be done about it.  The query will time out
+                Err(e) => debug!("Failed to send to DHT node {}: {}", address, e),
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
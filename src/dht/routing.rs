@@ -0,0 +1,140 @@
+//! The Kademlia routing table: the nodes we know of, in buckets by how close they are to us
+use super::krpc::{
+    NodeId,
+    NodeInfo,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+/// The most nodes in a bucket, and how many nodes lookups and responses deal in
+pub const K: usize = 8;
+/// A node that hasn't been heard from in this long may have gone away
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// A node that failed to answer this many queries in a row is replaced by the next one we hear of
+const MAX_FAILURES: u32 = 2;
+
+/// How far apart two ids are in the Kademlia metric
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut res = [0; 20];
+    for (res, (a, b)) in res.iter_mut().zip(a.iter().zip(b.iter())) {
+        *res = a ^ b;
+    }
+    res
+}
+
+/// Which bucket a node belongs in: the number of leading bits its id shares with ours.  None for
+/// our own id
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    distance(own, id).iter()
+        .enumerate()
+        .find(|(_, byte)| **byte != 0)
+        .map(|(i, byte)| i * 8 + byte.leading_zeros() as usize)
+}
+
+struct Entry {
+    info: NodeInfo,
+    last_seen: Instant,
+    // Queries in a row the node didn't answer
+    failures: u32,
+}
+
+impl Entry {
+    fn bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+pub struct RoutingTable {
+    id: NodeId,
+    // Bucket i holds nodes whose ids share exactly i leading bits with ours
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Notes that we heard from a node, adding it if its bucket has room or holds a bad node.
+    /// Returns whether the node is in the table
+    pub fn insert(&mut self, info: NodeInfo) -> bool {
+        let bucket = match bucket_index(&self.id, &info.id) {
+            Some(i) => &mut self.buckets[i],
+            None => return false,
+        };
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.info.id == info.id) {
+            // Don't let a node take over another's id by claiming it from a new address
+            if entry.info.address != info.address {
+                return false;
+            }
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+        let entry = Entry { info, last_seen: Instant::now(), failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        match bucket.iter_mut().find(|entry| entry.bad()) {
+            Some(bad) => {
+                *bad = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Notes that a node didn't answer a query
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(i) = bucket_index(&self.id, id) {
+            if let Some(entry) = self.buckets[i].iter_mut().find(|entry| entry.info.id == *id) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The count nodes closest to the target, nearest first, leaving out bad ones
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter()
+            .flatten()
+            .filter(|entry| !entry.bad())
+            .map(|entry| entry.info)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes we haven't heard from in a while, which should be pinged to see if they are still there
+    pub fn questionable(&self) -> Vec<NodeInfo> {
+        let now = Instant::now();
+        self.buckets.iter()
+            .flatten()
+            .filter(|entry| now.duration_since(entry.last_seen) >= QUESTIONABLE_AFTER)
+            .map(|entry| entry.info)
+            .collect()
+    }
+
+    /// Every node that isn't bad, for saving the table
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter()
+            .flatten()
+            .filter(|entry| !entry.bad())
+            .map(|entry| entry.info)
+            .collect()
+    }
+}
@@ -0,0 +1,188 @@
+use crate::testutil::temp_dir;
+use std::thread;
+use super::*;
+use tokio::runtime::Runtime;
+
+fn node(id: u8, port: u16) -> NodeInfo {
+    let mut node_id = [0; 20];
+    node_id[0] = id;
+    NodeInfo { id: node_id, address: SocketAddr::from(([127, 0, 0, 1], port)) }
+}
+
+#[test]
+fn test_krpc_round_trip() {
+    let messages = vec![
+        Body::Query([1; 20], Query::Ping),
+        Body::Query([1; 20], Query::FindNode([2; 20])),
+        Body::Query([1; 20], Query::GetPeers([3; 20])),
+        Body::Query([1; 20], Query::AnnouncePeer { info_hash: [3; 20], port: 6881, implied_port: true, token: b"abc".to_vec() }),
+        Body::Response(Response {
+            id: [4; 20],
+            nodes: vec![node(1, 1), NodeInfo { id: [5; 20], address: "[::1]:2".parse().unwrap() }],
+            values: vec!["1.2.3.4:5".parse().unwrap(), "[::2]:6".parse().unwrap()],
+            token: Some(b"tok".to_vec()),
+        }),
+        Body::Error(PROTOCOL_ERROR, "Bad token".to_string()),
+    ];
+    for body in messages {
+        let message = Message { transaction: b"aa".to_vec(), body };
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+    }
+}
+
+#[test]
+fn test_krpc_decode() {
+    // examples from BEP 5
+    let ping = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+    let mut id = [0; 20];
+    id.copy_from_slice(b"abcdefghij0123456789");
+    assert_eq!(ping, Message { transaction: b"aa".to_vec(), body: Body::Query(id, Query::Ping) });
+    let error = Message::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+    assert_eq!(error.body, Body::Error(201, "A Generic Error Ocurred".to_string()));
+
+    assert!(Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+    assert!(Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:nope1:t2:aa1:y1:qe").is_err());
+    assert!(Message::decode(b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re").is_err());
+}
+
+#[test]
+fn test_routing_table() {
+    let mut table = RoutingTable::new([0; 20]);
+    assert!(!table.insert(NodeInfo { id: [0; 20], address: "127.0.0.1:1".parse().unwrap() }));
+
+    // ids starting with 0x80..0xff all go in the first bucket, which fills up
+    for i in 0..K as u8 {
+        assert!(table.insert(node(0x80 + i, 100 + u16::from(i))));
+    }
+    assert!(!table.insert(node(0xf0, 200)));
+    // ids close to ours have buckets of their own
+    assert!(table.insert(node(0x01, 300)));
+    assert!(table.insert(node(0x02, 301)));
+    assert_eq!(table.len(), K + 2);
+
+    let mut target = [0; 20];
+    target[0] = 0x03;
+    assert_eq!(table.closest(&target, 3), vec![node(0x02, 301), node(0x01, 300), node(0x83, 103)]);
+
+    // a node that stops answering is replaced by the next one we hear from
+    table.failed(&node(0x80, 100).id);
+    assert!(!table.insert(node(0xf0, 200)));
+    table.failed(&node(0x80, 100).id);
+    assert!(table.insert(node(0xf0, 200)));
+    assert!(!table.nodes().contains(&node(0x80, 100)));
+    // and one can't move another's id to a new address
+    assert!(!table.insert(node(0xf0, 201)));
+}
+
+#[test]
+fn test_token() {
+    let secret = [7; 20];
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    assert_eq!(Dht::token(ip, &secret), Dht::token(ip, &secret));
+    assert_ne!(Dht::token(ip, &secret), Dht::token("10.0.0.2".parse().unwrap(), &secret));
+    assert_ne!(Dht::token(ip, &secret), Dht::token(ip, &[8; 20]));
+}
+
+fn config(bootstrap: Vec<String>) -> DhtConfig {
+    DhtConfig {
+        address: (Ipv4Addr::LOCALHOST, 0).into(),
+        bootstrap,
+        state_path: None,
+        query_timeout: Duration::from_millis(500),
+    }
+}
+
+#[test]
+fn test_cluster() {
+    let runtime = Runtime::new().unwrap();
+    let (first, first_handle) = Dht::new(config(vec![])).unwrap();
+    let bootstrap = vec![first.local_addr().to_string()];
+    runtime.executor().spawn(first);
+    let mut handles = vec![first_handle];
+    for _ in 0..7 {
+        let (dht, handle) = Dht::new(config(bootstrap.clone())).unwrap();
+        runtime.executor().spawn(dht);
+        handles.push(handle);
+    }
+    // let everyone join
+    thread::sleep(Duration::from_millis(500));
+
+    let info_hash = [0x42; 20];
+    let peers: Vec<SocketAddr> = handles[1].get_peers(info_hash, Some(7777)).wait().map(Result::unwrap).collect();
+    assert!(peers.is_empty());
+
+    // the announce goes out once the lookup is over, so give it a moment to land
+    let expected = SocketAddr::from(([127, 0, 0, 1], 7777));
+    let mut found = Vec::new();
+    for _ in 0..10 {
+        found = handles[5].get_peers(info_hash, None).wait().map(Result::unwrap).collect();
+        if !found.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(found, vec![expected]);
+}
+
+#[test]
+fn test_save_and_load() {
+    let dir = temp_dir("dht");
+    let path = dir.join("state");
+    let (mut dht, _handle) = Dht::new(DhtConfig { state_path: Some(path.clone()), ..config(vec![]) }).unwrap();
+    dht.routing.insert(node(0x10, 1));
+    dht.routing.insert(NodeInfo { id: [0x20; 20], address: "[::1]:2".parse().unwrap() });
+    dht.save();
+
+    let (id, mut nodes) = load_state(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(id, *dht.routing.id());
+    nodes.sort_by_key(|node| node.id);
+    assert_eq!(nodes, vec![node(0x10, 1), NodeInfo { id: [0x20; 20], address: "[::1]:2".parse().unwrap() }]);
+}
+
+#[test]
+fn test_announce_peer_limits() {
+    let (mut dht, _handle) = Dht::new(config(vec![])).unwrap();
+    let announce = |dht: &mut Dht, info_hash, from: SocketAddr, port| {
+        let token = Dht::token(from.ip(), &dht.secret);
+        dht.handle_query(Query::AnnouncePeer { info_hash, port, implied_port: false, token }, from)
+    };
+    let from = |i: usize| SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 1000));
+
+    match announce(&mut dht, [1; 20], from(0), 0) {
+        Body::Error(PROTOCOL_ERROR, _) => (),
+        body => panic!("expected port 0 to be refused, got {:?}", body),
+    }
+    assert!(dht.peers.get(&[1; 20]).filter(|peers| !peers.is_empty()).is_none());
+
+    for i in 0..MAX_PEERS_PER_HASH + 1 {
+        announce(&mut dht, [1; 20], from(i), 6881);
+    }
+    assert_eq!(dht.peers[&[1; 20]].len(), MAX_PEERS_PER_HASH);
+    // one we already have can still announce again
+    let first = dht.peers[&[1; 20]][&SocketAddr::new(from(0).ip(), 6881)];
+    announce(&mut dht, [1; 20], from(0), 6881);
+    assert!(dht.peers[&[1; 20]][&SocketAddr::new(from(0).ip(), 6881)] >= first);
+
+    // and the total is capped across every info hash
+    for hash in 2..=(MAX_PEERS / MAX_PEERS_PER_HASH) as u8 + 1 {
+        for i in 0..MAX_PEERS_PER_HASH {
+            announce(&mut dht, [hash; 20], from(i), 6881);
+        }
+    }
+    assert_eq!(dht.peers.values().map(HashMap::len).sum::<usize>(), MAX_PEERS);
+}
+
+#[test]
+fn test_error_from_another_address() {
+    let (mut dht, _handle) = Dht::new(config(vec![])).unwrap();
+    let asked = SocketAddr::from(([10, 0, 0, 1], 6881));
+    dht.pending.insert(b"aa".to_vec(), Pending { address: asked, id: None, lookup: None, deadline: Instant::now() });
+    let error = Message { transaction: b"aa".to_vec(), body: Body::Error(PROTOCOL_ERROR, "No".to_string()) };
+
+    // a node we didn't ask can't fail the query
+    dht.handle_datagram(&error.encode(), SocketAddr::from(([10, 0, 0, 2], 6881)));
+    assert!(dht.pending.contains_key(b"aa".as_ref()));
+    dht.handle_datagram(&error.encode(), asked);
+    assert!(dht.pending.is_empty());
+}
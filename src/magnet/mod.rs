@@ -0,0 +1,113 @@
+//! magnet parses magnet links, which name a torrent by its info hash instead of carrying the
+//! metainfo
+use log::warn;
+use percent_encoding::percent_decode;
+use std::net::SocketAddr;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Magnet {
+    // The SHA1 hash of the info dictionary, which has to be fetched from peers
+    pub info_hash: [u8; 20],
+    // A name to show until we have the metainfo
+    pub display_name: Option<String>,
+    // Tracker uris, in the order they were given
+    pub trackers: Vec<String>,
+    // Peers to try to get the metadata from directly
+    pub peers: Vec<SocketAddr>,
+    // Web seeds for the content
+    pub web_seeds: Vec<String>,
+}
+
+impl Magnet {
+    /// Parses a magnet uri.  Only BitTorrent info hashes are understood, in hex or base32
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let query = uri.strip_prefix("magnet:?").ok_or("Not a magnet link".to_string())?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        let mut web_seeds = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = match param.find('=') {
+                Some(i) => (&param[..i], &param[i + 1..]),
+                None => (param, ""),
+            };
+            // Keys may be numbered to give more than one value, like tr.1
+            let name = key.split('.').next().unwrap_or(key);
+            // The display name may use + for a space, which has to be undone before decoding so a
+            // %2B stays a plus.  Tracker uris keep theirs
+            let value = if name == "dn" { value.replace('+', " ") } else { value.to_string() };
+            let value = percent_decode(value.as_bytes()).decode_utf8()
+                .map_err(|_| format!("{} is not valid UTF-8", key))?;
+            match name {
+                "xt" if value.starts_with("urn:btih:") => {
+                    info_hash = Some(parse_info_hash(&value["urn:btih:".len()..])?);
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                // A peer we can't use, such as one given by hostname, doesn't make the rest useless
+                "x" if key == "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => warn!("Ignoring peer address in magnet link: {}", value),
+                },
+                "ws" => web_seeds.push(value.into_owned()),
+                _ => (),
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or("Missing key: xt".to_string())?,
+            display_name,
+            trackers,
+            peers,
+            web_seeds,
+        })
+    }
+
+    /// The trackers grouped into tiers, one tracker to a tier so they are tried in order
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()
+    }
+}
+
+/// Reads an info hash written as 40 hex digits, or 32 base32 digits
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], String> {
+    if !hash.is_ascii() {
+        return Err(format!("Invalid info hash: {}", hash));
+    }
+    let mut res = [0u8; 20];
+    match hash.len() {
+        40 => {
+            for (i, byte) in res.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16)
+                    .map_err(|_| format!("Invalid hex info hash: {}", hash))?;
+            }
+        }
+        32 => {
+            // Each digit is 5 bits, packed most significant first
+            let mut bits: u64 = 0;
+            let mut num_bits = 0;
+            let mut i = 0;
+            for c in hash.bytes() {
+                let digit = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return Err(format!("Invalid base32 info hash: {}", hash)),
+                };
+                bits = bits << 5 | u64::from(digit);
+                num_bits += 5;
+                if num_bits >= 8 {
+                    num_bits -= 8;
+                    res[i] = (bits >> num_bits) as u8;
+                    i += 1;
+                }
+            }
+        }
+        _ => return Err(format!("Info hash is the wrong length: {}", hash)),
+    }
+    Ok(res)
+}
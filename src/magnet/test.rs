@@ -0,0 +1,57 @@
+use super::*;
+
+const HASH: [u8; 20] = [
+    0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9,
+    0xf5, 0x19, 0xb3, 0x35, 0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a,
+];
+
+#[test]
+fn test_parse_hex() {
+    let magnet = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+        &dn=Some+File%20Name&tr=http%3A%2F%2Fexample.com%2Fannounce&tr=udp://example.org:80\
+        &x.pe=10.0.0.1:6881&x.pe=[::1]:6882&ws=http://example.com/file").unwrap();
+    assert_eq!(magnet, Magnet {
+        info_hash: HASH,
+        display_name: Some("Some File Name".to_string()),
+        trackers: vec!["http://example.com/announce".to_string(), "udp://example.org:80".to_string()],
+        peers: vec![([10, 0, 0, 1], 6881).into(), "[::1]:6882".parse().unwrap()],
+        web_seeds: vec!["http://example.com/file".to_string()],
+    });
+    assert_eq!(magnet.tiers(), vec![
+        vec!["http://example.com/announce".to_string()],
+        vec!["udp://example.org:80".to_string()],
+    ]);
+}
+
+#[test]
+fn test_parse_base32() {
+    let magnet = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+    assert_eq!(magnet.info_hash, HASH);
+    let lower = Magnet::parse("magnet:?xt=urn:btih:yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap();
+    assert_eq!(lower.info_hash, HASH);
+    assert!(magnet.trackers.is_empty());
+}
+
+#[test]
+fn test_parse_invalid() {
+    assert!(Magnet::parse("http://example.com").is_err());
+    assert!(Magnet::parse("magnet:?dn=name").is_err());
+    assert!(Magnet::parse("magnet:?xt=urn:btih:c12fe1").is_err());
+    assert!(Magnet::parse("magnet:?xt=urn:btih:z12fe1c06bba254a9dc9f519b335aa7c1367a88a").is_err());
+    assert!(Magnet::parse("magnet:?xt=urn:btih:1EX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").is_err());
+}
+
+#[test]
+fn test_parse_bad_peer() {
+    let magnet = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+        &x.pe=peer.example.com:6881&x.pe=10.0.0.1&x.pe=10.0.0.1:6881").unwrap();
+    assert_eq!(magnet.peers, vec![([10, 0, 0, 1], 6881).into()]);
+}
+
+#[test]
+fn test_parse_encoded_plus() {
+    let magnet = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+        &dn=a%2Bb+c&tr=http://example.com/a+b").unwrap();
+    assert_eq!(magnet.display_name, Some("a+b c".to_string()));
+    assert_eq!(magnet.trackers, vec!["http://example.com/a+b".to_string()]);
+}
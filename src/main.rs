@@ -1,23 +1,50 @@
 use crate::boostencode::{FromValue, Value};
-use clap::App;
+use clap::{
+    App,
+    ArgMatches,
+};
 use clap::load_yaml;
+use futures::future::{
+    Either,
+    Shared,
+};
+use futures::sync::oneshot;
 use log::{
     debug,
     error,
+    info,
     Level,
     warn,
 };
 use rand::prelude::*;
 use simple_logger::init_with_level;
 use std::fs::File;
+use tokio::prelude::Future;
+use tokio::runtime::Runtime;
 use std::io::Read;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::thread;
 
 mod boostencode;
+mod choker;
+mod create;
+mod dht;
+mod magnet;
+mod metadata;
 mod metainfo;
 mod tracker;
 mod server;
 mod piece;
 mod peer;
+mod pex;
+mod picker;
+mod resume;
+mod storage;
+#[cfg(test)]
+mod testutil;
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
@@ -35,26 +62,213 @@ fn main() {
         warn!("Garbage mode activated");
     }
 
-    if matches.is_present("torrent-file") {
+    if let Some(matches) = matches.subcommand_matches("create") {
+        create_torrent(matches);
+    } else if matches.is_present("torrent-file") {
         let string = matches.value_of("torrent-file").unwrap();
-        let mut f = File::open(string).expect("file not found");
-        let mut contents = Vec::new();
-        f.read_to_end(&mut contents).expect("error reading file");
-        let val = Value::decode(contents.as_ref()).unwrap();
-        debug!("{}", val);
-
-        let metainfo = metainfo::MetaInfo::from_value(&val).unwrap();
+        let peer_id = gen_peer_id();
+        let download_dir = PathBuf::from(matches.value_of("output-dir").unwrap_or("."));
+        // Before the runtime starts any threads, so none of them are handed the signals
+        let shutdown = shutdown_signal().shared();
+        let mut runtime = Runtime::new().expect("Failed to start runtime");
+        let use_dht = !matches.is_present("no-dht") && !matches.is_present("scrape");
+        let (metainfo, dht) = if string.starts_with("magnet:") {
+            // We can't know whether the torrent is private until we have its metadata, and the
+            // DHT may be the only way to find peers to get it from
+            let dht = if use_dht { start_dht(&matches, &download_dir, &runtime, Vec::new()) } else { None };
+            match fetch_metainfo(&mut runtime, peer_id, string, dht.clone(), shutdown.clone()) {
+                Some(metainfo) => (metainfo, dht),
+                None => return,
+            }
+        } else {
+            let mut f = File::open(string).expect("file not found");
+            let mut contents = Vec::new();
+            f.read_to_end(&mut contents).expect("error reading file");
+            let metainfo = match Value::decode(contents.as_ref()) {
+                Ok(val) => {
+                    debug!("{}", val);
+                    metainfo::MetaInfo::from_value(&val)
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            let metainfo = match metainfo {
+                Ok(metainfo) => metainfo,
+                Err(e) => {
+                    error!("Invalid torrent file: {}", e);
+                    return;
+                }
+            };
+            // Private torrents only get their peers from the tracker, so don't join the DHT at all
+            let dht = if use_dht && !metainfo.info.private {
+                start_dht(&matches, &download_dir, &runtime, metainfo.bootstrap_nodes())
+            } else {
+                None
+            };
+            (metainfo, dht)
+        };
         debug!("{:?}", metainfo);
 
-        let peer_id = gen_peer_id();
+        if matches.is_present("scrape") {
+            let tracker = tracker::Tracker::new(peer_id, metainfo.tiers(), metainfo.info_hash, 6888);
+            match runtime.block_on(tracker.scrape(&[metainfo.info_hash])) {
+                Ok(response) => match response.files.get(&metainfo.info_hash) {
+                    Some(info) => println!("seeders: {}, leechers: {}, downloaded: {}",
+                                           info.complete, info.incomplete, info.downloaded),
+                    None => println!("The tracker does not know about this torrent"),
+                },
+                Err(tracker::TrackerError::Failure(msg)) => error!("The tracker refused to scrape: {}", msg),
+                Err(e) => error!("Scrape failed: {:?}", e),
+            }
+            return;
+        }
 
-        let server = server::Server::new(peer_id, metainfo);
-        tokio::run(server);
+        let mut config = server::Config {
+            download_dir,
+            // A magnet link may turn out to be for a private torrent, and dropping the handle
+            // stops the node
+            dht: if metainfo.info.private { None } else { dht },
+            shutdown: Some(Box::new(shutdown.map(|_| ()).map_err(|_| ()))),
+            ..server::Config::default()
+        };
+        if let Some(max) = matches.value_of("max-connections") {
+            config.max_connections = max.parse().expect("max-connections must be a number");
+        }
+        if let Some(max) = matches.value_of("max-requests") {
+            config.max_requests = max.parse().expect("max-requests must be a number");
+        }
+        if let Some(slots) = matches.value_of("unchoke-slots") {
+            config.unchoke_slots = slots.parse().expect("unchoke-slots must be a number");
+        }
+        if let Some(ip) = matches.value_of("announce-ip") {
+            config.announce_ip = Some(ip.parse().expect("announce-ip must be an IP address"));
+        }
+        config.seed = matches.is_present("seed");
+        let server = server::Server::new(peer_id, metainfo, config);
+        // The DHT node stops once the server is done with it
+        runtime.spawn(server);
+        runtime.shutdown_on_idle().wait().unwrap();
     } else {
         error!("No torrent file provided");
     }
 }
 
+/// Fires when the user asks us to stop with Ctrl-C or SIGTERM, so the server can say goodbye to
+/// the tracker.  A second signal exits straight away.  The signals are blocked on this thread and
+/// every thread it starts after this, and waited for on a thread of their own
+#[cfg(unix)]
+fn shutdown_signal() -> oneshot::Receiver<()> {
+    let (sender, receiver) = oneshot::channel();
+    // sigset_t is plain data, and the calls only fail for invalid signal numbers
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        info!("Stopping, interrupt again to quit immediately");
+        let _ = sender.send(());
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        std::process::exit(1);
+    });
+    receiver
+}
+
+/// Without unix signals, the default handlers end the process and nothing fires
+#[cfg(not(unix))]
+fn shutdown_signal() -> oneshot::Receiver<()> {
+    let (sender, receiver) = oneshot::channel();
+    std::mem::forget(sender);
+    receiver
+}
+
+/// Starts a DHT node on the runtime, keeping its routing table in the download directory.  The
+/// torrent's own nodes are joined through as well as the usual ones
+fn start_dht(matches: &ArgMatches, download_dir: &Path, runtime: &Runtime, nodes: Vec<String>) -> Option<dht::DhtHandle> {
+    let mut config = dht::DhtConfig {
+        state_path: Some(download_dir.join(".dht")),
+        ..dht::DhtConfig::default()
+    };
+    if let Some(given) = matches.values_of("dht-node") {
+        config.bootstrap = given.map(|node| node.to_string()).collect();
+    }
+    config.bootstrap.extend(nodes);
+    match dht::Dht::new(config) {
+        Ok((node, handle)) => {
+            info!("DHT node listening on {}", node.local_addr());
+            runtime.executor().spawn(node);
+            Some(handle)
+        }
+        Err(e) => {
+            warn!("Failed to start the DHT node: {}", e);
+            None
+        }
+    }
+}
+
+/// Gets the metainfo for a magnet link from the peers in its swarm
+fn fetch_metainfo(runtime: &mut Runtime,
+                  peer_id: [u8; 20],
+                  uri: &str,
+                  dht: Option<dht::DhtHandle>,
+                  shutdown: Shared<oneshot::Receiver<()>>) -> Option<metainfo::MetaInfo> {
+    let magnet = match magnet::Magnet::parse(uri) {
+        Ok(magnet) => magnet,
+        Err(e) => {
+            error!("Invalid magnet link: {}", e);
+            return None;
+        }
+    };
+    if !magnet.web_seeds.is_empty() {
+        warn!("Web seeds are not supported, ignoring {}", magnet.web_seeds.join(", "));
+    }
+    info!("Fetching metadata for {}", magnet.display_name.as_ref().map_or("the magnet link", |name| name.as_str()));
+    match runtime.block_on(metadata::fetch(peer_id, &magnet, 6888, dht).select2(shutdown)) {
+        Ok(Either::A((metainfo, _))) => Some(metainfo),
+        Err(Either::A((e, _))) => {
+            error!("Could not get the metadata: {:?}", e);
+            None
+        }
+        // Nothing was downloaded, so there is nobody to say goodbye to
+        Ok(Either::B(_)) | Err(Either::B(_)) => None,
+    }
+}
+
+/// Makes a .torrent file for the create subcommand
+fn create_torrent(matches: &ArgMatches) {
+    let path = Path::new(matches.value_of("path").unwrap());
+    let options = create::Options {
+        // each use of --tracker is a tier, with its trackers separated by commas
+        trackers: matches.values_of("tracker").into_iter().flatten()
+            .map(|tier| tier.split(',').map(|uri| uri.trim().to_string()).filter(|uri| !uri.is_empty()).collect())
+            .collect(),
+        piece_length: matches.value_of("piece-length")
+            .map(|length| length.parse().expect("piece-length must be a number")),
+        comment: matches.value_of("comment").map(|comment| comment.to_string()),
+        private: matches.is_present("private"),
+        ..create::Options::default()
+    };
+
+    match create::create(path, &options) {
+        Ok(metainfo) => {
+            let output = match matches.value_of("output") {
+                Some(output) => PathBuf::from(output),
+                None => PathBuf::from(format!("{}.torrent", metainfo.info.file_info.name())),
+            };
+            match std::fs::write(&output, metainfo.to_value().encode()) {
+                Ok(()) => println!("Wrote {} with {} pieces of {} bytes",
+                                   output.display(), metainfo.info.num_pieces(), metainfo.info.piece_length),
+                Err(e) => error!("Failed to write {}: {}", output.display(), e),
+            }
+        }
+        Err(e) => error!("Failed to create the torrent: {:?}", e),
+    }
+}
+
 fn gen_peer_id() -> [u8; 20] {
     // Generate peer id in Azures style ("-<2 letter client code><4 digit version number>-<12 random digits>")
     let mut id = "-BO0001-".to_owned();
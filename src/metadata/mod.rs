@@ -0,0 +1,581 @@
+//! metadata downloads a torrent's info dictionary from peers with the ut_metadata extension
+//! (BEP 9), for when all we have is a magnet link
+use bytes::Bytes;
+use crate::boostencode::{FromValue, Value};
+use crate::dht::DhtHandle;
+use crate::magnet::Magnet;
+use crate::metainfo::{
+    MetaInfo,
+    sha1_hash,
+};
+use crate::peer::extension::{
+    self,
+    Extension,
+    ExtensionHandshake,
+    Outbox,
+};
+use crate::peer::message::{
+    Handshake,
+    Message,
+    MessageCodec,
+};
+use crate::tracker::{
+    Tracker,
+    TrackerError,
+    TrackerResponse,
+};
+use derive_error::Error;
+use futures::sync::mpsc::UnboundedReceiver;
+use log::{
+    debug,
+    trace,
+    warn,
+};
+use maplit::hashmap;
+use std::collections::{
+    HashSet,
+    VecDeque,
+};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::{
+    codec::Framed,
+    net::TcpStream,
+    prelude::{
+        Async,
+        AsyncSink,
+        Future,
+        Sink,
+        Stream,
+    },
+    timer::{
+        Delay,
+        Interval,
+        Timeout,
+    },
+};
+
+#[cfg(test)]
+mod test;
+
+/// Metadata is sent in pieces of this size, except for the last
+pub const PIECE_SIZE: usize = 16 * 1024;
+/// Metadata bigger than this is refused, so a peer can't make us allocate as much as it likes
+pub const MAX_SIZE: usize = 16 * 1024 * 1024;
+/// What the extension is called in extension handshakes
+const NAME: &str = "ut_metadata";
+/// The extended message id we ask peers to send ut_metadata messages with
+const UT_METADATA: u8 = 1;
+/// How long a single peer gets to hand over the metadata
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most peers we ask at once
+const MAX_PEERS: usize = 20;
+/// How often peers that failed are asked again, and the trackers and DHT asked for more
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long we wait for the tracker to hear that we are stopping, once we have the metadata
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// What we tell trackers we have left, since we don't know the size yet.  Saying 0 would make us
+/// look like a seed
+const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    /// Could not talk to a peer
+    Io(io::Error),
+    /// A peer broke the protocol, or couldn't give us the metadata
+    #[error(msg_embedded, non_std, no_from)]
+    Protocol(String),
+    /// A peer took too long to give us the metadata
+    Timeout,
+    /// There are no peers to ask for the metadata
+    NoPeers,
+}
+
+/// A message of the ut_metadata extension
+#[derive(Debug, PartialEq)]
+pub enum MetadataMessage {
+    /// Asks for a piece of the metadata
+    Request(u32),
+    /// A piece of the metadata, with the size of the whole thing
+    Data(u32, usize, Vec<u8>),
+    /// The peer won't send us the piece
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data(piece, _, _) => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+        let mut map = hashmap! {
+            b"msg_type".to_vec() => Value::Integer(msg_type),
+            b"piece".to_vec() => Value::Integer(i64::from(*piece)),
+        };
+        match self {
+            // The piece itself comes straight after the dictionary
+            MetadataMessage::Data(_, total_size, data) => {
+                map.insert(b"total_size".to_vec(), Value::Integer(*total_size as i64));
+                let mut res = Value::Dict(map).encode();
+                res.extend_from_slice(data);
+                res
+            }
+            _ => Value::Dict(map).encode(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let (val, length) = Value::decode_prefix(bytes)
+            .map_err(|e| format!("Invalid ut_metadata message: {}", e))?;
+        let map = val.dict().ok_or("ut_metadata message not a dictionary".to_string())?;
+
+        let piece = map.get("piece".as_bytes()).and_then(Value::integer)
+            .map(|i| *i as u32)
+            .ok_or("Missing key: piece".to_string())?;
+
+        match map.get("msg_type".as_bytes()).and_then(Value::integer) {
+            Some(0) => Ok(MetadataMessage::Request(piece)),
+            Some(1) => {
+                let total_size = map.get("total_size".as_bytes()).and_then(Value::integer)
+                    .map(|i| *i as usize)
+                    .ok_or("Missing key: total_size".to_string())?;
+                Ok(MetadataMessage::Data(piece, total_size, bytes[length..].to_vec()))
+            }
+            Some(2) => Ok(MetadataMessage::Reject(piece)),
+            Some(msg_type) => Err(format!("Unknown msg_type: {}", msg_type)),
+            None => Err("Missing key: msg_type".to_string()),
+        }
+    }
+}
+
+/// Serves the metadata of a torrent we have to peers that ask for it
+pub struct UtMetadata {
+    metadata: Arc<Vec<u8>>,
+}
+
+impl UtMetadata {
+    pub fn new(metadata: Arc<Vec<u8>>) -> Self {
+        UtMetadata { metadata }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+        handshake.metadata_size = Some(self.metadata.len());
+    }
+
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) -> Result<(), String> {
+        let reply = match MetadataMessage::decode(payload)? {
+            MetadataMessage::Request(piece) => {
+                let start = piece as usize * PIECE_SIZE;
+                if start < self.metadata.len() {
+                    let end = usize::min(start + PIECE_SIZE, self.metadata.len());
+                    MetadataMessage::Data(piece, self.metadata.len(), self.metadata[start..end].to_vec())
+                } else {
+                    MetadataMessage::Reject(piece)
+                }
+            }
+            // We never ask for the metadata of a torrent we already have
+            _ => {
+                trace!("Ignoring an unrequested ut_metadata message");
+                return Ok(());
+            }
+        };
+        outbox.messages.push(reply.encode().into());
+        Ok(())
+    }
+}
+
+/// Our extension handshake, saying we speak ut_metadata
+fn extension_handshake() -> Bytes {
+    let handshake = ExtensionHandshake {
+        extensions: hashmap! { NAME.to_string() => UT_METADATA },
+        ..ExtensionHandshake::default()
+    };
+    handshake.to_value().encode().into()
+}
+
+/// Reads the id the peer wants ut_metadata messages sent with, and the size of the metadata
+fn parse_extension_handshake(payload: &[u8]) -> Result<(u8, usize), String> {
+    let val = Value::decode(payload).map_err(|e| format!("Invalid extension handshake: {}", e))?;
+    let handshake = ExtensionHandshake::from_value(&val)?;
+    // An id of 0 means the peer has turned the extension off
+    let id = handshake.extensions.get(NAME)
+        .filter(|id| **id > 0)
+        .ok_or("Peer does not support ut_metadata".to_string())?;
+    let size = handshake.metadata_size
+        .filter(|size| *size <= MAX_SIZE)
+        .ok_or("Peer did not give a usable metadata_size".to_string())?;
+    Ok((*id, size))
+}
+
+/// Downloads the metadata from a single peer
+pub struct MetadataConnection {
+    conn: Framed<TcpStream, MessageCodec>,
+    info_hash: [u8; 20],
+    handshake_received: bool,
+    // The id the peer wants ut_metadata messages sent with, once it has told us
+    peer_ut_metadata: Option<u8>,
+    // Where the pieces are put together
+    metadata: Vec<u8>,
+    // Which pieces of the metadata have arrived
+    received: Vec<bool>,
+    // Messages waiting for room in the connection's write buffer
+    outgoing: VecDeque<Message>,
+}
+
+impl MetadataConnection {
+    pub fn new(conn: TcpStream, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut outgoing = VecDeque::new();
+        outgoing.push_back(Message::Handshake(Handshake::from((info_hash, peer_id)).with_extensions()));
+        outgoing.push_back(Message::Extended(extension::HANDSHAKE_ID, extension_handshake()));
+        MetadataConnection {
+            conn: Framed::new(conn, MessageCodec::new()),
+            info_hash,
+            handshake_received: false,
+            peer_ut_metadata: None,
+            metadata: Vec::new(),
+            received: Vec::new(),
+            outgoing,
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> Result<(), MetadataError> {
+        match message {
+            Message::Handshake(handshake) => {
+                if handshake.info_hash != self.info_hash {
+                    return Err(MetadataError::Protocol("The info hash sent by the peer does not match ours".to_string()));
+                }
+                if !handshake.supports_extensions() {
+                    return Err(MetadataError::Protocol("Peer does not support the extension protocol".to_string()));
+                }
+                self.handshake_received = true;
+            }
+            _ if !self.handshake_received => {
+                return Err(MetadataError::Protocol("Peer sent a message before the handshake".to_string()));
+            }
+            Message::Extended(extension::HANDSHAKE_ID, payload) => self.handle_extension_handshake(&payload)?,
+            Message::Extended(UT_METADATA, payload) => {
+                let message = MetadataMessage::decode(&payload).map_err(MetadataError::Protocol)?;
+                self.handle_metadata_message(message)?;
+            }
+            _ => trace!("Ignoring a message while fetching metadata"),
+        }
+        Ok(())
+    }
+
+    /// Learns how to ask the peer for the metadata, and asks for all of it
+    fn handle_extension_handshake(&mut self, payload: &[u8]) -> Result<(), MetadataError> {
+        // Peers may send more handshakes later on, but we already know what we need
+        if self.peer_ut_metadata.is_some() {
+            return Ok(());
+        }
+        let (id, size) = parse_extension_handshake(payload).map_err(MetadataError::Protocol)?;
+        self.peer_ut_metadata = Some(id);
+        self.metadata = vec![0; size];
+        self.received = vec![false; self.metadata.chunks(PIECE_SIZE).count()];
+        for piece in 0..self.received.len() {
+            self.send_metadata(MetadataMessage::Request(piece as u32));
+        }
+        Ok(())
+    }
+
+    fn handle_metadata_message(&mut self, message: MetadataMessage) -> Result<(), MetadataError> {
+        match message {
+            // We don't have anything to give
+            MetadataMessage::Request(piece) => self.send_metadata(MetadataMessage::Reject(piece)),
+            MetadataMessage::Reject(piece) => {
+                return Err(MetadataError::Protocol(format!("Peer rejected our request for metadata piece {}", piece)));
+            }
+            MetadataMessage::Data(piece, total_size, data) => {
+                let start = piece as usize * PIECE_SIZE;
+                if total_size != self.metadata.len() || start >= self.metadata.len() {
+                    return Err(MetadataError::Protocol(format!("Peer sent metadata piece {}, which we did not ask for", piece)));
+                }
+                let end = usize::min(start + PIECE_SIZE, self.metadata.len());
+                if data.len() != end - start {
+                    return Err(MetadataError::Protocol(format!("Metadata piece {} is the wrong size", piece)));
+                }
+                self.metadata[start..end].copy_from_slice(&data);
+                self.received[piece as usize] = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a ut_metadata message, if the peer has told us how to send them
+    fn send_metadata(&mut self, message: MetadataMessage) {
+        if let Some(id) = self.peer_ut_metadata {
+            self.outgoing.push_back(Message::Extended(id, message.encode().into()));
+        }
+    }
+}
+
+impl Future for MetadataConnection {
+    type Item = Vec<u8>;
+    type Error = MetadataError;
+
+    /// Finishes with the metadata once every piece has arrived and it matches the info hash
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.conn.poll()? {
+                Async::Ready(Some(message)) => self.handle_message(message)?,
+                Async::Ready(None) => return Err(MetadataError::Protocol("Peer closed the connection".to_string())),
+                Async::NotReady => break,
+            }
+        }
+        if !self.received.is_empty() && self.received.iter().all(|received| *received) {
+            if sha1_hash(&self.metadata) != self.info_hash {
+                return Err(MetadataError::Protocol("The metadata does not match the info hash".to_string()));
+            }
+            return Ok(Async::Ready(mem::take(&mut self.metadata)));
+        }
+        while let Some(message) = self.outgoing.pop_front() {
+            if let AsyncSink::NotReady(message) = self.conn.start_send(message)? {
+                self.outgoing.push_front(message);
+                break;
+            }
+        }
+        self.conn.poll_complete()?;
+        Ok(Async::NotReady)
+    }
+}
+
+/// Connects to a peer and downloads the metadata from it, giving up after a while
+fn fetch_from(address: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20]) -> impl Future<Item=Vec<u8>, Error=MetadataError> {
+    let download = TcpStream::connect(&address)
+        .map_err(MetadataError::from)
+        .and_then(move |conn| MetadataConnection::new(conn, info_hash, peer_id));
+    Timeout::new(download, PEER_TIMEOUT).map_err(move |e| {
+        let e = e.into_inner().unwrap_or(MetadataError::Timeout);
+        debug!("Could not get the metadata from {}: {:?}", address, e);
+        e
+    })
+}
+
+/// A download of the metadata from one peer
+type Download = Box<dyn Future<Item=Vec<u8>, Error=MetadataError> + Send>;
+
+/// Asks peers for the metadata as they are found, until one of them hands it over.  Peers that
+/// fail are asked again every RETRY_INTERVAL, when the trackers and DHT are also asked for more
+struct Fetch<C> {
+    info_hash: [u8; 20],
+    // Starts downloading the metadata from a peer
+    connect: C,
+    // Peers waiting to be asked, in the order they were found
+    candidates: VecDeque<SocketAddr>,
+    // Every peer we have heard of, so none is queued twice
+    known: HashSet<SocketAddr>,
+    // Peers that couldn't give us the metadata, to be asked again on the next round
+    failed: Vec<SocketAddr>,
+    downloads: Vec<(SocketAddr, Download)>,
+    tracker: Option<Tracker>,
+    // Whether the tracker is working on an announce
+    announcing: bool,
+    // Whether every tracker failed the last announce
+    announce_failed: bool,
+    dht: Option<DhtHandle>,
+    // Peers found by the current DHT lookup
+    dht_peers: Option<UnboundedReceiver<SocketAddr>>,
+    retry_timer: Interval,
+    // Once we have the metadata, it waits here while the tracker hears that we are stopping
+    stopping: Option<(Vec<u8>, Delay)>,
+}
+
+impl<C> Fetch<C> where C: FnMut(SocketAddr) -> Download {
+    /// Starts the announce and DHT lookup.  The given peers are asked first, straight away
+    fn new(info_hash: [u8; 20], peers: &[SocketAddr], mut tracker: Option<Tracker>, dht: Option<DhtHandle>, connect: C) -> Self {
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.start(UNKNOWN_LEFT);
+        }
+        let dht_peers = dht.as_ref().map(|dht| dht.get_peers(info_hash, None));
+        let mut fetch = Fetch {
+            info_hash,
+            connect,
+            candidates: VecDeque::new(),
+            known: HashSet::new(),
+            failed: Vec::new(),
+            downloads: Vec::new(),
+            announcing: tracker.is_some(),
+            announce_failed: false,
+            tracker,
+            dht,
+            dht_peers,
+            retry_timer: Interval::new(Instant::now() + RETRY_INTERVAL, RETRY_INTERVAL),
+            stopping: None,
+        };
+        fetch.add_candidates(peers.iter().cloned());
+        fetch
+    }
+
+    /// Queues peers we haven't heard of before
+    fn add_candidates<I: IntoIterator<Item=SocketAddr>>(&mut self, peers: I) {
+        for peer in peers {
+            if self.known.insert(peer) {
+                self.candidates.push_back(peer);
+            }
+        }
+    }
+
+    /// Asks the peers that failed again, and the trackers and DHT for more
+    fn retry(&mut self) {
+        trace!("Asking {} peers for the metadata again", self.failed.len());
+        let failed = mem::take(&mut self.failed);
+        self.candidates.extend(failed);
+        if let (false, Some(tracker)) = (self.announcing, self.tracker.as_mut()) {
+            if self.announce_failed {
+                tracker.retry(UNKNOWN_LEFT, 0, 0);
+            } else {
+                tracker.refresh(UNKNOWN_LEFT, 0, 0);
+            }
+            self.announcing = true;
+        }
+        if let (None, Some(dht)) = (&self.dht_peers, &self.dht) {
+            self.dht_peers = Some(dht.get_peers(self.info_hash, None));
+        }
+    }
+
+    /// Takes the peers from an answered announce
+    fn handle_announce(&mut self, result: Result<TrackerResponse, TrackerError>) {
+        self.announcing = false;
+        self.announce_failed = false;
+        match result {
+            Ok(TrackerResponse::Success(resp)) | Ok(TrackerResponse::Warning(_, resp)) => {
+                self.add_candidates(resp.peers.into_iter().map(|peer| peer.address));
+            }
+            Ok(TrackerResponse::Failure(msg, _)) => {
+                warn!("The tracker refused our announce: {}", msg);
+                self.announce_failed = true;
+            }
+            Err(e) => {
+                warn!("Something went wrong in making a request to the tracker: {:?}", e);
+                self.announce_failed = true;
+            }
+        }
+    }
+
+    /// Tells the tracker we are leaving, now that we have what we came for
+    fn stop(&mut self, metadata: Vec<u8>) {
+        self.downloads.clear();
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.cancel(UNKNOWN_LEFT, 0, 0);
+        }
+        self.stopping = Some((metadata, Delay::new(Instant::now() + STOP_TIMEOUT)));
+    }
+}
+
+impl<C> Future for Fetch<C> where C: FnMut(SocketAddr) -> Download {
+    type Item = Vec<u8>;
+    type Error = MetadataError;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        if self.stopping.is_some() {
+            let answered = !matches!(self.tracker.as_mut().map(Future::poll), Some(Ok(Async::NotReady)));
+            let timed_out = !matches!(self.stopping.as_mut().map(|(_, timer)| timer.poll()), Some(Ok(Async::NotReady)));
+            if timed_out && !answered {
+                debug!("The tracker did not answer our stopped announce in time");
+            }
+            return match (answered || timed_out, self.stopping.take()) {
+                (true, Some((metadata, _))) => Ok(Async::Ready(metadata)),
+                (_, stopping) => {
+                    self.stopping = stopping;
+                    Ok(Async::NotReady)
+                }
+            };
+        }
+
+        if self.announcing {
+            match self.tracker.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(resp))) => self.handle_announce(Ok(resp)),
+                Some(Err(e)) => self.handle_announce(Err(e)),
+                _ => (),
+            }
+        }
+        loop {
+            match self.dht_peers.as_mut().map(Stream::poll) {
+                Some(Ok(Async::Ready(Some(peer)))) => self.add_candidates(Some(peer)),
+                Some(Ok(Async::NotReady)) | None => break,
+                // The lookup is over
+                _ => {
+                    self.dht_peers = None;
+                    break;
+                }
+            }
+        }
+        loop {
+            match self.retry_timer.poll() {
+                Ok(Async::Ready(Some(_))) => self.retry(),
+                Err(e) => {
+                    warn!("Metadata retry timer failed: {}", e);
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        // Keep going while downloads fail, so their places go to the next candidates
+        loop {
+            while self.downloads.len() < MAX_PEERS {
+                match self.candidates.pop_front() {
+                    Some(address) => {
+                        let download = (self.connect)(address);
+                        self.downloads.push((address, download));
+                    }
+                    None => break,
+                }
+            }
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.downloads.len() {
+                match self.downloads[i].1.poll() {
+                    Ok(Async::NotReady) => i += 1,
+                    Ok(Async::Ready(metadata)) => {
+                        self.stop(metadata);
+                        // Poll the stopped announce, or finish straight away without a tracker
+                        return self.poll();
+                    }
+                    Err(_) => {
+                        let (address, _) = self.downloads.swap_remove(i);
+                        self.failed.push(address);
+                        failed = true;
+                    }
+                }
+            }
+            if !failed || self.candidates.is_empty() {
+                break;
+            }
+        }
+
+        let searching = self.tracker.is_some() || self.dht.is_some();
+        if !searching && self.known.is_empty() {
+            return Err(MetadataError::NoPeers);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Finds peers through the magnet link's trackers and peer addresses, and the DHT if there is a
+/// node, and builds the metainfo from the metadata of whichever peer hands it over first
+pub fn fetch(peer_id: [u8; 20], magnet: &Magnet, port: u16, dht: Option<DhtHandle>) -> impl Future<Item=MetaInfo, Error=MetadataError> {
+    let info_hash = magnet.info_hash;
+    let tiers = magnet.tiers();
+    let tracker = if tiers.is_empty() {
+        None
+    } else {
+        Some(Tracker::new(peer_id, tiers.clone(), info_hash, port))
+    };
+    let connect = move |address| -> Download { Box::new(fetch_from(address, info_hash, peer_id)) };
+    Fetch::new(info_hash, &magnet.peers, tracker, dht, connect)
+        .and_then(move |metadata| MetaInfo::from_info(&metadata, tiers).map_err(MetadataError::Protocol))
+}
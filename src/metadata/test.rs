@@ -0,0 +1,161 @@
+use bytes::BytesMut;
+use futures::future;
+use crate::boostencode::FromValue;
+use crate::metainfo::InfoDict;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use super::*;
+use tokio::codec::{Decoder, Encoder};
+
+/// An info dictionary big enough to take two pieces
+fn info() -> Vec<u8> {
+    Value::Dict(hashmap! {
+        b"name".to_vec() => Value::BString(b"file".to_vec()),
+        b"length".to_vec() => Value::Integer(1000 * 16 * 1024),
+        b"piece length".to_vec() => Value::Integer(16 * 1024),
+        b"pieces".to_vec() => Value::BString(vec![7; 1000 * 20]),
+    }).encode()
+}
+
+/// Reads messages off a blocking socket until one can be decoded
+fn read_message(remote: &mut std::net::TcpStream, codec: &mut MessageCodec, buf: &mut BytesMut) -> Message {
+    loop {
+        if let Some(message) = codec.decode(buf).unwrap() {
+            return message;
+        }
+        let mut bytes = [0; 4096];
+        let read = remote.read(&mut bytes).unwrap();
+        assert!(read > 0, "connection closed");
+        buf.extend_from_slice(&bytes[..read]);
+    }
+}
+
+fn write_message(remote: &mut std::net::TcpStream, message: Message) {
+    let mut buf = BytesMut::new();
+    MessageCodec::new().encode(message, &mut buf).unwrap();
+    remote.write_all(&buf).unwrap();
+}
+
+/// Plays a peer that has the metadata, serving it with ut_metadata id 3
+fn serve_metadata(metadata: Vec<u8>, info_hash: [u8; 20]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut remote, _) = listener.accept().unwrap();
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        match read_message(&mut remote, &mut codec, &mut buf) {
+            Message::Handshake(handshake) => assert!(handshake.supports_extensions()),
+            other => panic!("expected a handshake, got {:?}", other),
+        }
+        write_message(&mut remote, Message::Handshake(Handshake::from((info_hash, [9; 20])).with_extensions()));
+        let handshake = Value::Dict(hashmap! {
+            b"m".to_vec() => Value::Dict(hashmap! { b"ut_metadata".to_vec() => Value::Integer(3) }),
+            b"metadata_size".to_vec() => Value::Integer(metadata.len() as i64),
+        });
+        write_message(&mut remote, Message::Extended(0, handshake.encode().into()));
+        loop {
+            match read_message(&mut remote, &mut codec, &mut buf) {
+                Message::Extended(3, payload) => match MetadataMessage::decode(&payload).unwrap() {
+                    MetadataMessage::Request(piece) => {
+                        let start = piece as usize * PIECE_SIZE;
+                        let end = usize::min(start + PIECE_SIZE, metadata.len());
+                        let data = MetadataMessage::Data(piece, metadata.len(), metadata[start..end].to_vec());
+                        write_message(&mut remote, Message::Extended(UT_METADATA, data.encode().into()));
+                    }
+                    other => panic!("unexpected {:?}", other),
+                },
+                Message::Extended(0, _) => (),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    });
+    address
+}
+
+/// Starts fetching the metadata from the peer at address
+fn connect(address: SocketAddr, info_hash: [u8; 20]) -> MetadataConnection {
+    let conn = std::net::TcpStream::connect(address).unwrap();
+    let conn = TcpStream::from_std(conn, &tokio::reactor::Handle::default()).unwrap();
+    MetadataConnection::new(conn, info_hash, [2; 20])
+}
+
+#[test]
+fn test_metadata_message() {
+    let data = MetadataMessage::Data(2, 40_000, vec![1, 2, 3]);
+    let encoded = data.encode();
+    assert!(encoded.starts_with(b"d8:msg_typei1e5:piecei2e10:total_sizei40000ee"));
+    assert!(encoded.ends_with(&[1, 2, 3]));
+    assert_eq!(MetadataMessage::decode(&encoded), Ok(data));
+    assert_eq!(MetadataMessage::decode(&MetadataMessage::Request(5).encode()), Ok(MetadataMessage::Request(5)));
+    assert_eq!(MetadataMessage::decode(&MetadataMessage::Reject(1).encode()), Ok(MetadataMessage::Reject(1)));
+    assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+}
+
+#[test]
+fn test_fetch_from_peer() {
+    let metadata = info();
+    let info_hash = sha1_hash(&metadata);
+    let address = serve_metadata(metadata.clone(), info_hash);
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let fetched = runtime.block_on(connect(address, info_hash)).unwrap();
+    assert_eq!(fetched, metadata);
+
+    let meta = MetaInfo::from_info(&fetched, vec![vec!["http://example.com/announce".to_string()]]).unwrap();
+    assert_eq!(meta.info_hash, info_hash);
+    assert_eq!(meta.info, InfoDict::from_value(&Value::decode(&metadata).unwrap()).unwrap());
+    assert_eq!(meta.announce, Some("http://example.com/announce".to_string()));
+}
+
+#[test]
+fn test_fetch_wrong_metadata() {
+    let metadata = info();
+    let mut info_hash = sha1_hash(&metadata);
+    info_hash[0] ^= 1;
+    let address = serve_metadata(metadata, info_hash);
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    match runtime.block_on(connect(address, info_hash)) {
+        Err(MetadataError::Protocol(_)) => (),
+        other => panic!("expected a protocol error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_fetch_retries_peers() {
+    let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let connect = {
+        let asked = asked.clone();
+        move |address| -> Download {
+            let mut asked = asked.lock().unwrap();
+            asked.push(address);
+            // the second peer only has the metadata the second time it is asked
+            if address == second && asked.len() > 2 {
+                Box::new(future::ok(vec![1, 2, 3]))
+            } else {
+                Box::new(future::err(MetadataError::Timeout))
+            }
+        }
+    };
+    let mut fetch = Fetch::new([1; 20], &[first, second, first], None, None, connect);
+    fetch.retry_timer = Interval::new_interval(Duration::from_millis(10));
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    assert_eq!(runtime.block_on(fetch).unwrap(), vec![1, 2, 3]);
+    // the peers from the magnet link are asked first, in order, and again after they fail
+    assert_eq!(*asked.lock().unwrap(), vec![first, second, first, second]);
+}
+
+#[test]
+fn test_fetch_without_peers() {
+    let fetch = Fetch::new([1; 20], &[], None, None, |_| -> Download { panic!("there is no one to ask") });
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    match runtime.block_on(fetch) {
+        Err(MetadataError::NoPeers) => (),
+        other => panic!("expected NoPeers, got {:?}", other),
+    }
+}
@@ -2,7 +2,9 @@
 use crate::boostencode::{FromValue, Value};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use maplit::hashmap;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 #[cfg(test)]
 mod test;
@@ -47,12 +49,16 @@ pub struct InfoDict {
 pub struct MetaInfo {
     // The SHA1 hash of the value of the info key in the torrent file
     pub info_hash: [u8; 20],
+    // The info dictionary exactly as it was hashed, which is what ut_metadata hands out
+    pub info_bytes: Vec<u8>,
     // Information about the file to be downloaded
     pub info: InfoDict,
-    // The url for the tracker
-    pub announce: String,
+    // The url for the tracker.  Trackerless torrents find their peers through the DHT instead
+    pub announce: Option<String>,
     // An optional list of more trackers, the lower the usize the higher priority
     pub announce_list: Option<Vec<(usize, String)>>,
+    // DHT nodes to join through, as host and port (BEP 5)
+    pub nodes: Vec<(String, u16)>,
     // The UNIX epoch timestamp of when this torrent was created
     pub creation_date: Option<u64>,
     // Free-form textual comments of the author
@@ -72,6 +78,8 @@ impl FromValue for SingleFile {
 
         let file_name = map.get("name".as_bytes()).and_then(Value::bstring_utf8)
             .ok_or("Missing key: name".to_string())?;
+        MultiFile::check_path_component(&file_name)
+            .map_err(|e| format!("Invalid file name: {}", e))?;
 
         let length = map.get("length".as_bytes()).and_then(Value::integer)
             .map(|i| *i as usize).ok_or("Missing key: length".to_string())?;
@@ -89,8 +97,73 @@ impl FromValue for SingleFile {
 impl FromValue for MultiFile {
     type Error = String;
 
-    fn from_value(_val: &Value) -> Result<Self, Self::Error> where Self: Sized {
-        unimplemented!()
+    fn from_value(val: &Value) -> Result<Self, Self::Error> where Self: Sized {
+        let map = val.dict().ok_or("Multi file not a dictionary".to_string())?;
+
+        let root_dir_name = map.get("name".as_bytes()).and_then(Value::bstring_utf8)
+            .ok_or("Missing key: name".to_string())?;
+        MultiFile::check_path_component(&root_dir_name)
+            .map_err(|e| format!("Invalid root directory name: {}", e))?;
+
+        let files = map.get("files".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: files".to_string())?
+            .iter()
+            .enumerate()
+            .map(|(i, file)| MultiFile::interpret_file(file)
+                .map_err(|e| format!("Invalid entry {} in files: {}", i, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if files.is_empty() {
+            return Err("Empty list: files".to_string());
+        }
+
+        Ok(MultiFile {
+            root_dir_name,
+            files,
+        })
+    }
+}
+
+impl MultiFile {
+    /// Parses a single entry of the files list.  The path components are joined with '/'
+    fn interpret_file(val: &Value) -> Result<SingleFile, String> {
+        let map = val.dict().ok_or("File not a dictionary".to_string())?;
+
+        let length = map.get("length".as_bytes()).and_then(Value::integer)
+            .ok_or("Missing key: length".to_string())?;
+        if *length < 0 {
+            return Err(format!("Negative length: {}", length));
+        }
+
+        let components = map.get("path".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: path".to_string())?
+            .iter()
+            .map(|component| component.bstring_utf8()
+                .ok_or("Path component not a utf8 string".to_string())
+                .and_then(|c| MultiFile::check_path_component(&c).map(|_| c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if components.is_empty() {
+            return Err("Empty list: path".to_string());
+        }
+
+        let md5sum = map.get("md5sum".as_bytes()).and_then(Value::bstring_utf8);
+
+        Ok(SingleFile {
+            file_name: components.join("/"),
+            length: *length as usize,
+            md5sum,
+        })
+    }
+
+    /// Makes sure a path component can not escape the torrent's directory
+    fn check_path_component(component: &str) -> Result<(), String> {
+        if component.is_empty() || component == "." || component == ".." {
+            Err(format!("Illegal path component: '{}'", component))
+        } else if component.contains('/') || component.contains('\\') || component.contains('\0') {
+            Err(format!("Path component contains a separator: '{}'", component))
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -115,6 +188,46 @@ impl FileInfo {
             FileInfo::Multi(m) => m.files.iter().fold(0, |a, h| a + h.length)
         }
     }
+
+    /// The name of the file, or of the root directory for multiple files
+    pub fn name(&self) -> &str {
+        match self {
+            FileInfo::Single(s) => &s.file_name,
+            FileInfo::Multi(m) => &m.root_dir_name,
+        }
+    }
+
+    /// Adds the keys describing the file(s) to an info dictionary
+    fn add_to(&self, map: &mut HashMap<Vec<u8>, Value>) {
+        match self {
+            FileInfo::Single(file) => {
+                map.insert(b"name".to_vec(), Value::BString(file.file_name.clone().into_bytes()));
+                map.insert(b"length".to_vec(), Value::Integer(file.length as i64));
+                if let Some(md5sum) = &file.md5sum {
+                    map.insert(b"md5sum".to_vec(), Value::BString(md5sum.clone().into_bytes()));
+                }
+            }
+            FileInfo::Multi(multi) => {
+                let files = multi.files.iter()
+                    .map(|file| {
+                        let path = file.file_name.split('/')
+                            .map(|component| Value::BString(component.as_bytes().to_vec()))
+                            .collect();
+                        let mut entry = hashmap! {
+                            b"length".to_vec() => Value::Integer(file.length as i64),
+                            b"path".to_vec() => Value::List(path),
+                        };
+                        if let Some(md5sum) = &file.md5sum {
+                            entry.insert(b"md5sum".to_vec(), Value::BString(md5sum.clone().into_bytes()));
+                        }
+                        Value::Dict(entry)
+                    })
+                    .collect();
+                map.insert(b"name".to_vec(), Value::BString(multi.root_dir_name.clone().into_bytes()));
+                map.insert(b"files".to_vec(), Value::List(files));
+            }
+        }
+    }
 }
 
 impl FromValue for InfoDict {
@@ -149,6 +262,56 @@ impl FromValue for InfoDict {
     }
 }
 
+impl InfoDict {
+    /// The info dictionary as it appears in a .torrent file.  Its encoding is what the info hash is
+    /// taken from
+    pub fn to_value(&self) -> Value {
+        let pieces = self.pieces.iter()
+            .flat_map(|hex| (0..hex.len() / 2)
+                .map(move |i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap_or(0)))
+            .collect();
+        let mut map = hashmap! {
+            b"piece length".to_vec() => Value::Integer(self.piece_length as i64),
+            b"pieces".to_vec() => Value::BString(pieces),
+        };
+        if self.private {
+            map.insert(b"private".to_vec(), Value::Integer(1));
+        }
+        self.file_info.add_to(&mut map);
+        Value::Dict(map)
+    }
+
+    /// The number of pieces in the torrent
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Gets the size of a piece in bytes.  Every piece is piece_length bytes except for the last,
+    /// which holds whatever is left over
+    pub fn piece_size(&self, index: usize) -> usize {
+        let total = self.file_info.size();
+        let start = index * self.piece_length;
+        if start >= total {
+            0
+        } else {
+            usize::min(self.piece_length, total - start)
+        }
+    }
+
+    /// Gets the raw SHA1 hash of a piece
+    pub fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        let hex = self.pieces.get(index)?;
+        if hex.len() != 40 {
+            return None;
+        }
+        let mut res = [0u8; 20];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(res)
+    }
+}
+
 impl FromValue for MetaInfo {
     type Error = String;
 
@@ -156,14 +319,19 @@ impl FromValue for MetaInfo {
         let map = val.dict().ok_or("Not a dictionary".to_string())?;
 
         let info_val = map.get("info".as_bytes()).ok_or("Missing key: info".to_string())?;
-        let info_hash = sha1_hash(&info_val.clone().encode());
+        let info_bytes = info_val.encode();
+        let info_hash = sha1_hash(&info_bytes);
         let info = InfoDict::from_value(info_val)?;
 
-        let announce = map.get("announce".as_bytes()).and_then(Value::bstring_utf8).ok_or("Missing key: announce".to_string())?;
+        let announce = map.get("announce".as_bytes()).and_then(Value::bstring_utf8);
 
         let announce_list = map.get("announce-list".as_bytes()).and_then(Value::list)
             .and_then(MetaInfo::interpret_announce_list);
 
+        let nodes = map.get("nodes".as_bytes()).and_then(Value::list)
+            .map(|nodes| MetaInfo::interpret_nodes(nodes))
+            .unwrap_or_default();
+
         let creation_date = map.get("creation date".as_bytes()).and_then(Value::integer)
             .map(|i| *i as u64);
 
@@ -175,9 +343,11 @@ impl FromValue for MetaInfo {
 
         Ok(MetaInfo {
             info_hash,
+            info_bytes,
             info,
             announce,
             announce_list,
+            nodes,
             creation_date,
             comment,
             created_by,
@@ -187,6 +357,118 @@ impl FromValue for MetaInfo {
 }
 
 impl MetaInfo {
+    /// Builds the metainfo for a torrent we only had the info hash of, from the bencoded info
+    /// dictionary downloaded from peers and trackers found some other way
+    pub fn from_info(info: &[u8], tiers: Vec<Vec<String>>) -> Result<Self, String> {
+        let info_val = Value::decode(info).map_err(|e| format!("Invalid info dictionary: {}", e))?;
+        let info_hash = sha1_hash(info);
+        let info_bytes = info.to_vec();
+        let info = InfoDict::from_value(&info_val)?;
+        let announce = tiers.iter().flatten().next().cloned();
+        let announce_list = if tiers.iter().flatten().count() > 1 {
+            Some(tiers.into_iter()
+                .enumerate()
+                .flat_map(|(i, tier)| tier.into_iter().map(move |uri| (i, uri)))
+                .collect())
+        } else {
+            None
+        };
+
+        Ok(MetaInfo {
+            info_hash,
+            info_bytes,
+            info,
+            announce,
+            announce_list,
+            nodes: Vec::new(),
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+        })
+    }
+
+    /// The whole .torrent file.  Encode it to write it out
+    pub fn to_value(&self) -> Value {
+        let mut map = hashmap! {
+            b"info".to_vec() => self.info.to_value(),
+        };
+        if let Some(announce) = &self.announce {
+            map.insert(b"announce".to_vec(), Value::BString(announce.clone().into_bytes()));
+        }
+        if self.announce_list.is_some() {
+            let tiers = self.tiers().into_iter()
+                .map(|tier| Value::List(tier.into_iter().map(|uri| Value::BString(uri.into_bytes())).collect()))
+                .collect();
+            map.insert(b"announce-list".to_vec(), Value::List(tiers));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self.nodes.iter()
+                .map(|(host, port)| Value::List(vec![
+                    Value::BString(host.clone().into_bytes()),
+                    Value::Integer(i64::from(*port)),
+                ]))
+                .collect();
+            map.insert(b"nodes".to_vec(), Value::List(nodes));
+        }
+        if let Some(date) = self.creation_date {
+            map.insert(b"creation date".to_vec(), Value::Integer(date as i64));
+        }
+        let strings = vec![
+            ("comment", &self.comment),
+            ("created by", &self.created_by),
+            ("encoding", &self.encoding),
+        ];
+        for (key, string) in strings {
+            if let Some(string) = string {
+                map.insert(key.as_bytes().to_vec(), Value::BString(string.clone().into_bytes()));
+            }
+        }
+        Value::Dict(map)
+    }
+
+    /// The trackers to use, grouped into tiers.  Per BEP 12, announce is only used when there is no
+    /// announce-list
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+        for (tier, uri) in self.announce_list.iter().flatten() {
+            while tiers.len() <= *tier {
+                tiers.push(Vec::new());
+            }
+            tiers[*tier].push(uri.clone());
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        if let (true, Some(announce)) = (tiers.is_empty(), &self.announce) {
+            tiers.push(vec![announce.clone()]);
+        }
+        tiers
+    }
+
+    /// The torrent's DHT nodes as host:port, the way the DHT takes its bootstrap nodes
+    pub fn bootstrap_nodes(&self) -> Vec<String> {
+        self.nodes.iter()
+            .map(|(host, port)| if host.contains(':') {
+                // IPv6 addresses need brackets to tell them apart from the port
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            })
+            .collect()
+    }
+
+    /// Reads a list of [host, port] pairs.  The nodes are only hints, so malformed ones are skipped
+    fn interpret_nodes(nodes: &[Value]) -> Vec<(String, u16)> {
+        nodes.iter()
+            .filter_map(|node| match node.list()?.as_slice() {
+                [host, port] => {
+                    let port = u16::try_from(*port.integer()?).ok().filter(|port| *port != 0)?;
+                    Some((host.bstring_utf8()?, port))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
     fn interpret_announce_list(tiers: &Vec<Value>) -> Option<Vec<(usize, String)>> {
         let mut res = Vec::new();
 
@@ -208,8 +490,7 @@ impl MetaInfo {
     }
 }
 
-
-fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
+pub fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
     let mut res = [0u8; 20];
     let mut hasher = Sha1::new();
     hasher.input(bytes);
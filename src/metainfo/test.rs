@@ -32,6 +32,7 @@ fn test_metainfo_from_value_valid() {
 
     assert_eq!(MetaInfo::from_value(&val), Ok(MetaInfo {
         info_hash: sha1_hash(info.encode().as_ref()),
+        info_bytes: info.encode(),
         info: InfoDict {
             piece_length: 20,
             pieces: vec!["00010203".to_string()],
@@ -42,11 +43,148 @@ fn test_metainfo_from_value_valid() {
                 md5sum: None,
             }),
         },
-        announce: "http://example.com".to_string(),
+        announce: Some("http://example.com".to_string()),
         announce_list: Some(vec![(0, "site1a".to_string()), (0, "site2a".to_string()), (1, "site1b".to_string()), (1, "site2b".to_string())]),
+        nodes: vec![],
         creation_date: None,
         comment: None,
         created_by: None,
         encoding: None,
     }));
-}
\ No newline at end of file
+}
+
+fn multi_file_info(files: Vec<Value>) -> Value {
+    Value::Dict(hashmap! {
+        bytes("piece length") => Value::Integer(20),
+        bytes("pieces") => Value::BString(vec![0, 1, 2, 3]),
+        bytes("name") => Value::BString(bytes("root")),
+        bytes("files") => Value::List(files),
+    })
+}
+
+fn file_entry(length: i64, path: &[&str]) -> Value {
+    Value::Dict(hashmap! {
+        bytes("length") => Value::Integer(length),
+        bytes("path") => Value::List(path.iter().map(|p| Value::BString(bytes(p))).collect()),
+    })
+}
+
+#[test]
+fn test_multi_file_from_value_valid() {
+    let mut with_md5 = file_entry(10, &["a.txt"]);
+    if let Value::Dict(map) = &mut with_md5 {
+        map.insert(bytes("md5sum"), Value::BString(bytes("d41d8cd98f00b204e9800998ecf8427e")));
+    }
+    let info = multi_file_info(vec![with_md5, file_entry(32, &["dir", "sub", "b.bin"])]);
+
+    assert_eq!(InfoDict::from_value(&info).map(|i| i.file_info), Ok(FileInfo::Multi(MultiFile {
+        root_dir_name: "root".to_string(),
+        files: vec![
+            SingleFile {
+                file_name: "a.txt".to_string(),
+                length: 10,
+                md5sum: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            },
+            SingleFile {
+                file_name: "dir/sub/b.bin".to_string(),
+                length: 32,
+                md5sum: None,
+            },
+        ],
+    })));
+}
+
+#[test]
+fn test_multi_file_from_value_invalid() {
+    // no files at all
+    assert!(MultiFile::from_value(&multi_file_info(vec![])).is_err());
+    // missing path
+    let no_path = Value::Dict(hashmap! { bytes("length") => Value::Integer(1) });
+    assert!(MultiFile::from_value(&multi_file_info(vec![no_path])).is_err());
+    // empty path
+    assert!(MultiFile::from_value(&multi_file_info(vec![file_entry(1, &[])])).is_err());
+    // negative length
+    assert!(MultiFile::from_value(&multi_file_info(vec![file_entry(-1, &["a"])])).is_err());
+    // escaping the root directory
+    assert!(MultiFile::from_value(&multi_file_info(vec![file_entry(1, &["..", "a"])])).is_err());
+    assert!(MultiFile::from_value(&multi_file_info(vec![file_entry(1, &["a/b"])])).is_err());
+    // the error says which entry was bad
+    let err = MultiFile::from_value(&multi_file_info(vec![file_entry(1, &["a"]), file_entry(1, &[""])]));
+    assert!(err.unwrap_err().contains("entry 1"));
+}
+
+#[test]
+fn test_single_file_from_value_invalid() {
+    let single_file_info = |name: &str| Value::Dict(hashmap! {
+        bytes("length") => Value::Integer(1),
+        bytes("name") => Value::BString(bytes(name)),
+    });
+    assert!(SingleFile::from_value(&single_file_info("a.txt")).is_ok());
+    // the name must not lead out of the download directory
+    assert!(SingleFile::from_value(&single_file_info("..")).is_err());
+    assert!(SingleFile::from_value(&single_file_info("../../.bashrc")).is_err());
+    assert!(SingleFile::from_value(&single_file_info("/etc/x")).is_err());
+    assert!(SingleFile::from_value(&single_file_info("")).is_err());
+}
+
+#[test]
+fn test_tiers() {
+    let mut meta = MetaInfo {
+        info_hash: [0; 20],
+        info_bytes: Vec::new(),
+        info: InfoDict {
+            piece_length: 1,
+            pieces: vec![],
+            private: false,
+            file_info: FileInfo::Single(SingleFile { file_name: "a".to_string(), length: 0, md5sum: None }),
+        },
+        announce: Some("http://primary".to_string()),
+        announce_list: None,
+        nodes: vec![],
+        creation_date: None,
+        comment: None,
+        created_by: None,
+        encoding: None,
+    };
+    assert_eq!(meta.tiers(), vec![vec!["http://primary".to_string()]]);
+
+    meta.announce_list = Some(vec![
+        (0, "http://a".to_string()),
+        (0, "http://b".to_string()),
+        (1, "udp://c".to_string()),
+    ]);
+    assert_eq!(meta.tiers(), vec![
+        vec!["http://a".to_string(), "http://b".to_string()],
+        vec!["udp://c".to_string()],
+    ]);
+}
+
+#[test]
+fn test_trackerless() {
+    let info = Value::Dict(hashmap! {
+        bytes("piece length") => Value::Integer(20),
+        bytes("pieces") => Value::BString(vec![0; 20]),
+        bytes("length") => Value::Integer(10),
+        bytes("name") => Value::BString(bytes("a")),
+    });
+    let node = |host: &str, port| Value::List(vec![Value::BString(bytes(host)), Value::Integer(port)]);
+    let torrent = Value::Dict(hashmap! {
+        bytes("info") => info,
+        bytes("nodes") => Value::List(vec![
+            node("router.example.com", 6881),
+            node("::1", 6882),
+            // not a node anyone could reach, so it is left out
+            node("10.0.0.1", 0),
+            Value::Integer(1),
+        ]),
+    });
+
+    let meta = MetaInfo::from_value(&Value::decode(&torrent.encode()).unwrap()).unwrap();
+    assert_eq!(meta.announce, None);
+    assert!(meta.tiers().is_empty());
+    assert_eq!(meta.nodes, vec![("router.example.com".to_string(), 6881), ("::1".to_string(), 6882)]);
+    assert_eq!(meta.bootstrap_nodes(), vec!["router.example.com:6881".to_string(), "[::1]:6882".to_string()]);
+
+    // written back out, it is still trackerless
+    assert_eq!(MetaInfo::from_value(&Value::decode(&meta.to_value().encode()).unwrap()), Ok(meta));
+}
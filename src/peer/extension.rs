@@ -0,0 +1,248 @@
+//! The extension protocol from BEP 10, which lets peers agree on extra messages to exchange
+use bytes::Bytes;
+use crate::boostencode::{FromValue, Value};
+use std::collections::HashMap;
+use std::net::{
+    IpAddr,
+    SocketAddr,
+};
+
+/// Extended message id 0 is always the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// How we introduce ourselves in handshakes
+const CLIENT: &str = concat!("boosttorrent2 ", env!("CARGO_PKG_VERSION"));
+
+/// What each side of a connection tells the other about the extensions it supports
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExtensionHandshake {
+    // The extensions the sender supports, and the id it wants each one's messages sent with.  An id
+    // of 0 turns the extension off
+    pub extensions: HashMap<String, u8>,
+    // The client's name and version
+    pub client: Option<String>,
+    // The port the sender listens for connections on
+    pub port: Option<u16>,
+    // The most requests the sender will queue before dropping more
+    pub max_requests: Option<u32>,
+    // The receiver's address, as the sender sees it
+    pub your_ip: Option<IpAddr>,
+    // The size of the info dictionary, from ut_metadata
+    pub metadata_size: Option<usize>,
+}
+
+impl FromValue for ExtensionHandshake {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> where Self: Sized {
+        let map = val.dict().ok_or("Extension handshake not a dictionary".to_string())?;
+
+        // Entries we can't make sense of are just extensions we don't know
+        let extensions = map.get("m".as_bytes()).and_then(Value::dict)
+            .map(|m| m.iter()
+                .filter_map(|(name, id)| {
+                    let name = String::from_utf8(name.clone()).ok()?;
+                    let id = id.integer().filter(|id| **id >= 0 && **id <= 255)?;
+                    Some((name, *id as u8))
+                })
+                .collect())
+            .unwrap_or_default();
+
+        let client = map.get("v".as_bytes()).and_then(Value::bstring)
+            .map(|v| String::from_utf8_lossy(v).into_owned());
+
+        let port = map.get("p".as_bytes()).and_then(Value::integer)
+            .filter(|p| **p > 0 && **p <= 65535)
+            .map(|p| *p as u16);
+
+        let max_requests = map.get("reqq".as_bytes()).and_then(Value::integer)
+            .filter(|r| **r > 0)
+            .map(|r| *r as u32);
+
+        let your_ip = map.get("yourip".as_bytes()).and_then(Value::bstring)
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from([ip[0], ip[1], ip[2], ip[3]])),
+                16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(ip);
+                    Some(IpAddr::from(octets))
+                }
+                _ => None,
+            });
+
+        let metadata_size = map.get("metadata_size".as_bytes()).and_then(Value::integer)
+            .filter(|s| **s > 0)
+            .map(|s| *s as usize);
+
+        Ok(ExtensionHandshake {
+            extensions,
+            client,
+            port,
+            max_requests,
+            your_ip,
+            metadata_size,
+        })
+    }
+}
+
+impl ExtensionHandshake {
+    pub fn to_value(&self) -> Value {
+        let m = self.extensions.iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Integer(i64::from(*id))))
+            .collect();
+        let mut map = HashMap::new();
+        map.insert(b"m".to_vec(), Value::Dict(m));
+        if let Some(client) = &self.client {
+            map.insert(b"v".to_vec(), Value::BString(client.as_bytes().to_vec()));
+        }
+        if let Some(port) = self.port {
+            map.insert(b"p".to_vec(), Value::Integer(i64::from(port)));
+        }
+        if let Some(max_requests) = self.max_requests {
+            map.insert(b"reqq".to_vec(), Value::Integer(i64::from(max_requests)));
+        }
+        if let Some(ip) = self.your_ip {
+            let octets = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            map.insert(b"yourip".to_vec(), Value::BString(octets));
+        }
+        if let Some(size) = self.metadata_size {
+            map.insert(b"metadata_size".to_vec(), Value::Integer(size as i64));
+        }
+        Value::Dict(map)
+    }
+}
+
+/// Messages for the peer, with the extended message ids to send them with
+pub type Replies = Vec<(u8, Bytes)>;
+
+/// Another peer we are connected to, as the server describes it to extensions that gossip about
+/// the swarm
+#[derive(Debug, PartialEq, Clone)]
+pub struct SwarmPeer {
+    // Where the peer accepts connections
+    pub address: SocketAddr,
+    // The peer has every piece
+    pub seed: bool,
+    // We reached the peer by connecting to it, so others should be able to as well
+    pub connectable: bool,
+}
+
+/// What an extension wants done after handling a message
+#[derive(Debug, PartialEq, Default)]
+pub struct Outbox {
+    // Messages to send back to the peer
+    pub messages: Vec<Bytes>,
+    // Other peers the extension heard about, which we might connect to
+    pub peers: Vec<SocketAddr>,
+}
+
+/// An extension that can be plugged into peer connections
+pub trait Extension: Send {
+    /// The name the extension goes by in the handshake
+    fn name(&self) -> &'static str;
+
+    /// Adds anything the extension has to say to our handshake
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// Sees the peer's handshake.  Only called if the peer supports the extension
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) {}
+
+    /// Handles a message for this extension.  Returns Err if the peer broke the extension's
+    /// protocol
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) -> Result<(), String>;
+
+    /// Hears about the other peers we are connected to.  Anything pushed onto messages is sent to
+    /// the peer.  Only called if the peer supports the extension
+    fn on_swarm(&mut self, _swarm: &[SwarmPeer], _messages: &mut Vec<Bytes>) {}
+}
+
+/// The extensions a connection supports, and which of them the peer supports too.  Our id for
+/// each extension is its position in the registry, starting at 1
+pub struct Registry {
+    extensions: Vec<Box<dyn Extension>>,
+    // The ids the peer wants messages for each extension sent with
+    peer_ids: HashMap<String, u8>,
+    // The port we listen for connections on, if we do
+    port: Option<u16>,
+}
+
+impl Registry {
+    pub fn new(port: Option<u16>) -> Self {
+        Registry {
+            extensions: Vec::new(),
+            peer_ids: HashMap::new(),
+            port,
+        }
+    }
+
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    /// Our handshake for a peer at the address, which queues up to max_requests of its requests
+    pub fn handshake(&self, peer_ip: IpAddr, max_requests: u32) -> ExtensionHandshake {
+        let mut handshake = ExtensionHandshake {
+            client: Some(CLIENT.to_string()),
+            port: self.port,
+            max_requests: Some(max_requests),
+            your_ip: Some(peer_ip),
+            ..ExtensionHandshake::default()
+        };
+        for (i, extension) in self.extensions.iter().enumerate() {
+            handshake.extensions.insert(extension.name().to_string(), i as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// Learns which extensions the peer supports from its handshake.  Later handshakes may turn
+    /// extensions on or off
+    pub fn handle_handshake(&mut self, handshake: &ExtensionHandshake) {
+        for (name, id) in &handshake.extensions {
+            if *id == 0 {
+                self.peer_ids.remove(name);
+            } else {
+                self.peer_ids.insert(name.clone(), *id);
+            }
+        }
+        for extension in self.extensions.iter_mut() {
+            if self.peer_ids.contains_key(extension.name()) {
+                extension.on_handshake(handshake);
+            }
+        }
+    }
+
+    /// Passes a message on to the extension it was sent to.  Returns the replies, with the ids to
+    /// send them with, and any peers the extension heard about
+    pub fn handle_message(&mut self, id: u8, payload: &[u8]) -> Result<(Replies, Vec<SocketAddr>), String> {
+        let extensions = &mut self.extensions;
+        let extension = match (id as usize).checked_sub(1).and_then(|i| extensions.get_mut(i)) {
+            Some(extension) => extension,
+            None => return Err(format!("Unknown extended message id {}", id)),
+        };
+        let mut outbox = Outbox::default();
+        extension.on_message(payload, &mut outbox)?;
+        // The peer can't be sent anything for an extension it doesn't support
+        let replies = match self.peer_ids.get(extension.name()) {
+            Some(peer_id) => outbox.messages.into_iter().map(|reply| (*peer_id, reply)).collect(),
+            None => Vec::new(),
+        };
+        Ok((replies, outbox.peers))
+    }
+
+    /// Tells the extensions the peer supports about the rest of the swarm.  Returns the messages
+    /// they want sent, with the ids to send them with
+    pub fn update_swarm(&mut self, swarm: &[SwarmPeer]) -> Replies {
+        let mut res = Vec::new();
+        for extension in self.extensions.iter_mut() {
+            if let Some(peer_id) = self.peer_ids.get(extension.name()) {
+                let mut messages = Vec::new();
+                extension.on_swarm(swarm, &mut messages);
+                res.extend(messages.into_iter().map(|message| (*peer_id, message)));
+            }
+        }
+        res
+    }
+}
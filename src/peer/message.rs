@@ -1,13 +1,17 @@
 use byteorder::{ByteOrder, NetworkEndian};
 use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
+use crate::metadata;
+use crate::piece::BLOCK_SIZE;
 use derive_error::Error;
+use log::trace;
 use std::io;
 use tokio::codec::{Decoder, Encoder};
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct Request {
-    index: u32,
-    begin: u32,
-    length: u32,
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
 impl From<(u32, u32, u32)> for Request {
@@ -16,10 +20,11 @@ impl From<(u32, u32, u32)> for Request {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct Piece {
-    index: u32,
-    begin: u32,
-    block: Bytes,
+    pub index: u32,
+    pub begin: u32,
+    pub block: Bytes,
 }
 
 impl Piece {
@@ -28,7 +33,10 @@ impl Piece {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct Handshake {
+    // Each set bit says the client supports some extension to the protocol
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -36,14 +44,41 @@ pub struct Handshake {
 impl From<([u8; 20], [u8; 20])> for Handshake {
     fn from(pair: ([u8; 20], [u8; 20])) -> Self {
         Handshake {
+            reserved: [0; 8],
             info_hash: pair.0,
             peer_id: pair.1,
         }
     }
 }
 
+impl Handshake {
+    /// Advertises support for the extension protocol from BEP 10
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    /// Whether the peer speaks the extension protocol from BEP 10
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Advertises support for the Fast extension from BEP 6
+    pub fn with_fast(mut self) -> Self {
+        self.reserved[7] |= 0x04;
+        self
+    }
+
+    /// Whether the peer speaks the Fast extension from BEP 6
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Message {
     Handshake(Handshake),
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -53,14 +88,43 @@ pub enum Message {
     Request(Request),
     Piece(Piece),
     Cancel(Request),
+    /// The sender thinks the receiver would do well to download this piece next.  Fast only
+    SuggestPiece(u32),
+    /// The sender has every piece, in place of a bitfield.  Fast only
+    HaveAll,
+    /// The sender has no pieces, in place of a bitfield.  Fast only
+    HaveNone,
+    /// The sender will not answer this request.  Fast only
+    RejectRequest(Request),
+    /// The receiver may request this piece even while choked.  Fast only
+    AllowedFast(u32),
+    /// A message of the extension protocol.  Holds the extended message id and the payload
+    Extended(u8, Bytes),
 }
 
-pub struct MessageCodec;
+pub struct MessageCodec {
+    // The handshake is always the first message in each direction, and has a different format
+    // from every message after it
+    handshake_received: bool,
+}
 
 
 impl MessageCodec {
     pub fn new() -> MessageCodec {
-        MessageCodec {}
+        MessageCodec {
+            handshake_received: false,
+        }
+    }
+}
+
+/// The longest message with the id given we will accept, so a peer can't make us buffer as much
+/// as it likes.  Blocks are never bigger than we ask for, bitfields never longer than the biggest
+/// metadata we accept could need, and extended messages never bigger than that metadata
+fn max_length(type_id: u8) -> usize {
+    match type_id {
+        5 => 1 + (metadata::MAX_SIZE / 20).div_ceil(8),
+        20 => 2 + metadata::MAX_SIZE,
+        _ => 9 + BLOCK_SIZE as usize,
     }
 }
 
@@ -69,7 +133,11 @@ impl Decoder for MessageCodec {
     type Error = io::Error;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
 
-        if src.len() >= (1 + 19 + 8 + 20 + 20) && &src[0..20] == b"\x13BitTorrent protocol" {
+        if !self.handshake_received {
+            if src.len() < (1 + 19 + 8 + 20 + 20) {
+                return Ok(None);
+            }
+            self.handshake_received = true;
             let src = src.split_to(1 + 19 + 8 + 20 + 20);
             let mut buf = src.into_buf();
             if buf.get_u8() != 19 {
@@ -82,8 +150,8 @@ impl Decoder for MessageCodec {
                 return Err(io::Error::new(io::ErrorKind::Other, "invalid protocol name"));
             }
 
-            // read reserved bytes
-            buf.copy_to_slice(&mut name[0..8]);
+            let mut reserved: [u8; 8] = [0; 8];
+            buf.copy_to_slice(&mut reserved);
 
             let mut info_hash: [u8; 20] = [0; 20];
             buf.copy_to_slice(&mut info_hash);
@@ -91,49 +159,92 @@ impl Decoder for MessageCodec {
             let mut peer_id: [u8; 20] = [0; 20];
             buf.copy_to_slice(&mut peer_id);
 
-            Ok(Some(Message::Handshake((info_hash, peer_id).into())))
+            Ok(Some(Message::Handshake(Handshake { reserved, info_hash, peer_id })))
         } else {
-            let length = NetworkEndian::read_u32(&src.split_to(4)) as usize;
-            if src.len() < length {
-                return Ok(None);
-            }
-            let mut buf = src.split_to(length).into_buf();
-            let type_id = buf.get_u8();
-
-            let message = match type_id {
-                0 => Some(Message::Choke),
-                1 => Some(Message::Unchoke),
-                2 => Some(Message::Interested),
-                3 => Some(Message::NotInterested),
-                4 => Some(Message::Have(buf.get_u32_be())),
-                5 => {
-                    let mut bytes = Vec::with_capacity(length - 1);
-                    buf.copy_to_slice(&mut bytes);
-                    Some(Message::Bitfield(bit_vec::BitVec::from_bytes(&bytes)))
+            loop {
+                if src.len() < 4 {
+                    return Ok(None);
+                }
+                let length = NetworkEndian::read_u32(&src[0..4]) as usize;
+                // Refuse an oversized message as soon as its id is known, without waiting for the rest
+                if length > max_length(20) || (src.len() > 4 && length > max_length(src[4])) {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Message too long"));
                 }
-                6 => {
-                    let index = buf.get_u32_be();
-                    let begin = buf.get_u32_be();
-                    let length = buf.get_u32_be();
-                    Some(Message::Request((index, begin, length).into()))
+                if src.len() < 4 + length {
+                    return Ok(None);
                 }
-                7 => {
-                    let index = buf.get_u32_be();
-                    let begin = buf.get_u32_be();
-                    let block = buf.collect();
-                    Some(Message::Piece(Piece::new(index, begin, block)))
+                src.advance(4);
+                if length == 0 {
+                    return Ok(Some(Message::KeepAlive));
                 }
-                8 => {
-                    let index = buf.get_u32_be();
-                    let begin = buf.get_u32_be();
-                    let length = buf.get_u32_be();
-                    Some(Message::Cancel((index, begin, length).into()))
+                let mut buf = src.split_to(length).into_buf();
+                let type_id = buf.get_u8();
+
+                let length_ok = match type_id {
+                    0..=3 | 14 | 15 => length == 1,
+                    4 | 13 | 17 => length == 5,
+                    6 | 8 | 16 => length == 13,
+                    7 => length >= 9,
+                    20 => length >= 2,
+                    _ => true,
+                };
+                if !length_ok {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Invalid message length"));
                 }
-                _ => None,
-            };
 
-            message.ok_or(io::Error::new(io::ErrorKind::Other, "Invalid message id"))
-                .map(|m| Some(m))
+                let message = match type_id {
+                    0 => Some(Message::Choke),
+                    1 => Some(Message::Unchoke),
+                    2 => Some(Message::Interested),
+                    3 => Some(Message::NotInterested),
+                    4 => Some(Message::Have(buf.get_u32_be())),
+                    5 => {
+                        let mut bytes = vec![0; length - 1];
+                        buf.copy_to_slice(&mut bytes);
+                        Some(Message::Bitfield(bit_vec::BitVec::from_bytes(&bytes)))
+                    }
+                    6 => {
+                        let index = buf.get_u32_be();
+                        let begin = buf.get_u32_be();
+                        let length = buf.get_u32_be();
+                        Some(Message::Request((index, begin, length).into()))
+                    }
+                    7 => {
+                        let index = buf.get_u32_be();
+                        let begin = buf.get_u32_be();
+                        let block = buf.collect();
+                        Some(Message::Piece(Piece::new(index, begin, block)))
+                    }
+                    8 => {
+                        let index = buf.get_u32_be();
+                        let begin = buf.get_u32_be();
+                        let length = buf.get_u32_be();
+                        Some(Message::Cancel((index, begin, length).into()))
+                    }
+                    13 => Some(Message::SuggestPiece(buf.get_u32_be())),
+                    14 => Some(Message::HaveAll),
+                    15 => Some(Message::HaveNone),
+                    16 => {
+                        let index = buf.get_u32_be();
+                        let begin = buf.get_u32_be();
+                        let length = buf.get_u32_be();
+                        Some(Message::RejectRequest((index, begin, length).into()))
+                    }
+                    17 => Some(Message::AllowedFast(buf.get_u32_be())),
+                    20 => {
+                        let id = buf.get_u8();
+                        Some(Message::Extended(id, buf.collect()))
+                    }
+                    _ => None,
+                };
+
+                match message {
+                    Some(message) => return Ok(Some(message)),
+                    // Messages of extensions we don't support, like the DHT's port message, are
+                    // skipped rather than treated as an error
+                    None => trace!("Ignoring a message with unknown id {}", type_id),
+                }
+            }
         }
     }
 }
@@ -149,10 +260,14 @@ impl Encoder for MessageCodec {
 
                 dst.put(19u8);
                 dst.put(b"BitTorrent protocol".as_ref());
-                dst.put([0u8; 8].as_ref());
+                dst.put(item.reserved.as_ref());
                 dst.put(item.info_hash.as_ref());
                 dst.put(item.peer_id.as_ref());
             },
+            Message::KeepAlive => {
+                dst.reserve(4);
+                dst.put_u32_be(0);
+            }
             Message::Choke => length_and_id(dst, 1, 0),
             Message::Unchoke => length_and_id(dst, 1, 1),
             Message::Interested => length_and_id(dst, 1, 2),
@@ -162,8 +277,9 @@ impl Encoder for MessageCodec {
                 dst.put_u32_be(piece_index);
             }
             Message::Bitfield(bit_vec) => {
-                length_and_id(dst, 1 + bit_vec.len() as u32, 5);
-                dst.put(&bit_vec.to_bytes());
+                let bytes = bit_vec.to_bytes();
+                length_and_id(dst, 1 + bytes.len() as u32, 5);
+                dst.put(&bytes);
             }
             Message::Request(request) => {
                 length_and_id(dst, 13, 6);
@@ -183,6 +299,27 @@ impl Encoder for MessageCodec {
                 dst.put_u32_be(request.begin);
                 dst.put_u32_be(request.length);
             }
+            Message::SuggestPiece(piece_index) => {
+                length_and_id(dst, 5, 13);
+                dst.put_u32_be(piece_index);
+            }
+            Message::HaveAll => length_and_id(dst, 1, 14),
+            Message::HaveNone => length_and_id(dst, 1, 15),
+            Message::RejectRequest(request) => {
+                length_and_id(dst, 13, 16);
+                dst.put_u32_be(request.index);
+                dst.put_u32_be(request.begin);
+                dst.put_u32_be(request.length);
+            }
+            Message::AllowedFast(piece_index) => {
+                length_and_id(dst, 5, 17);
+                dst.put_u32_be(piece_index);
+            }
+            Message::Extended(id, payload) => {
+                length_and_id(dst, 2 + payload.len() as u32, 20);
+                dst.put_u8(id);
+                dst.put(&payload);
+            }
         }
         Ok(())
     }
@@ -192,4 +329,4 @@ fn length_and_id(dst: &mut BytesMut, length: u32, id: u8) {
     dst.reserve((length + 4) as usize);
     dst.put_u32_be(length);
     dst.put_u8(id);
-}
\ No newline at end of file
+}
@@ -1,7 +1,16 @@
-use crate::piece::Piece;
+use crate::boostencode::{FromValue, Value};
+use crate::metainfo::sha1_hash;
+use crate::piece::{
+    BLOCK_SIZE,
+    Piece,
+};
+use crate::storage::Storage;
+use bytes::Bytes;
 use futures::sync::mpsc::{
     Receiver,
     Sender,
+    UnboundedReceiver,
+    UnboundedSender,
 };
 use tokio::{
     net::TcpStream,
@@ -15,47 +24,709 @@ use tokio::{
     codec::Framed,
 };
 use bit_vec::BitVec;
-use log::error;
+use log::{
+    error,
+    trace,
+    warn,
+};
+use std::cmp;
+use std::collections::VecDeque;
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    SocketAddr,
+};
+use std::sync::Arc;
+
+pub mod extension;
+pub mod message;
+#[cfg(test)]
+mod test;
+
+/// The most block requests from a peer we will hold on to before ignoring new ones
+const MAX_QUEUED_UPLOADS: usize = 256;
+/// How many pieces a peer with the Fast extension may download from us while choked
+const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Instructions the server can give to a running peer task
+pub enum PeerCommand {
+    /// We finished downloading and verifying a piece
+    Have(u32),
+    /// Another peer delivered this block during endgame
+    Block(u32, u32, Bytes),
+    /// Stop downloading this piece, it has been handed to someone else
+    Release(u32),
+    /// Stop uploading to the peer
+    Choke,
+    /// Start uploading to the peer
+    Unchoke,
+    /// The other peers we are connected to, for extensions that tell the peer about them
+    Swarm(Vec<extension::SwarmPeer>),
+}
+
+/// Things a running peer task tells the server about
+#[derive(Debug, PartialEq)]
+pub enum PeerEvent {
+    /// The peer's handshake arrived, with the id it gave
+    Handshake([u8; 20]),
+    /// The peer told us every piece it has
+    Bitfield(BitVec),
+    /// The peer got a new piece
+    Have(u32),
+    /// The peer stopped letting us download
+    Choked,
+    /// The peer started letting us download
+    Unchoked,
+    /// The peer wants to download from us
+    Interested,
+    /// The peer no longer wants to download from us
+    NotInterested,
+    /// The peer delivered a block of a piece we are downloading
+    Block(u32, u32, Bytes),
+    /// The peer told us the port it accepts connections on
+    ListenPort(u16),
+    /// The peer told us about other peers in the swarm
+    Peers(Vec<SocketAddr>),
+    /// The peer will let us download this piece even while it is choking us
+    AllowedFast(u32),
+}
+
+/// The channels a peer task talks to the server through
+pub struct PeerChannels {
+    // Bytes uploaded to and downloaded from the peer
+    pub uploaded: Sender<u32>,
+    pub downloaded: Sender<u32>,
+    // Verified pieces go back to the server here
+    pub finished_pieces: Sender<Piece>,
+    // The server hands us pieces to download here
+    pub pieces: Receiver<Piece>,
+    pub events: UnboundedSender<PeerEvent>,
+    pub commands: UnboundedReceiver<PeerCommand>,
+}
 
-mod message;
+/// How a peer task runs its connection
+pub struct PeerConfig {
+    // The extensions we support on the connection
+    pub extensions: extension::Registry,
+    // The most block requests to have in flight at once
+    pub max_requests: usize,
+    // We opened the connection, so we send our handshake first
+    pub initiates: bool,
+}
 
 /// A connection to a peer.  Can download pieces from this connection
 pub struct Peer {
     conn: Framed<TcpStream, message::MessageCodec>,
     uploaded_sender: Sender<u32>,
     downloaded_sender: Sender<u32>,
-    // When a piece is done, the peer will send the piece to the receiver, along with what pieces
-    // this peer has, and a way to send a new piece back
-    finished_piece_sender: Sender<(Piece, Sender<Piece>, BitVec)>,
+    // When a piece is done, the peer will send the piece to the server
+    finished_piece_sender: Sender<Piece>,
+    // The server hands us pieces to download through this channel
+    piece_receiver: Receiver<Piece>,
+    events: UnboundedSender<PeerEvent>,
+    commands: UnboundedReceiver<PeerCommand>,
+    // Messages waiting for room in the connection's write buffer
+    outgoing: VecDeque<message::Message>,
+    // Pieces we are currently downloading from this peer
+    pieces: Vec<Piece>,
+    // Finished pieces waiting for room in the finished_piece_sender channel
+    finished: VecDeque<Piece>,
+    // Block requests we have sent that have not been answered yet
+    requested: Vec<message::Request>,
+    // The most block requests we will have in flight at once
+    max_requests: usize,
+    // Bytes downloaded that have not been reported yet
+    downloaded: u32,
+    // Where verified pieces are read back from to serve uploads
+    storage: Arc<Storage>,
+    // Block requests from the peer that we have not answered yet
+    upload_queue: VecDeque<message::Request>,
+    // Bytes uploaded that have not been reported yet
+    uploaded: u32,
+    // The extensions we support on this connection
+    extensions: extension::Registry,
+    // Where the peer is connected from, which we tell it in the extension handshake
+    address: Option<SocketAddr>,
+    // The peer set the extension protocol bit in its handshake
+    peer_extensions: bool,
+    // Both sides set the Fast extension bit in their handshakes
+    fast: bool,
+    // Pieces we serve the peer even while choking it
+    allowed_fast: Vec<u32>,
+    // Pieces the peer serves us even while choking us
+    peer_allowed_fast: Vec<u32>,
     peers_pieces: BitVec,
+    our_pieces: BitVec,
     info_hash: [u8; 20],
     peer_id: [u8; 20],
     initiates: bool,
+    handshake_received: bool,
+    // The peer may still tell us which pieces it has with Bitfield, HaveAll or HaveNone, which is
+    // only allowed straight after the handshake
+    pieces_expected: bool,
+    // We are refusing to upload to the peer
+    am_choking: bool,
+    // We want to download from the peer
+    am_interested: bool,
+    // The peer is refusing to upload to us
+    peer_choking: bool,
+    // The peer wants to download from us
+    peer_interested: bool,
 }
 
 impl Peer {
     pub fn new(conn: TcpStream,
-               uploaded_sender: Sender<u32>,
-               downloaded_sender: Sender<u32>,
-               finished_piece_sender: Sender<(Piece, Sender<Piece>, BitVec)>,
+               channels: PeerChannels,
+               config: PeerConfig,
+               storage: Arc<Storage>,
+               our_pieces: BitVec,
                info_hash: [u8; 20],
-               peer_id: [u8; 20],
-               initiates: bool) -> Self {
-        let mut conn = Framed::new(conn, message::MessageCodec::new());
-        if initiates {
-            let _res = conn.start_send(message::Message::Handshake((info_hash.clone(), peer_id.clone()).into()));
-        }
-        Peer {
+               peer_id: [u8; 20]) -> Self {
+        let PeerConfig { extensions, max_requests, initiates } = config;
+        let address = conn.peer_addr().ok();
+        let conn = Framed::new(conn, message::MessageCodec::new());
+        let num_pieces = our_pieces.len();
+        let mut peer = Peer {
             conn,
-            uploaded_sender,
-            downloaded_sender,
-            finished_piece_sender,
-            peers_pieces: BitVec::new(),
+            uploaded_sender: channels.uploaded,
+            downloaded_sender: channels.downloaded,
+            finished_piece_sender: channels.finished_pieces,
+            piece_receiver: channels.pieces,
+            events: channels.events,
+            commands: channels.commands,
+            outgoing: VecDeque::new(),
+            pieces: Vec::new(),
+            finished: VecDeque::new(),
+            requested: Vec::new(),
+            max_requests,
+            downloaded: 0,
+            storage,
+            upload_queue: VecDeque::new(),
+            uploaded: 0,
+            extensions,
+            address,
+            peer_extensions: false,
+            fast: false,
+            allowed_fast: Vec::new(),
+            peer_allowed_fast: Vec::new(),
+            peers_pieces: BitVec::from_elem(num_pieces, false),
+            our_pieces,
             info_hash,
             peer_id,
             initiates,
+            handshake_received: false,
+            pieces_expected: false,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        };
+        if initiates {
+            let handshake = message::Handshake::from((info_hash, peer_id)).with_extensions().with_fast();
+            peer.send(message::Message::Handshake(handshake));
         }
+        peer
     }
+
+    /// Tells the server about something that happened on this connection
+    fn notify(&self, event: PeerEvent) {
+        // If the server is gone, the task will end when the command channel closes
+        let _ = self.events.unbounded_send(event);
+    }
+
+    /// Queues a message to be sent to the peer
+    fn send(&mut self, message: message::Message) {
+        self.outgoing.push_back(message);
+    }
+
+    /// Handles a single message from the peer.  Returns Err if the connection should be closed
+    fn handle_message(&mut self, message: message::Message) -> Result<(), ()> {
+        if !self.handshake_received {
+            return match message {
+                message::Message::Handshake(item) => self.handle_handshake(item),
+                _ => {
+                    error!("Peer sent a message before the handshake");
+                    Err(())
+                }
+            };
+        }
+
+        let fast_only = matches!(message,
+            message::Message::SuggestPiece(_) |
+            message::Message::HaveAll |
+            message::Message::HaveNone |
+            message::Message::RejectRequest(_) |
+            message::Message::AllowedFast(_));
+        if fast_only && !self.fast {
+            error!("Peer sent a Fast extension message without negotiating the extension");
+            return Err(());
+        }
+        let announces_pieces = matches!(message,
+            message::Message::Bitfield(_) |
+            message::Message::HaveAll |
+            message::Message::HaveNone);
+        if announces_pieces && !self.pieces_expected {
+            error!("Peer sent the pieces it has after other messages");
+            return Err(());
+        }
+        // Some clients send their extension handshake before their pieces, so only that and keep
+        // alives leave the window open
+        if !matches!(message,
+            message::Message::KeepAlive |
+            message::Message::Extended(extension::HANDSHAKE_ID, _)) {
+            self.pieces_expected = false;
+        }
+
+        match message {
+            message::Message::Handshake(_) => {
+                error!("Peer sent a second handshake");
+                return Err(());
+            }
+            message::Message::KeepAlive => (),
+            message::Message::Choke => {
+                self.peer_choking = true;
+                // Without the Fast extension the peer silently throws away our outstanding
+                // requests when it chokes us.  With it, the ones it drops are rejected one by one
+                if !self.fast {
+                    self.requested.clear();
+                }
+                self.notify(PeerEvent::Choked);
+            }
+            message::Message::Unchoke => {
+                self.peer_choking = false;
+                self.notify(PeerEvent::Unchoked);
+                self.request_blocks();
+            }
+            message::Message::Interested => {
+                if !self.peer_interested {
+                    self.peer_interested = true;
+                    self.notify(PeerEvent::Interested);
+                }
+            }
+            message::Message::NotInterested => {
+                if self.peer_interested {
+                    self.peer_interested = false;
+                    self.notify(PeerEvent::NotInterested);
+                }
+            }
+            message::Message::Have(index) => {
+                if index as usize >= self.peers_pieces.len() {
+                    error!("Peer claimed to have piece {}, which does not exist", index);
+                    return Err(());
+                }
+                self.peers_pieces.set(index as usize, true);
+                self.notify(PeerEvent::Have(index));
+                self.update_interest();
+            }
+            message::Message::Bitfield(mut bitfield) => {
+                let num_pieces = self.peers_pieces.len();
+                // The bitfield is padded out to a whole number of bytes, and the padding must be 0
+                if bitfield.len() != num_pieces.div_ceil(8) * 8 || bitfield.iter().skip(num_pieces).any(|b| b) {
+                    error!("Peer sent a bitfield of the wrong size");
+                    return Err(());
+                }
+                bitfield.truncate(num_pieces);
+                self.set_peers_pieces(bitfield);
+            }
+            message::Message::HaveAll => self.set_peers_pieces(BitVec::from_elem(self.peers_pieces.len(), true)),
+            message::Message::HaveNone => self.set_peers_pieces(BitVec::from_elem(self.peers_pieces.len(), false)),
+            message::Message::Piece(block) => self.handle_block(block),
+            message::Message::Request(request) => self.handle_request(request),
+            message::Message::Cancel(request) => {
+                let queued = self.upload_queue.len();
+                self.upload_queue.retain(|r| *r != request);
+                // With the Fast extension every request gets an answer, even a cancelled one
+                if self.upload_queue.len() < queued {
+                    self.reject(request);
+                }
+            }
+            // Only logged.  The server's picker decides what we download, and suggestions are
+            // just hints that are safe to ignore
+            message::Message::SuggestPiece(index) => trace!("Peer suggested we download piece {}", index),
+            message::Message::RejectRequest(request) => {
+                // The block will be asked for again when the peer is next willing to serve it
+                match self.requested.iter().position(|r| *r == request) {
+                    Some(position) => {
+                        self.requested.swap_remove(position);
+                    }
+                    None => trace!("Peer rejected a request we did not send"),
+                }
+            }
+            message::Message::AllowedFast(index) => {
+                if index as usize >= self.peers_pieces.len() {
+                    warn!("Peer allowed us piece {}, which does not exist", index);
+                } else if !self.peer_allowed_fast.contains(&index) {
+                    self.peer_allowed_fast.push(index);
+                    self.notify(PeerEvent::AllowedFast(index));
+                    self.request_blocks();
+                }
+            }
+            message::Message::Extended(id, payload) => return self.handle_extended(id, &payload),
+        }
+        Ok(())
+    }
+
+    /// Replaces what we know of the peer's pieces with everything it has
+    fn set_peers_pieces(&mut self, bitfield: BitVec) {
+        self.notify(PeerEvent::Bitfield(bitfield.clone()));
+        self.peers_pieces = bitfield;
+        self.update_interest();
+    }
+
+    fn handle_handshake(&mut self, item: message::Handshake) -> Result<(), ()> {
+        if self.info_hash != item.info_hash {
+            error!("The info hash sent by a peer does not match ours");
+            return Err(())
+        }
+        if self.peer_id == item.peer_id {
+            error!("Connected to ourselves, closing the connection");
+            return Err(())
+        }
+        self.handshake_received = true;
+        self.pieces_expected = true;
+        self.notify(PeerEvent::Handshake(item.peer_id));
+        if !self.initiates {
+            let handshake = message::Handshake::from((self.info_hash, self.peer_id)).with_extensions().with_fast();
+            self.send(message::Message::Handshake(handshake));
+        }
+        self.fast = item.supports_fast();
+        if self.fast && self.our_pieces.all() {
+            self.send(message::Message::HaveAll);
+        } else if self.fast && self.our_pieces.none() {
+            self.send(message::Message::HaveNone);
+        } else if self.our_pieces.any() {
+            self.send(message::Message::Bitfield(self.our_pieces.clone()));
+        }
+        // The set is only defined for IPv4 addresses
+        if let (true, Some(IpAddr::V4(ip))) = (self.fast, self.address.map(|address| address.ip())) {
+            self.allowed_fast = allowed_fast_set(ip, &self.info_hash, self.our_pieces.len(), ALLOWED_FAST_COUNT);
+            for index in self.allowed_fast.clone() {
+                self.send(message::Message::AllowedFast(index));
+            }
+        }
+        if item.supports_extensions() {
+            self.peer_extensions = true;
+            if let Some(address) = self.address {
+                let handshake = self.extensions.handshake(address.ip(), MAX_QUEUED_UPLOADS as u32);
+                let payload = Bytes::from(handshake.to_value().encode());
+                self.send(message::Message::Extended(extension::HANDSHAKE_ID, payload));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles an extension protocol message.  Returns Err if the connection should be closed
+    fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), ()> {
+        if !self.peer_extensions {
+            error!("Peer sent an extended message without supporting the extension protocol");
+            return Err(());
+        }
+        if id == extension::HANDSHAKE_ID {
+            let handshake = Value::decode(payload).ok()
+                .and_then(|val| extension::ExtensionHandshake::from_value(&val).ok());
+            let handshake = match handshake {
+                Some(handshake) => handshake,
+                None => {
+                    error!("Peer sent an invalid extension handshake");
+                    return Err(());
+                }
+            };
+            trace!("Peer is running {:?}", handshake.client);
+            // The port of a connection the peer made to us is no use to anyone else
+            if let (false, Some(port)) = (self.initiates, handshake.port) {
+                self.notify(PeerEvent::ListenPort(port));
+            }
+            // Requests beyond what the peer will queue would just be dropped
+            if let Some(max_requests) = handshake.max_requests {
+                self.max_requests = cmp::min(self.max_requests, max_requests as usize);
+            }
+            self.extensions.handle_handshake(&handshake);
+            return Ok(());
+        }
+        match self.extensions.handle_message(id, payload) {
+            Ok((replies, peers)) => {
+                for (id, reply) in replies {
+                    self.send(message::Message::Extended(id, reply));
+                }
+                if !peers.is_empty() {
+                    self.notify(PeerEvent::Peers(peers));
+                }
+                Ok(())
+            }
+            Err(msg) => {
+                error!("{}", msg);
+                Err(())
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: PeerCommand) {
+        match command {
+            PeerCommand::Have(index) => {
+                self.our_pieces.set(index as usize, true);
+                // In endgame another peer may have finished a piece we are still working on
+                self.cancel_piece(index);
+                if self.handshake_received {
+                    self.send(message::Message::Have(index));
+                    self.update_interest();
+                }
+            }
+            PeerCommand::Release(index) => {
+                self.cancel_piece(index);
+                self.update_interest();
+            }
+            PeerCommand::Choke => {
+                if !self.am_choking {
+                    self.am_choking = true;
+                    self.send(message::Message::Choke);
+                    // Choking throws away everything the peer has asked for, except the pieces
+                    // it may download while choked
+                    let allowed_fast = &self.allowed_fast;
+                    let (kept, dropped): (VecDeque<_>, VecDeque<_>) = self.upload_queue.drain(..)
+                        .partition(|r| allowed_fast.contains(&r.index));
+                    self.upload_queue = kept;
+                    for request in dropped {
+                        self.reject(request);
+                    }
+                }
+            }
+            PeerCommand::Unchoke => {
+                if self.am_choking {
+                    self.am_choking = false;
+                    self.send(message::Message::Unchoke);
+                }
+            }
+            PeerCommand::Swarm(swarm) => {
+                for (id, message) in self.extensions.update_swarm(&swarm) {
+                    self.send(message::Message::Extended(id, message));
+                }
+            }
+            PeerCommand::Block(index, begin, block) => {
+                let has_block = self.pieces.iter()
+                    .find(|p| p.index() == index)
+                    .map_or(true, |p| p.has_block((begin / BLOCK_SIZE) as usize));
+                if has_block {
+                    return;
+                }
+                let position = self.requested.iter().position(|r| r.index == index && r.begin == begin);
+                if let Some(position) = position {
+                    let request = self.requested.swap_remove(position);
+                    self.send(message::Message::Cancel(request));
+                }
+                self.store_block(index, begin, &block);
+            }
+        }
+    }
+
+    /// Stops downloading a piece, cancelling any requests for it that are in flight
+    fn cancel_piece(&mut self, index: u32) {
+        self.pieces.retain(|p| p.index() != index);
+        let (cancelled, requested) = self.requested.drain(..).partition(|r| r.index == index);
+        self.requested = requested;
+        for request in cancelled {
+            self.send(message::Message::Cancel(request));
+        }
+    }
+
+    /// Queues a block request from the peer, if it is one we are willing and able to answer.
+    /// Otherwise the request is rejected
+    fn handle_request(&mut self, request: message::Request) {
+        let accepted = if self.am_choking && !self.allowed_fast.contains(&request.index) {
+            trace!("Refusing a request from a choked peer");
+            false
+        } else if request.length == 0 || request.length > BLOCK_SIZE {
+            warn!("Peer requested a block of {} bytes", request.length);
+            false
+        } else if !self.our_pieces.get(request.index as usize).unwrap_or(false) {
+            warn!("Peer requested piece {}, which we do not have", request.index);
+            false
+        } else if self.upload_queue.len() >= MAX_QUEUED_UPLOADS {
+            warn!("Peer has too many requests queued, refusing another");
+            false
+        } else {
+            if !self.upload_queue.contains(&request) {
+                self.upload_queue.push_back(request.clone());
+            }
+            true
+        };
+        if !accepted {
+            self.reject(request);
+        }
+    }
+
+    /// Tells the peer we will not answer a request.  Without the Fast extension there is no way
+    /// to, and the request is just dropped
+    fn reject(&mut self, request: message::Request) {
+        if self.fast {
+            self.send(message::Message::RejectRequest(request));
+        }
+    }
+
+    /// Reads the next queued request off disk, skipping any that can't be read
+    fn next_upload(&mut self) -> Option<message::Message> {
+        while let Some(request) = self.upload_queue.pop_front() {
+            match self.storage.read_block(request.index, request.begin, request.length) {
+                Ok(block) => {
+                    self.uploaded += request.length;
+                    return Some(message::Message::Piece(
+                        message::Piece::new(request.index, request.begin, block.into())));
+                }
+                Err(e) => warn!("Could not read requested block from piece {}: {}", request.index, e),
+            }
+        }
+        None
+    }
+
+    /// Hands queued messages to the connection.  Returns false if its write buffer is full
+    fn flush(&mut self) -> Result<bool, ()> {
+        while let Some(message) = self.outgoing.pop_front() {
+            match self.conn.start_send(message) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(message)) => {
+                    // The write buffer is full, try again once it drains
+                    self.outgoing.push_front(message);
+                    return Ok(false);
+                }
+                Err(e) => {
+                    error!("Connection to peer closed with error '{}'", e);
+                    return Err(());
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stores a block the peer sent us, and hands the piece off to the server when it is complete
+    fn handle_block(&mut self, block: message::Piece) {
+        let position = self.requested.iter().position(|r| {
+            r.index == block.index && r.begin == block.begin && r.length as usize == block.block.len()
+        });
+        match position {
+            Some(position) => {
+                self.requested.swap_remove(position);
+            }
+            None => {
+                trace!("Peer sent a block we did not request");
+                return;
+            }
+        }
+        self.downloaded += block.block.len() as u32;
+
+        if self.store_block(block.index, block.begin, &block.block) {
+            self.notify(PeerEvent::Block(block.index, block.begin, block.block));
+        }
+        self.request_blocks();
+    }
+
+    /// Adds a block to the piece it belongs to, queueing the piece for the server if that
+    /// completed it.  Returns false if the block was not wanted
+    fn store_block(&mut self, index: u32, begin: u32, block: &[u8]) -> bool {
+        let i = match self.pieces.iter().position(|p| p.index() == index) {
+            Some(i) => i,
+            None => return false,
+        };
+        if !self.pieces[i].add_block(begin, block) {
+            error!("Peer sent a block that does not fit in piece {}", index);
+            return false;
+        }
+        if self.pieces[i].is_complete() {
+            let piece = self.pieces.swap_remove(i);
+            self.finished.push_back(piece);
+        }
+        true
+    }
+
+    /// Keeps up to max_requests block requests in flight, from whichever pieces the peer is
+    /// letting us download
+    fn request_blocks(&mut self) {
+        let mut new_requests = Vec::new();
+        'pieces: for piece in &self.pieces {
+            if self.peer_choking && !self.peer_allowed_fast.contains(&piece.index()) {
+                continue;
+            }
+            for block_index in 0..piece.num_blocks() {
+                if self.requested.len() + new_requests.len() >= self.max_requests {
+                    break 'pieces;
+                }
+                if piece.has_block(block_index) {
+                    continue;
+                }
+                let (begin, length) = piece.block_range(block_index);
+                let request: message::Request = (piece.index(), begin, length).into();
+                if !self.requested.contains(&request) {
+                    new_requests.push(request);
+                }
+            }
+        }
+        for request in new_requests {
+            self.send(message::Message::Request(request.clone()));
+            self.requested.push(request);
+        }
+    }
+
+    /// Passes finished pieces and statistics on to the server
+    fn report(&mut self) -> Result<(), ()> {
+        while let Some(piece) = self.finished.pop_front() {
+            match self.finished_piece_sender.start_send(piece) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(piece)) => {
+                    self.finished.push_front(piece);
+                    break;
+                }
+                // The server has gone away
+                Err(_) => return Err(()),
+            }
+        }
+        let _ = self.finished_piece_sender.poll_complete();
+        if self.downloaded > 0 && self.downloaded_sender.try_send(self.downloaded).is_ok() {
+            self.downloaded = 0;
+        }
+        if self.uploaded > 0 && self.uploaded_sender.try_send(self.uploaded).is_ok() {
+            self.uploaded = 0;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer whether we want anything from them, if that has changed
+    fn update_interest(&mut self) {
+        if !self.handshake_received {
+            return;
+        }
+        let interested = !self.pieces.is_empty() || wants_pieces(&self.our_pieces, &self.peers_pieces);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            self.send(if interested {
+                message::Message::Interested
+            } else {
+                message::Message::NotInterested
+            });
+        }
+    }
+}
+
+/// The pieces a peer at ip may download while choked, generated as described in BEP 6.  Every
+/// address in the same /24 gets the same set, so a peer can't collect more by using several
+fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], num_pieces: usize, count: usize) -> Vec<u32> {
+    let count = cmp::min(count, num_pieces);
+    let mut allowed = Vec::with_capacity(count);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        let hash = sha1_hash(&x);
+        for chunk in hash.chunks(4) {
+            let index = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) % num_pieces as u32;
+            if allowed.len() < count && !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+        x = hash.to_vec();
+    }
+    allowed
+}
+
+/// True if the peer has any piece we do not
+fn wants_pieces(our_pieces: &BitVec, peers_pieces: &BitVec) -> bool {
+    our_pieces.iter().zip(peers_pieces.iter()).any(|(ours, theirs)| theirs && !ours)
 }
 
 // Peer can be spun into tasks
@@ -64,45 +735,43 @@ impl Future for Peer {
     type Error = ();
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(command))) => self.handle_command(command),
+                // The server has gone away, nothing left to do
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                _ => break,
+            }
+        }
+        while let Ok(Async::Ready(Some(piece))) = self.piece_receiver.poll() {
+            self.pieces.push(piece);
+            self.update_interest();
+            self.request_blocks();
+        }
         loop {
             match self.conn.poll() {
                 Ok(Async::NotReady) => break, // No more messages right now
                 Ok(Async::Ready(None)) => return Ok(Async::Ready(())), // connection closed, end the task
-                Ok(Async::Ready(Some(message))) => {
-                    match message {
-                        message::Message::Handshake(item) => {
-                            if self.info_hash != item.info_hash {
-                                error!("The info hash sent by a peer does not match ours");
-                                return Err(())
-                            }
-                            if !self.initiates {
-                                let _res = self.conn.start_send(
-                                    message::Message::Handshake(
-                                        (self.info_hash.clone(), self.peer_id.clone()).into()
-                                    ));
-                            }
-                        }
-                        // TODO Process Message
-                        _ => {}
-                    }
-                }
+                Ok(Async::Ready(Some(message))) => self.handle_message(message)?,
                 Err(e) => {
                     error!("Connection to peer closed with error '{}'", e);
                     return Err(());
                 }
             }
         };
-        // TODO maybe send a message using self.conn.start_send here
-        loop {
-            match self.conn.poll_complete() {
-                Ok(Async::NotReady) => break, // Can't make anymore progress
-                Ok(Async::Ready(())) => (), // All sends complete
-                Err(e) => {
-                    error!("Connection to peer closed with error '{}'", e);
-                    return Err(());
-                }
+        // Serve uploads only while the connection keeps up, so cancels have a chance to arrive
+        while self.flush()? {
+            match self.next_upload() {
+                Some(message) => self.send(message),
+                None => break,
             }
         }
+        self.report()?;
+        // Either the sends are complete or the connection will wake us when it can take more
+        if let Err(e) = self.conn.poll_complete() {
+            error!("Connection to peer closed with error '{}'", e);
+            return Err(());
+        }
         Ok(Async::NotReady)
     }
-}
\ No newline at end of file
+}
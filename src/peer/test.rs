@@ -0,0 +1,628 @@
+use bytes::{Bytes, BytesMut};
+use crate::boostencode::{FromValue, Value};
+use crate::metadata::{MetadataMessage, UtMetadata};
+use crate::metainfo::{sha1_hash, FileInfo, InfoDict, SingleFile};
+use crate::testutil::temp_dir;
+use futures::sync::mpsc::{channel, unbounded, UnboundedSender};
+use maplit::hashmap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use super::*;
+use super::extension::{Extension, ExtensionHandshake, Outbox, Registry};
+use super::message::{Message, MessageCodec};
+use tokio::codec::{Decoder, Encoder};
+use tokio::runtime::Runtime;
+
+fn handshake() -> Message {
+    Message::Handshake(([1; 20], [2; 20]).into())
+}
+
+#[test]
+fn test_codec_round_trip() {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    let mut bitfield = BitVec::from_elem(10, false);
+    bitfield.set(3, true);
+
+    codec.encode(handshake(), &mut buf).unwrap();
+    codec.encode(Message::KeepAlive, &mut buf).unwrap();
+    codec.encode(Message::Interested, &mut buf).unwrap();
+    codec.encode(Message::Have(7), &mut buf).unwrap();
+    codec.encode(Message::Bitfield(bitfield), &mut buf).unwrap();
+    codec.encode(Message::Cancel((1, 2, 3).into()), &mut buf).unwrap();
+    codec.encode(Message::Extended(3, Bytes::from(&b"d1:ai1ee"[..])), &mut buf).unwrap();
+    codec.encode(Message::SuggestPiece(4), &mut buf).unwrap();
+    codec.encode(Message::HaveAll, &mut buf).unwrap();
+    codec.encode(Message::HaveNone, &mut buf).unwrap();
+    codec.encode(Message::RejectRequest((4, 5, 6).into()), &mut buf).unwrap();
+    codec.encode(Message::AllowedFast(9), &mut buf).unwrap();
+
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(handshake()));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::KeepAlive));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Interested));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(7)));
+    // bitfields come back padded to a whole byte
+    let mut padded = BitVec::from_elem(16, false);
+    padded.set(3, true);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Bitfield(padded)));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Cancel((1, 2, 3).into())));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Extended(3, Bytes::from(&b"d1:ai1ee"[..]))));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::SuggestPiece(4)));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::HaveAll));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::HaveNone));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::RejectRequest((4, 5, 6).into())));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::AllowedFast(9)));
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn test_handshake_extension_bit() {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    let handshake = message::Handshake::from(([1; 20], [2; 20])).with_extensions();
+    assert!(handshake.supports_extensions());
+    codec.encode(Message::Handshake(handshake), &mut buf).unwrap();
+    assert_eq!(buf[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+
+    match codec.decode(&mut buf).unwrap() {
+        Some(Message::Handshake(handshake)) => assert!(handshake.supports_extensions()),
+        other => panic!("expected a handshake, got {:?}", other),
+    }
+    assert!(!message::Handshake::from(([1; 20], [2; 20])).supports_extensions());
+
+    let fast = message::Handshake::from(([1; 20], [2; 20])).with_fast();
+    assert_eq!(fast.reserved, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+    assert!(fast.supports_fast() && !fast.supports_extensions());
+}
+
+#[test]
+fn test_allowed_fast_set() {
+    // the example from BEP 6
+    let ip = Ipv4Addr::new(80, 4, 4, 200);
+    assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+    assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+    // the whole /24 shares a set, and there can't be more pieces allowed than exist
+    assert_eq!(allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &[0xaa; 20], 1313, 7), allowed_fast_set(ip, &[0xaa; 20], 1313, 7));
+    assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 3, 7).len(), 3);
+}
+
+#[test]
+fn test_codec_partial_messages() {
+    let mut codec = MessageCodec::new();
+    let mut encoded = BytesMut::new();
+    codec.encode(handshake(), &mut encoded).unwrap();
+    codec.encode(Message::Have(1), &mut encoded).unwrap();
+
+    // feed the bytes in one at a time, nothing should be lost while waiting for the rest
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+    for byte in encoded.iter() {
+        buf.extend_from_slice(&[*byte]);
+        if let Some(message) = codec.decode(&mut buf).unwrap() {
+            decoded.push(message);
+        }
+    }
+    assert_eq!(decoded, vec![handshake(), Message::Have(1)]);
+}
+
+#[test]
+fn test_codec_invalid_length() {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(handshake(), &mut buf).unwrap();
+    codec.decode(&mut buf).unwrap();
+
+    // a have message with no index
+    buf.extend_from_slice(&[0, 0, 0, 1, 4]);
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[test]
+fn test_codec_unknown_id() {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(handshake(), &mut buf).unwrap();
+    codec.decode(&mut buf).unwrap();
+
+    // a port message is skipped, and the have after it still comes through
+    buf.extend_from_slice(&[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+    codec.encode(Message::Have(7), &mut buf).unwrap();
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(7)));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_codec_too_long() {
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(handshake(), &mut buf).unwrap();
+    codec.decode(&mut buf).unwrap();
+
+    // a piece bigger than any block we would ask for is refused before the rest of it arrives
+    buf.extend_from_slice(&[0, 0x01, 0, 0, 7]);
+    assert!(codec.decode(&mut buf).is_err());
+
+    let mut codec = MessageCodec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(handshake(), &mut buf).unwrap();
+    codec.decode(&mut buf).unwrap();
+    buf.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[test]
+fn test_wants_pieces() {
+    let ours = BitVec::from_bytes(&[0b1100_0000]);
+    assert!(!wants_pieces(&ours, &BitVec::from_bytes(&[0b1000_0000])));
+    assert!(wants_pieces(&ours, &BitVec::from_bytes(&[0b0010_0000])));
+    assert!(!wants_pieces(&ours, &BitVec::from_bytes(&[0])));
+}
+
+/// Reads one length prefixed message off a blocking socket, returning its id and payload
+fn read_message(stream: &mut std::net::TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length == 0 {
+            continue;
+        }
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload)?;
+        let id = payload.remove(0);
+        return Ok((id, payload));
+    }
+}
+
+fn write_message(stream: &mut std::net::TcpStream, message: Message) {
+    let mut buf = BytesMut::new();
+    MessageCodec::new().encode(message, &mut buf).unwrap();
+    stream.write_all(&buf).unwrap();
+}
+
+fn read_u32(payload: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+}
+
+/// A Peer running against a blocking socket that the test plays the other side of
+struct Harness {
+    remote: std::net::TcpStream,
+    // Keeps the peer task running until the test is over
+    _runtime: Runtime,
+    finished: Receiver<Piece>,
+    events: UnboundedReceiver<PeerEvent>,
+    uploaded: Receiver<u32>,
+    pieces: Sender<Piece>,
+    commands: UnboundedSender<PeerCommand>,
+    dir: PathBuf,
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts a peer for a torrent made of a single piece holding data, which is written to disk
+/// first if seeding is set.  The handshake has already been exchanged when this returns
+fn start_peer(data: &[u8], seeding: bool, max_requests: usize) -> Harness {
+    start_extended_peer(data, seeding, max_requests, None, false)
+}
+
+/// Like start_peer, but if extensions are given the other side says it supports the extension
+/// protocol and the peer runs them.  If fast is set the other side supports the Fast extension
+fn start_extended_peer(data: &[u8], seeding: bool, max_requests: usize, extensions: Option<Registry>, fast: bool) -> Harness {
+    let dir = temp_dir("peer");
+    let info = InfoDict {
+        piece_length: data.len(),
+        pieces: vec![String::new()],
+        private: false,
+        file_info: FileInfo::Single(SingleFile { file_name: "file".to_string(), length: data.len(), md5sum: None }),
+    };
+    let extended = extensions.is_some();
+    let storage = Storage::new(&dir, &info).unwrap();
+    if seeding {
+        storage.write_piece(&mut Piece::from_data(0, data.to_vec(), sha1_hash(data))).unwrap();
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let ours = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut remote, _) = listener.accept().unwrap();
+
+    let runtime = Runtime::new().unwrap();
+    let conn = TcpStream::from_std(ours, &tokio::reactor::Handle::default()).unwrap();
+    let (up_sender, up_receiver) = channel(10);
+    let (down_sender, _down_receiver) = channel(10);
+    let (finished_sender, finished_receiver) = channel(10);
+    let (new_piece_sender, new_piece_receiver) = channel(10);
+    let (event_sender, event_receiver) = unbounded();
+    let (command_sender, command_receiver) = unbounded();
+    let channels = PeerChannels {
+        uploaded: up_sender,
+        downloaded: down_sender,
+        finished_pieces: finished_sender,
+        pieces: new_piece_receiver,
+        events: event_sender,
+        commands: command_receiver,
+    };
+    let config = PeerConfig {
+        extensions: extensions.unwrap_or_else(|| Registry::new(None)),
+        max_requests,
+        initiates: true,
+    };
+    let peer = Peer::new(conn, channels, config, Arc::new(storage), BitVec::from_elem(1, seeding), [1; 20], [2; 20]);
+    runtime.executor().spawn(peer);
+
+    let mut handshake = [0u8; 68];
+    remote.read_exact(&mut handshake).unwrap();
+    let mut buf = BytesMut::new();
+    let mut theirs = message::Handshake::from(([1; 20], [3; 20]));
+    if extended {
+        theirs = theirs.with_extensions();
+    }
+    if fast {
+        theirs = theirs.with_fast();
+    }
+    MessageCodec::new().encode(Message::Handshake(theirs), &mut buf).unwrap();
+    remote.write_all(&buf).unwrap();
+    remote.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    // the server hears who the peer is first, so it can drop a second connection to it
+    let mut events = event_receiver;
+    assert_eq!(events.by_ref().wait().next().unwrap(), Ok(PeerEvent::Handshake([3; 20])));
+
+    Harness {
+        remote,
+        _runtime: runtime,
+        finished: finished_receiver,
+        events,
+        uploaded: up_receiver,
+        pieces: new_piece_sender,
+        commands: command_sender,
+        dir,
+    }
+}
+
+#[test]
+fn test_pipelined_download() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 10).map(|i| i as u8).collect();
+    let mut harness = start_peer(&data, false, 2);
+    harness.pieces.try_send(Piece::new(0, data.len() as u32, sha1_hash(&data))).unwrap();
+
+    // play the part of a seeding peer
+    let remote = &mut harness.remote;
+    write_message(remote, Message::Bitfield(BitVec::from_bytes(&[0b1000_0000])));
+    write_message(remote, Message::Unchoke);
+
+    let mut outstanding = Vec::new();
+    let mut served = 0;
+    while served < 4 {
+        match read_message(remote) {
+            Ok((2, _)) => (), // interested
+            Ok((6, payload)) => {
+                outstanding.push((read_u32(&payload, 0), read_u32(&payload, 4), read_u32(&payload, 8)));
+                assert!(outstanding.len() <= 2, "more requests in flight than allowed");
+            }
+            Ok((id, _)) => panic!("unexpected message {}", id),
+            // the peer is waiting on us, answer the oldest request
+            Err(_) => {
+                if served + outstanding.len() < 4 {
+                    assert_eq!(outstanding.len(), 2, "pipeline was not kept full");
+                }
+                let (index, begin, length) = outstanding.remove(0);
+                let block = Bytes::from(&data[begin as usize..(begin + length) as usize]);
+                write_message(remote, Message::Piece(message::Piece::new(index, begin, block)));
+                served += 1;
+            }
+        }
+    }
+
+    let mut finished = harness.finished.by_ref().wait().next().unwrap().unwrap();
+    assert!(finished.verify());
+    let events: Vec<PeerEvent> = harness.events.by_ref().wait().take(2).map(Result::unwrap).collect();
+    assert_eq!(events, vec![PeerEvent::Bitfield(BitVec::from_elem(1, true)), PeerEvent::Unchoked]);
+}
+
+#[test]
+fn test_release_piece() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+    let mut harness = start_peer(&data, false, 2);
+    harness.pieces.try_send(Piece::new(0, data.len() as u32, sha1_hash(&data))).unwrap();
+    write_message(&mut harness.remote, Message::Bitfield(BitVec::from_bytes(&[0b1000_0000])));
+    write_message(&mut harness.remote, Message::Unchoke);
+    let mut requests = Vec::new();
+    while requests.len() < 2 {
+        match read_message(&mut harness.remote).unwrap() {
+            (2, _) => (), // interested
+            (6, payload) => requests.push(payload),
+            (id, _) => panic!("unexpected message {}", id),
+        }
+    }
+
+    // the piece went to another peer, so everything asked for is cancelled
+    harness.commands.unbounded_send(PeerCommand::Release(0)).unwrap();
+    let mut cancels = vec![read_message(&mut harness.remote).unwrap(), read_message(&mut harness.remote).unwrap()];
+    cancels.sort();
+    assert_eq!(cancels, requests.into_iter().map(|payload| (8, payload)).collect::<Vec<_>>());
+    assert!(read_message(&mut harness.remote).is_err());
+}
+
+#[test]
+fn test_block_from_another_peer() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| i as u8).collect();
+    let mut harness = start_peer(&data, false, 2);
+    harness.pieces.try_send(Piece::new(0, data.len() as u32, sha1_hash(&data))).unwrap();
+    write_message(&mut harness.remote, Message::Bitfield(BitVec::from_bytes(&[0b1000_0000])));
+    write_message(&mut harness.remote, Message::Unchoke);
+    let mut requests = Vec::new();
+    while requests.len() < 2 {
+        match read_message(&mut harness.remote).unwrap() {
+            (2, _) => (), // interested
+            (6, payload) => requests.push(payload),
+            (id, _) => panic!("unexpected message {}", id),
+        }
+    }
+    requests.sort();
+
+    // in endgame another peer got the first block to us first, so our request for it is cancelled
+    let first = Bytes::from(&data[..BLOCK_SIZE as usize]);
+    harness.commands.unbounded_send(PeerCommand::Block(0, 0, first)).unwrap();
+    assert_eq!(read_message(&mut harness.remote).unwrap(), (8, requests[0].clone()));
+
+    // the block the peer does send is reported, for resuming and to share with the others
+    let second = Bytes::from(&data[BLOCK_SIZE as usize..]);
+    write_message(&mut harness.remote, Message::Piece(message::Piece::new(0, BLOCK_SIZE, second.clone())));
+    let mut finished = harness.finished.by_ref().wait().next().unwrap().unwrap();
+    assert!(finished.verify());
+    let events: Vec<PeerEvent> = harness.events.by_ref().wait().take(3).map(Result::unwrap).collect();
+    assert_eq!(events[2], PeerEvent::Block(0, BLOCK_SIZE, second));
+}
+
+#[test]
+fn test_serve_upload() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i * 7) as u8).collect();
+    let mut harness = start_peer(&data, true, 2);
+    let remote = &mut harness.remote;
+
+    // we have the piece, so the peer opens with its bitfield
+    let (id, payload) = read_message(remote).unwrap();
+    assert_eq!((id, payload), (5, vec![0b1000_0000]));
+
+    write_message(remote, Message::Interested);
+    let event = harness.events.by_ref().wait().next().unwrap().unwrap();
+    assert_eq!(event, PeerEvent::Interested);
+    harness.commands.unbounded_send(PeerCommand::Unchoke).unwrap();
+    assert_eq!(read_message(remote).unwrap().0, 1);
+
+    // oversized and cancelled requests are never answered.  Send everything at once so the
+    // cancel arrives before the request is served
+    let mut buf = BytesMut::new();
+    let mut codec = MessageCodec::new();
+    codec.encode(Message::Request((0, 0, BLOCK_SIZE * 2).into()), &mut buf).unwrap();
+    codec.encode(Message::Request((0, BLOCK_SIZE, BLOCK_SIZE).into()), &mut buf).unwrap();
+    codec.encode(Message::Cancel((0, BLOCK_SIZE, BLOCK_SIZE).into()), &mut buf).unwrap();
+    codec.encode(Message::Request((0, 0, 100).into()), &mut buf).unwrap();
+    remote.write_all(&buf).unwrap();
+    let (id, payload) = read_message(remote).unwrap();
+    assert_eq!((id, read_u32(&payload, 0), read_u32(&payload, 4)), (7, 0, 0));
+    assert_eq!(&payload[8..], &data[..100]);
+    assert!(read_message(remote).is_err());
+
+    let uploaded = harness.uploaded.by_ref().wait().next().unwrap().unwrap();
+    assert_eq!(uploaded, 100);
+}
+
+#[test]
+fn test_fast_serve_while_choked() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i * 3) as u8).collect();
+    let mut harness = start_extended_peer(&data, true, 2, None, true);
+    let remote = &mut harness.remote;
+
+    // a seed says so in one message, and with a single piece that is the one we may have choked
+    assert_eq!(read_message(remote).unwrap(), (14, vec![]));
+    assert_eq!(read_message(remote).unwrap(), (17, vec![0, 0, 0, 0]));
+
+    // every request is answered, with the block or a reject
+    let mut buf = BytesMut::new();
+    let mut codec = MessageCodec::new();
+    codec.encode(Message::Request((0, 0, 100).into()), &mut buf).unwrap();
+    codec.encode(Message::Request((0, 0, BLOCK_SIZE * 2).into()), &mut buf).unwrap();
+    codec.encode(Message::Request((3, 0, 100).into()), &mut buf).unwrap();
+    codec.encode(Message::Request((0, BLOCK_SIZE, BLOCK_SIZE).into()), &mut buf).unwrap();
+    codec.encode(Message::Cancel((0, BLOCK_SIZE, BLOCK_SIZE).into()), &mut buf).unwrap();
+    remote.write_all(&buf).unwrap();
+    let mut rejected = Vec::new();
+    for _ in 0..3 {
+        let (id, payload) = read_message(remote).unwrap();
+        assert_eq!(id, 16);
+        rejected.push((read_u32(&payload, 0), read_u32(&payload, 4), read_u32(&payload, 8)));
+    }
+    assert_eq!(rejected, vec![(0, 0, BLOCK_SIZE * 2), (3, 0, 100), (0, BLOCK_SIZE, BLOCK_SIZE)]);
+    let (id, payload) = read_message(remote).unwrap();
+    assert_eq!((id, read_u32(&payload, 0), read_u32(&payload, 4)), (7, 0, 0));
+    assert_eq!(&payload[8..], &data[..100]);
+    assert!(read_message(remote).is_err());
+}
+
+#[test]
+fn test_fast_download_while_choked() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+    let mut harness = start_extended_peer(&data, false, 2, None, true);
+    assert_eq!(read_message(&mut harness.remote).unwrap(), (15, vec![]));
+    assert_eq!(read_message(&mut harness.remote).unwrap(), (17, vec![0, 0, 0, 0]));
+
+    // the peer may download the piece without ever being unchoked
+    write_message(&mut harness.remote, Message::HaveAll);
+    write_message(&mut harness.remote, Message::AllowedFast(0));
+    harness.pieces.try_send(Piece::new(0, data.len() as u32, sha1_hash(&data))).unwrap();
+    let mut requests = Vec::new();
+    let mut read_requests = |remote: &mut std::net::TcpStream, count: usize| {
+        while requests.len() < count {
+            match read_message(remote).unwrap() {
+                (2, _) => (), // interested
+                (6, payload) => requests.push((read_u32(&payload, 0), read_u32(&payload, 4), read_u32(&payload, 8))),
+                (id, _) => panic!("unexpected message {}", id),
+            }
+        }
+    };
+    read_requests(&mut harness.remote, 2);
+
+    // a rejected block is asked for again once there is room in the pipeline
+    write_message(&mut harness.remote, Message::RejectRequest((0, 0, BLOCK_SIZE).into()));
+    let block = Bytes::from(&data[BLOCK_SIZE as usize..BLOCK_SIZE as usize * 2]);
+    write_message(&mut harness.remote, Message::Piece(message::Piece::new(0, BLOCK_SIZE, block)));
+    read_requests(&mut harness.remote, 4);
+    assert_eq!(requests, vec![(0, 0, BLOCK_SIZE), (0, BLOCK_SIZE, BLOCK_SIZE), (0, 0, BLOCK_SIZE), (0, BLOCK_SIZE * 2, BLOCK_SIZE)]);
+
+    let events: Vec<PeerEvent> = harness.events.by_ref().wait().take(2).map(Result::unwrap).collect();
+    assert_eq!(events, vec![PeerEvent::Bitfield(BitVec::from_elem(1, true)), PeerEvent::AllowedFast(0)]);
+}
+
+/// Sends back every message it gets
+struct Echo;
+
+impl Extension for Echo {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) -> Result<(), String> {
+        if payload.is_empty() {
+            return Err("empty echo".to_string());
+        }
+        outbox.messages.push(Bytes::from(payload));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_extension_handshake_round_trip() {
+    let handshake = ExtensionHandshake {
+        extensions: hashmap! { "ut_metadata".to_string() => 3, "ut_pex".to_string() => 0 },
+        client: Some("test 1.0".to_string()),
+        port: Some(6881),
+        max_requests: Some(250),
+        your_ip: Some("::1".parse().unwrap()),
+        metadata_size: Some(31_235),
+    };
+    let decoded = Value::decode(&handshake.to_value().encode()).unwrap();
+    assert_eq!(ExtensionHandshake::from_value(&decoded), Ok(handshake));
+
+    // everything but the extension dictionary is optional, and junk is skipped over
+    let sparse = Value::decode(b"d1:md3:bad5:hello2:oki2ee1:pi70000e6:yourip3:abce").unwrap();
+    assert_eq!(ExtensionHandshake::from_value(&sparse), Ok(ExtensionHandshake {
+        extensions: hashmap! { "ok".to_string() => 2 },
+        ..ExtensionHandshake::default()
+    }));
+    assert!(ExtensionHandshake::from_value(&Value::Integer(1)).is_err());
+}
+
+#[test]
+fn test_registry() {
+    let mut registry = Registry::new(Some(6888));
+    registry.register(Box::new(Echo));
+    let ours = registry.handshake("10.0.0.1".parse().unwrap(), 100);
+    assert_eq!(ours.extensions, hashmap! { "echo".to_string() => 1 });
+    assert_eq!(ours.port, Some(6888));
+    assert_eq!(ours.max_requests, Some(100));
+    assert_eq!(ours.your_ip, Some("10.0.0.1".parse().unwrap()));
+
+    // nothing is sent back until the peer says which id it wants
+    assert_eq!(registry.handle_message(1, b"hi"), Ok((vec![], vec![])));
+    let mut theirs = ExtensionHandshake {
+        extensions: hashmap! { "echo".to_string() => 7 },
+        ..ExtensionHandshake::default()
+    };
+    registry.handle_handshake(&theirs);
+    assert_eq!(registry.handle_message(1, b"hi"), Ok((vec![(7, Bytes::from(&b"hi"[..]))], vec![])));
+    assert!(registry.handle_message(1, b"").is_err());
+    assert!(registry.handle_message(2, b"hi").is_err());
+
+    // and a later handshake can turn it off again
+    theirs.extensions.insert("echo".to_string(), 0);
+    registry.handle_handshake(&theirs);
+    assert_eq!(registry.handle_message(1, b"hi"), Ok((vec![], vec![])));
+}
+
+#[test]
+fn test_extended_messages() {
+    let data: Vec<u8> = (0..100).collect();
+    let metadata: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut extensions = Registry::new(None);
+    extensions.register(Box::new(UtMetadata::new(Arc::new(metadata.clone()))));
+    let mut harness = start_extended_peer(&data, false, 2, Some(extensions), false);
+    let remote = &mut harness.remote;
+
+    // the peer introduces itself straight after the handshake
+    let (id, payload) = read_message(remote).unwrap();
+    assert_eq!((id, payload[0]), (20, 0));
+    let ours = ExtensionHandshake::from_value(&Value::decode(&payload[1..]).unwrap()).unwrap();
+    assert_eq!(ours.extensions, hashmap! { "ut_metadata".to_string() => 1 });
+    assert_eq!(ours.metadata_size, Some(metadata.len()));
+    assert_eq!(ours.max_requests, Some(MAX_QUEUED_UPLOADS as u32));
+    assert_eq!(ours.your_ip, Some(remote.local_addr().unwrap().ip()));
+
+    let theirs = ExtensionHandshake {
+        extensions: hashmap! { "ut_metadata".to_string() => 5 },
+        ..ExtensionHandshake::default()
+    };
+    write_message(remote, Message::Extended(0, theirs.to_value().encode().into()));
+    write_message(remote, Message::Extended(1, MetadataMessage::Request(0).encode().into()));
+    let (id, payload) = read_message(remote).unwrap();
+    assert_eq!((id, payload[0]), (20, 5));
+    let reply = MetadataMessage::decode(&payload[1..]).unwrap();
+    assert_eq!(reply, MetadataMessage::Data(0, metadata.len(), metadata));
+
+    // a message for an extension we never offered ends the connection
+    write_message(remote, Message::Extended(9, Bytes::new()));
+    remote.set_read_timeout(None).unwrap();
+    let mut rest = Vec::new();
+    assert_eq!(remote.read_to_end(&mut rest).unwrap_or(0), 0);
+}
+
+/// Checks that the peer closes the connection, after whatever it sent before
+fn assert_closed(remote: &mut std::net::TcpStream) {
+    remote.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = Vec::new();
+    if let Err(e) = remote.read_to_end(&mut rest) {
+        assert!(e.kind() != std::io::ErrorKind::WouldBlock && e.kind() != std::io::ErrorKind::TimedOut,
+                "the connection was left open");
+    }
+}
+
+#[test]
+fn test_late_bitfield() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut harness = start_peer(&data, false, 2);
+    write_message(&mut harness.remote, Message::Have(0));
+    write_message(&mut harness.remote, Message::Bitfield(BitVec::from_bytes(&[0b1000_0000])));
+    assert_closed(&mut harness.remote);
+}
+
+#[test]
+fn test_second_have_all() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut harness = start_extended_peer(&data, false, 2, None, true);
+    assert_eq!(read_message(&mut harness.remote).unwrap(), (15, vec![]));
+    assert_eq!(read_message(&mut harness.remote).unwrap(), (17, vec![0, 0, 0, 0]));
+    write_message(&mut harness.remote, Message::HaveAll);
+    write_message(&mut harness.remote, Message::HaveNone);
+    assert_closed(&mut harness.remote);
+}
+
+#[test]
+fn test_bitfield_after_extension_handshake() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut harness = start_extended_peer(&data, false, 2, Some(Registry::new(None)), false);
+    let (id, _) = read_message(&mut harness.remote).unwrap();
+    assert_eq!(id, 20);
+
+    // the extension handshake may come first, and the pieces are still welcome after it
+    let theirs = ExtensionHandshake::default();
+    write_message(&mut harness.remote, Message::KeepAlive);
+    write_message(&mut harness.remote, Message::Extended(0, theirs.to_value().encode().into()));
+    write_message(&mut harness.remote, Message::Bitfield(BitVec::from_bytes(&[0b1000_0000])));
+    let event = harness.events.by_ref().wait().next().unwrap().unwrap();
+    assert_eq!(event, PeerEvent::Bitfield(BitVec::from_elem(1, true)));
+}
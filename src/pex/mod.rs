@@ -0,0 +1,174 @@
+//! pex swaps lists of peers with the peers we are connected to, using the ut_pex extension
+//! (BEP 11), so we can keep finding peers when the trackers stop answering
+use bytes::Bytes;
+use crate::boostencode::Value;
+use crate::peer::extension::{
+    Extension,
+    Outbox,
+    SwarmPeer,
+};
+use crate::tracker::{
+    compact_peer,
+    parse_compact_peers,
+    parse_compact_peers6,
+};
+use log::trace;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// What the extension is called in extension handshakes
+const NAME: &str = "ut_pex";
+/// Peers send each other at most one message this often
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// The least time between two messages before the second counts as too soon.  Shorter than
+/// PEX_INTERVAL, so that timers firing a little early or late on either side don't get a message
+/// ignored, or held back until the interval after
+const MIN_INTERVAL: Duration = Duration::from_secs(45);
+/// The most peers added or dropped in a single message
+const MAX_PEERS: usize = 50;
+/// The peer has every piece
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer accepts incoming connections
+pub const FLAG_CONNECTABLE: u8 = 0x10;
+
+/// A message of the ut_pex extension: the changes to the sender's peers since its last message
+#[derive(Debug, PartialEq, Default)]
+pub struct PexMessage {
+    // Peers the sender has connected to, with their flags
+    pub added: Vec<(SocketAddr, u8)>,
+    // Peers the sender is no longer connected to
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut map = HashMap::new();
+        for (v6, suffix) in [(false, ""), (true, "6")].iter() {
+            let added: Vec<_> = self.added.iter().filter(|(address, _)| address.is_ipv6() == *v6).collect();
+            let compact = added.iter().flat_map(|(address, _)| compact_peer(address)).collect();
+            let flags = added.iter().map(|(_, flags)| *flags).collect();
+            let dropped = self.dropped.iter()
+                .filter(|address| address.is_ipv6() == *v6)
+                .flat_map(compact_peer)
+                .collect();
+            map.insert(format!("added{}", suffix).into_bytes(), Value::BString(compact));
+            map.insert(format!("added{}.f", suffix).into_bytes(), Value::BString(flags));
+            map.insert(format!("dropped{}", suffix).into_bytes(), Value::BString(dropped));
+        }
+        Value::Dict(map).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("Invalid ut_pex message: {}", e))?;
+        let map = val.dict().ok_or("ut_pex message not a dictionary".to_string())?;
+        let list = |key: &str| map.get(key.as_bytes()).and_then(Value::bstring).map_or(&[][..], Vec::as_slice);
+
+        // Flags are optional, and default to saying nothing about the peer
+        let v4 = (parse_compact_peers(list("added"))?, list("added.f"));
+        let v6 = (parse_compact_peers6(list("added6"))?, list("added6.f"));
+        let added = vec![v4, v6].into_iter()
+            .flat_map(|(peers, flags)| peers.into_iter()
+                .enumerate()
+                .map(move |(i, peer)| (peer.address, flags.get(i).cloned().unwrap_or(0))))
+            .collect();
+        let dropped = parse_compact_peers(list("dropped"))?.into_iter()
+            .chain(parse_compact_peers6(list("dropped6"))?)
+            .map(|peer| peer.address)
+            .collect();
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+/// Exchanges peers with a single peer
+#[derive(Default)]
+pub struct UtPex {
+    // The peers we have told the peer about, with the flags we sent
+    sent: HashMap<SocketAddr, u8>,
+    // When we last sent and received a message, for rate limiting
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new() -> Self {
+        UtPex::default()
+    }
+}
+
+/// Whether a message at the time given would come too soon after one at last
+fn too_soon(last: Option<Instant>, now: Instant) -> bool {
+    match last {
+        Some(last) => now < last + MIN_INTERVAL,
+        None => false,
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) -> Result<(), String> {
+        let message = PexMessage::decode(payload)?;
+        let now = Instant::now();
+        if too_soon(self.last_received, now) {
+            trace!("Ignoring a ut_pex message that came too soon after the last");
+            return Ok(());
+        }
+        self.last_received = Some(now);
+        // Only take as many as a well behaved peer would send, so one can't flood us, and none
+        // that nobody could be listening on
+        outbox.peers.extend(message.added.into_iter()
+            .take(MAX_PEERS)
+            .map(|(address, _)| address)
+            .filter(|address| address.port() != 0 && !address.ip().is_unspecified()));
+        Ok(())
+    }
+
+    /// Sends the peer whatever changed since the last message, if it has been long enough
+    fn on_swarm(&mut self, swarm: &[SwarmPeer], messages: &mut Vec<Bytes>) {
+        let now = Instant::now();
+        if too_soon(self.last_sent, now) {
+            return;
+        }
+        let current: HashMap<SocketAddr, u8> = swarm.iter()
+            .map(|peer| {
+                let mut flags = 0;
+                if peer.seed {
+                    flags |= FLAG_SEED;
+                }
+                if peer.connectable {
+                    flags |= FLAG_CONNECTABLE;
+                }
+                (peer.address, flags)
+            })
+            .collect();
+        // Anything past the limit waits for the next message
+        let added: Vec<(SocketAddr, u8)> = current.iter()
+            .filter(|(address, _)| !self.sent.contains_key(address))
+            .take(MAX_PEERS)
+            .map(|(address, flags)| (*address, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self.sent.keys()
+            .filter(|address| !current.contains_key(address))
+            .take(MAX_PEERS)
+            .cloned()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return;
+        }
+        for address in &dropped {
+            self.sent.remove(address);
+        }
+        self.sent.extend(added.iter().cloned());
+        self.last_sent = Some(now);
+        messages.push(PexMessage { added, dropped }.encode().into());
+    }
+}
@@ -0,0 +1,111 @@
+use maplit::hashmap;
+use super::*;
+
+fn swarm_peer(address: &str, seed: bool, connectable: bool) -> SwarmPeer {
+    SwarmPeer { address: address.parse().unwrap(), seed, connectable }
+}
+
+#[test]
+fn test_message_round_trip() {
+    let message = PexMessage {
+        added: vec![
+            ("1.2.3.4:6881".parse().unwrap(), FLAG_SEED),
+            ("[2001:db8::1]:51413".parse().unwrap(), FLAG_CONNECTABLE),
+        ],
+        dropped: vec!["5.6.7.8:80".parse().unwrap()],
+    };
+    let encoded = message.encode();
+    assert!(encoded.starts_with(b"d5:added6:\x01\x02\x03\x04\x1a\xe17:added.f1:\x02"));
+    assert_eq!(PexMessage::decode(&encoded), Ok(message));
+}
+
+#[test]
+fn test_decode() {
+    // flags may be left out, and so may whole lists
+    let message = PexMessage::decode(b"d5:added12:\x01\x02\x03\x04\x00\x50\x05\x06\x07\x08\x00\x51e").unwrap();
+    assert_eq!(message, PexMessage {
+        added: vec![("1.2.3.4:80".parse().unwrap(), 0), ("5.6.7.8:81".parse().unwrap(), 0)],
+        dropped: vec![],
+    });
+    assert!(PexMessage::decode(b"d5:added5:\x01\x02\x03\x04\x00e").is_err());
+    assert!(PexMessage::decode(b"le").is_err());
+}
+
+#[test]
+fn test_send_changes() {
+    let mut pex = UtPex::new();
+    let mut messages = Vec::new();
+    pex.on_swarm(&[swarm_peer("1.2.3.4:80", true, true), swarm_peer("[::1]:81", false, false)], &mut messages);
+    assert_eq!(messages.len(), 1);
+    let mut sent = PexMessage::decode(&messages[0]).unwrap();
+    sent.added.sort();
+    assert_eq!(sent, PexMessage {
+        added: vec![("1.2.3.4:80".parse().unwrap(), FLAG_SEED | FLAG_CONNECTABLE), ("[::1]:81".parse().unwrap(), 0)],
+        dropped: vec![],
+    });
+
+    // too soon for another message, even though the swarm changed
+    messages.clear();
+    pex.on_swarm(&[swarm_peer("1.2.3.4:80", true, true)], &mut messages);
+    assert!(messages.is_empty());
+
+    // once it has been long enough, only the difference is sent.  The timer may fire a little
+    // before a full interval since the last message went out
+    pex.last_sent = Some(Instant::now() - PEX_INTERVAL + Duration::from_secs(5));
+    pex.on_swarm(&[swarm_peer("1.2.3.4:80", true, true), swarm_peer("9.9.9.9:99", false, true)], &mut messages);
+    assert_eq!(PexMessage::decode(&messages[0]), Ok(PexMessage {
+        added: vec![("9.9.9.9:99".parse().unwrap(), FLAG_CONNECTABLE)],
+        dropped: vec!["[::1]:81".parse().unwrap()],
+    }));
+    assert_eq!(pex.sent, hashmap! {
+        "1.2.3.4:80".parse().unwrap() => FLAG_SEED | FLAG_CONNECTABLE,
+        "9.9.9.9:99".parse().unwrap() => FLAG_CONNECTABLE,
+    });
+
+    // nothing changed, so nothing is sent
+    messages.clear();
+    pex.last_sent = Some(Instant::now() - PEX_INTERVAL);
+    pex.on_swarm(&[swarm_peer("1.2.3.4:80", true, true), swarm_peer("9.9.9.9:99", false, true)], &mut messages);
+    assert!(messages.is_empty());
+}
+
+#[test]
+fn test_receive_peers() {
+    let mut pex = UtPex::new();
+    let many = PexMessage {
+        added: (1..=MAX_PEERS as u16 + 10).map(|port| (SocketAddr::from(([10, 0, 0, 1], port)), 0)).collect(),
+        dropped: vec![],
+    };
+    let mut outbox = Outbox::default();
+    pex.on_message(&many.encode(), &mut outbox).unwrap();
+    assert_eq!(outbox.peers, many.added[..MAX_PEERS].iter().map(|(address, _)| *address).collect::<Vec<_>>());
+    assert!(outbox.messages.is_empty());
+
+    // a peer sending too often is ignored, and a broken message is an error
+    let mut outbox = Outbox::default();
+    pex.on_message(&many.encode(), &mut outbox).unwrap();
+    assert!(outbox.peers.is_empty());
+    assert!(pex.on_message(b"i1e", &mut outbox).is_err());
+
+    // one that comes a little before the interval is up still counts
+    pex.last_received = Some(Instant::now() - PEX_INTERVAL + Duration::from_secs(5));
+    pex.on_message(&many.encode(), &mut outbox).unwrap();
+    assert_eq!(outbox.peers.len(), MAX_PEERS);
+}
+
+#[test]
+fn test_receive_unusable_peers() {
+    let mut pex = UtPex::new();
+    let message = PexMessage {
+        added: vec![
+            ("10.0.0.1:0".parse().unwrap(), 0),
+            ("0.0.0.0:6881".parse().unwrap(), 0),
+            ("[::]:6881".parse().unwrap(), 0),
+            ("10.0.0.1:6881".parse().unwrap(), 0),
+        ],
+        dropped: vec![],
+    };
+    let mut outbox = Outbox::default();
+    pex.on_message(&message.encode(), &mut outbox).unwrap();
+    assert_eq!(outbox.peers, vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+}
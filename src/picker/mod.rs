@@ -0,0 +1,122 @@
+//! picker decides which piece each peer should download next
+use bit_vec::BitVec;
+use rand::prelude::*;
+use std::collections::HashSet;
+
+#[cfg(test)]
+mod test;
+
+/// Until we have this many pieces, pick at random so we have something to share as soon as
+/// possible, instead of waiting on a rare piece from a slow peer
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Tracks how common each piece is in the swarm and which pieces are being downloaded
+pub struct PiecePicker {
+    // The number of connected peers that have each piece
+    availability: Vec<u32>,
+    // The pieces we have verified
+    have: BitVec,
+    // The number of peers each piece is currently assigned to.  Outside of endgame this is never
+    // more than 1
+    in_progress: Vec<u32>,
+}
+
+impl PiecePicker {
+    pub fn new(have: BitVec) -> Self {
+        let num_pieces = have.len();
+        PiecePicker {
+            availability: vec![0; num_pieces],
+            have,
+            in_progress: vec![0; num_pieces],
+        }
+    }
+
+    pub fn have(&self) -> &BitVec {
+        &self.have
+    }
+
+    /// Counts a peer's pieces towards the swarm's availability
+    pub fn add_peer(&mut self, peers_pieces: &BitVec) {
+        for (count, has) in self.availability.iter_mut().zip(peers_pieces.iter()) {
+            if has {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Removes a peer's pieces from the swarm's availability, e.g. when it disconnects
+    pub fn remove_peer(&mut self, peers_pieces: &BitVec) {
+        for (count, has) in self.availability.iter_mut().zip(peers_pieces.iter()) {
+            if has {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// A peer announced it got a new piece
+    pub fn peer_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Chooses a piece for a peer to download, and marks it as in progress.  Returns None if the
+    /// peer has nothing we need that is not already being downloaded
+    pub fn pick(&mut self, peers_pieces: &BitVec) -> Option<u32> {
+        let candidates: Vec<usize> = (0..self.have.len())
+            .filter(|&i| peers_pieces.get(i).unwrap_or(false) && !self.have[i] && self.in_progress[i] == 0)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut rng = thread_rng();
+        let index = if self.have.iter().filter(|h| *h).count() < RANDOM_FIRST_PIECES {
+            candidates[rng.gen_range(0, candidates.len())]
+        } else {
+            // rarest first, breaking ties at random so peers don't all chase the same piece
+            let rarest = candidates.iter().map(|&i| self.availability[i]).min().unwrap();
+            let rarest: Vec<usize> = candidates.into_iter()
+                .filter(|&i| self.availability[i] == rarest)
+                .collect();
+            rarest[rng.gen_range(0, rarest.len())]
+        };
+        self.in_progress[index] += 1;
+        Some(index as u32)
+    }
+
+    /// True once every piece we are missing is being downloaded by some peer.  From then on the
+    /// remaining pieces are handed out to every peer that has them, so the download doesn't stall
+    /// on the slowest peer
+    pub fn in_endgame(&self) -> bool {
+        !self.have.all() && (0..self.have.len()).all(|i| self.have[i] || self.in_progress[i] > 0)
+    }
+
+    /// Chooses an in progress piece for a peer to download as well, preferring the pieces with
+    /// the fewest peers on them.  Pieces in exclude (the ones the peer already has) are skipped
+    pub fn pick_endgame(&mut self, peers_pieces: &BitVec, exclude: &HashSet<u32>) -> Option<u32> {
+        let candidates: Vec<usize> = (0..self.have.len())
+            .filter(|&i| peers_pieces.get(i).unwrap_or(false) && !self.have[i] && !exclude.contains(&(i as u32)))
+            .collect();
+        let fewest = candidates.iter().map(|&i| self.in_progress[i]).min()?;
+        let candidates: Vec<usize> = candidates.into_iter()
+            .filter(|&i| self.in_progress[i] == fewest)
+            .collect();
+        let index = candidates[thread_rng().gen_range(0, candidates.len())];
+        self.in_progress[index] += 1;
+        Some(index as u32)
+    }
+
+    /// The piece was verified and written
+    pub fn complete(&mut self, index: u32) {
+        self.in_progress[index as usize] = 0;
+        self.have.set(index as usize, true);
+    }
+
+    /// The piece will not be finished by one of the peers it was assigned to.  Once no peer is
+    /// working on it, it can be picked again
+    pub fn abandon(&mut self, index: u32) {
+        let count = &mut self.in_progress[index as usize];
+        *count = count.saturating_sub(1);
+    }
+}
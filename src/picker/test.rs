@@ -0,0 +1,103 @@
+use super::*;
+
+fn bits(bits: &[bool]) -> BitVec {
+    let mut res = BitVec::from_elem(bits.len(), false);
+    for (i, b) in bits.iter().enumerate() {
+        res.set(i, *b);
+    }
+    res
+}
+
+#[test]
+fn test_pick_rarest_first() {
+    // we already have enough pieces to be past the random first stage
+    let mut have = BitVec::from_elem(8, false);
+    for i in 0..RANDOM_FIRST_PIECES {
+        have.set(i, true);
+    }
+    let mut picker = PiecePicker::new(have);
+    let all = BitVec::from_elem(8, true);
+    picker.add_peer(&all);
+    picker.add_peer(&bits(&[true, true, true, true, true, false, true, true]));
+    picker.add_peer(&bits(&[true, true, true, true, false, false, true, true]));
+    picker.peer_have(7);
+
+    // 5 is only held by one peer
+    assert_eq!(picker.pick(&all), Some(5));
+    // 4 is the rarest that is not in progress
+    assert_eq!(picker.pick(&all), Some(4));
+    // 6 is less common than 7
+    assert_eq!(picker.pick(&all), Some(6));
+    assert_eq!(picker.pick(&all), Some(7));
+    assert_eq!(picker.pick(&all), None);
+}
+
+#[test]
+fn test_pick_never_assigns_twice() {
+    let mut picker = PiecePicker::new(BitVec::from_elem(3, false));
+    let all = BitVec::from_elem(3, true);
+    picker.add_peer(&all);
+
+    let mut picked: Vec<u32> = (0..3).filter_map(|_| picker.pick(&all)).collect();
+    picked.sort();
+    assert_eq!(picked, vec![0, 1, 2]);
+    assert_eq!(picker.pick(&all), None);
+
+    // abandoned pieces can be picked again, completed ones can not
+    picker.abandon(1);
+    picker.complete(2);
+    assert_eq!(picker.pick(&all), Some(1));
+    assert_eq!(picker.pick(&all), None);
+    assert!(picker.have().get(2).unwrap());
+}
+
+#[test]
+fn test_pick_only_from_peers_pieces() {
+    let mut picker = PiecePicker::new(BitVec::from_elem(4, false));
+    let peers = bits(&[false, false, true, false]);
+    picker.add_peer(&peers);
+
+    assert_eq!(picker.pick(&peers), Some(2));
+    assert_eq!(picker.pick(&peers), None);
+    assert_eq!(picker.pick(&BitVec::from_elem(4, false)), None);
+}
+
+#[test]
+fn test_remove_peer() {
+    let mut picker = PiecePicker::new(BitVec::from_elem(2, false));
+    let all = BitVec::from_elem(2, true);
+    picker.add_peer(&all);
+    picker.add_peer(&all);
+    picker.remove_peer(&bits(&[true, false]));
+    assert_eq!(picker.availability, vec![1, 2]);
+}
+
+#[test]
+fn test_endgame() {
+    let mut picker = PiecePicker::new(bits(&[true, false, false]));
+    let all = BitVec::from_elem(3, true);
+    picker.add_peer(&all);
+    picker.add_peer(&all);
+
+    let first = picker.pick(&all).unwrap();
+    assert!(!picker.in_endgame());
+    let second = picker.pick(&all).unwrap();
+    assert!(picker.in_endgame());
+    assert_eq!(picker.pick(&all), None);
+
+    // a second peer can download the same pieces, but never one it already has assigned
+    let mut assigned = HashSet::new();
+    assigned.insert(first);
+    assert_eq!(picker.pick_endgame(&all, &assigned), Some(second));
+    assigned.insert(second);
+    assert_eq!(picker.pick_endgame(&all, &assigned), None);
+
+    // one peer giving up on a shared piece does not make it pickable normally
+    picker.abandon(second);
+    assert!(picker.in_endgame());
+    assert_eq!(picker.pick(&all), None);
+
+    picker.complete(first);
+    picker.complete(second);
+    assert!(!picker.in_endgame());
+}
@@ -4,8 +4,22 @@ use crypto::{
 };
 use bit_vec::BitVec;
 
+#[cfg(test)]
+mod test;
+
+/// The largest block that can be requested from a peer
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// The number of blocks a piece of piece_size bytes is split into
+pub fn blocks_in(piece_size: u32) -> usize {
+    let mut num_blocks = piece_size / BLOCK_SIZE;
+    num_blocks += if piece_size % BLOCK_SIZE == 0 { 0 } else { 1 };
+    num_blocks as usize
+}
+
 /// Holds the data of a downloaded piece
 pub struct Piece {
+    index: u32,
     data: Vec<u8>,
     hasher: Sha1,
     hash: [u8; 20],
@@ -15,17 +29,77 @@ pub struct Piece {
 }
 
 impl Piece {
-    pub fn new(piece_size: u32, piece_hash: [u8;20]) -> Self {
-        let mut num_subpieces = piece_size / (1 << 14);
-        num_subpieces += if piece_size % (1 << 14) == 0 { 0 } else { 1 };
+    pub fn new(index: u32, piece_size: u32, piece_hash: [u8;20]) -> Self {
         Piece {
-            data: Vec::with_capacity(piece_size as usize),
+            index,
+            data: vec![0; piece_size as usize],
             hasher: Sha1::new(),
             hash: piece_hash,
-            sub_pieces: BitVec::from_elem(num_subpieces as usize, false)
+            sub_pieces: BitVec::from_elem(blocks_in(piece_size), false)
         }
     }
 
+    /// Creates a piece whose data is already known, e.g. read back from disk
+    pub fn from_data(index: u32, data: Vec<u8>, piece_hash: [u8;20]) -> Self {
+        let mut piece = Piece::new(index, data.len() as u32, piece_hash);
+        piece.data = data;
+        piece.sub_pieces.set_all();
+        piece
+    }
+
+    /// Creates a partially downloaded piece, where only the blocks marked in blocks are valid
+    pub fn from_blocks(index: u32, data: Vec<u8>, piece_hash: [u8;20], blocks: &BitVec) -> Self {
+        let mut piece = Piece::new(index, data.len() as u32, piece_hash);
+        piece.data = data;
+        for (i, have) in blocks.iter().take(piece.num_blocks()).enumerate() {
+            piece.sub_pieces.set(i, have);
+        }
+        piece
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The number of blocks that make up this piece
+    pub fn num_blocks(&self) -> usize {
+        self.sub_pieces.len()
+    }
+
+    /// Whether the block at block_index has been received
+    pub fn has_block(&self, block_index: usize) -> bool {
+        self.sub_pieces.get(block_index).unwrap_or(false)
+    }
+
+    /// The (begin, length) of a block to request.  Every block is BLOCK_SIZE except the last,
+    /// which may be short
+    pub fn block_range(&self, block_index: usize) -> (u32, u32) {
+        let begin = block_index as u32 * BLOCK_SIZE;
+        (begin, u32::min(BLOCK_SIZE, self.data.len() as u32 - begin))
+    }
+
+    /// Stores a block received from a peer.  Returns false if the block does not line up with
+    /// one of this piece's blocks
+    pub fn add_block(&mut self, begin: u32, block: &[u8]) -> bool {
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        if begin % BLOCK_SIZE != 0 || block_index >= self.num_blocks()
+            || self.block_range(block_index).1 as usize != block.len() {
+            return false;
+        }
+        self.data[begin as usize..begin as usize + block.len()].copy_from_slice(block);
+        self.sub_pieces.set(block_index, true);
+        true
+    }
+
+    /// Whether every block has been received
+    pub fn is_complete(&self) -> bool {
+        self.sub_pieces.all()
+    }
+
     pub fn verify(&mut self) -> bool {
         self.hasher.reset();
         self.hasher.input(&self.data);
@@ -34,4 +108,4 @@ impl Piece {
         return data_hash == self.hash;
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,29 @@
+use super::*;
+
+#[test]
+fn test_blocks_with_short_final_block() {
+    let piece = Piece::new(0, BLOCK_SIZE * 2 + 100, [0; 20]);
+
+    assert_eq!(piece.num_blocks(), 3);
+    assert_eq!(piece.block_range(0), (0, BLOCK_SIZE));
+    assert_eq!(piece.block_range(1), (BLOCK_SIZE, BLOCK_SIZE));
+    assert_eq!(piece.block_range(2), (BLOCK_SIZE * 2, 100));
+}
+
+#[test]
+fn test_add_block() {
+    let mut piece = Piece::new(3, BLOCK_SIZE + 4, [0; 20]);
+
+    // misaligned, wrong size, and out of range blocks are refused
+    assert!(!piece.add_block(1, &[0; 4]));
+    assert!(!piece.add_block(BLOCK_SIZE, &[0; 5]));
+    assert!(!piece.add_block(BLOCK_SIZE * 2, &[0; 4]));
+
+    assert!(piece.add_block(BLOCK_SIZE, &[1, 2, 3, 4]));
+    assert!(piece.has_block(1));
+    assert!(!piece.is_complete());
+
+    assert!(piece.add_block(0, &vec![9; BLOCK_SIZE as usize]));
+    assert!(piece.is_complete());
+    assert_eq!(&piece.data()[BLOCK_SIZE as usize - 1..], &[9, 1, 2, 3, 4]);
+}
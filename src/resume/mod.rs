@@ -0,0 +1,148 @@
+//! resume persists download progress between runs, so restarts don't have to rehash every piece
+use bit_vec::BitVec;
+use crate::boostencode::{FromValue, Value};
+use crate::metainfo::InfoDict;
+use crate::piece::blocks_in;
+use crate::storage::FileStamp;
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[cfg(test)]
+mod test;
+
+/// Everything needed to pick a download back up without a recheck
+#[derive(Debug, PartialEq, Clone)]
+pub struct Resume {
+    // The pieces that have been verified and written
+    pub have: BitVec,
+    // The state of each file when this was saved.  If any differ, the data can't be trusted
+    pub files: Vec<FileStamp>,
+    // Blocks of unfinished pieces that are already on disk
+    pub partial: HashMap<u32, BitVec>,
+    // Lifetime transfer totals
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl FromValue for Resume {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> where Self: Sized {
+        let map = val.dict().ok_or("Resume not a dictionary".to_string())?;
+
+        let have = map.get("have".as_bytes()).and_then(Value::bstring)
+            .map(|bytes| BitVec::from_bytes(bytes))
+            .ok_or("Missing key: have".to_string())?;
+
+        let files = map.get("files".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: files".to_string())?
+            .iter()
+            .map(|file| {
+                let file = file.dict().ok_or("File not a dictionary".to_string())?;
+                let length = file.get("length".as_bytes()).and_then(Value::integer)
+                    .ok_or("Missing key: length".to_string())?;
+                let modified = file.get("mtime".as_bytes()).and_then(Value::integer)
+                    .ok_or("Missing key: mtime".to_string())?;
+                // Values that don't fit mean the file is damaged, and the pieces get rechecked
+                let length = u64::try_from(*length).map_err(|_| format!("Invalid length: {}", length))?;
+                let modified = u64::try_from(*modified).map_err(|_| format!("Invalid mtime: {}", modified))?;
+                Ok(FileStamp { length, modified })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let partial = map.get("partial".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: partial".to_string())?
+            .iter()
+            .map(|piece| {
+                let piece = piece.dict().ok_or("Partial piece not a dictionary".to_string())?;
+                let index = piece.get("index".as_bytes()).and_then(Value::integer)
+                    .ok_or("Missing key: index".to_string())?;
+                let blocks = piece.get("blocks".as_bytes()).and_then(Value::bstring)
+                    .ok_or("Missing key: blocks".to_string())?;
+                let index = u32::try_from(*index).map_err(|_| format!("Invalid piece index: {}", index))?;
+                Ok((index, BitVec::from_bytes(blocks)))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+
+        let uploaded = map.get("uploaded".as_bytes()).and_then(Value::integer)
+            .map_or(0, |i| *i as u64);
+        let downloaded = map.get("downloaded".as_bytes()).and_then(Value::integer)
+            .map_or(0, |i| *i as u64);
+
+        Ok(Resume {
+            have,
+            files,
+            partial,
+            uploaded,
+            downloaded,
+        })
+    }
+}
+
+impl Resume {
+    /// Reads a resume file.  Returns None if there isn't one or it can't be understood
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = fs::read(path).ok()?;
+        let val = Value::decode(&bytes).ok()?;
+        Resume::from_value(&val).ok()
+    }
+
+    /// Writes the resume file, replacing the old one in a single step so a crash can't leave
+    /// half of one behind
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, self.to_value().encode())?;
+        fs::rename(temp, path)
+    }
+
+    pub fn to_value(&self) -> Value {
+        let files = self.files.iter()
+            .map(|file| Value::Dict(hashmap! {
+                b"length".to_vec() => Value::Integer(file.length as i64),
+                b"mtime".to_vec() => Value::Integer(file.modified as i64),
+            }))
+            .collect();
+        let mut partial: Vec<_> = self.partial.iter().collect();
+        partial.sort_by_key(|(index, _)| **index);
+        let partial = partial.into_iter()
+            .map(|(index, blocks)| Value::Dict(hashmap! {
+                b"index".to_vec() => Value::Integer(*index as i64),
+                b"blocks".to_vec() => Value::BString(blocks.to_bytes()),
+            }))
+            .collect();
+        Value::Dict(hashmap! {
+            b"have".to_vec() => Value::BString(self.have.to_bytes()),
+            b"files".to_vec() => Value::List(files),
+            b"partial".to_vec() => Value::List(partial),
+            b"uploaded".to_vec() => Value::Integer(self.uploaded as i64),
+            b"downloaded".to_vec() => Value::Integer(self.downloaded as i64),
+        })
+    }
+
+    /// Checks the resume data against the torrent and the files on disk.  Returns it with the
+    /// bitfields trimmed to size if it can be trusted
+    pub fn validate(mut self, info: &InfoDict, files: &[FileStamp]) -> Option<Self> {
+        let num_pieces = info.num_pieces();
+        // Bitfields are stored as whole bytes, so they come back padded out to a multiple of 8
+        if self.files != files || self.have.len() < num_pieces || self.have.len() >= num_pieces + 8 {
+            return None;
+        }
+        self.have.truncate(num_pieces);
+        for (index, blocks) in self.partial.iter_mut() {
+            let index = *index as usize;
+            if index >= num_pieces || self.have[index] {
+                return None;
+            }
+            let num_blocks = blocks_in(info.piece_size(index) as u32);
+            if blocks.len() < num_blocks || blocks.len() >= num_blocks + 8 {
+                return None;
+            }
+            blocks.truncate(num_blocks);
+        }
+        Some(self)
+    }
+}
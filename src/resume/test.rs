@@ -0,0 +1,82 @@
+use crate::metainfo::{FileInfo, SingleFile};
+use crate::piece::BLOCK_SIZE;
+use super::*;
+
+/// Three pieces of two blocks each, except the last which has one
+fn info() -> InfoDict {
+    InfoDict {
+        piece_length: BLOCK_SIZE as usize * 2,
+        pieces: vec![String::new(); 3],
+        private: false,
+        file_info: FileInfo::Single(SingleFile {
+            file_name: "file".to_string(),
+            length: BLOCK_SIZE as usize * 5,
+            md5sum: None,
+        }),
+    }
+}
+
+fn resume() -> Resume {
+    Resume {
+        have: BitVec::from_bytes(&[0b1000_0000]),
+        files: vec![FileStamp { length: BLOCK_SIZE as u64 * 5, modified: 1_546_300_800_123_456_789 }],
+        partial: hashmap! { 1 => BitVec::from_bytes(&[0b0100_0000]) },
+        uploaded: 5_000_000_000,
+        downloaded: 123,
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let resume = resume();
+    let decoded = Value::decode(&resume.to_value().encode()).unwrap();
+    assert_eq!(Resume::from_value(&decoded), Ok(resume));
+}
+
+#[test]
+fn test_out_of_range() {
+    // values that can't be right are refused, so the data gets rechecked instead
+    let with = |key: &[u8], value: i64| {
+        let mut val = resume().to_value();
+        if let Value::Dict(map) = &mut val {
+            let list = if key == b"index" { &b"partial"[..] } else { &b"files"[..] };
+            if let Some(Value::List(items)) = map.get_mut(list) {
+                if let Value::Dict(item) = &mut items[0] {
+                    item.insert(key.to_vec(), Value::Integer(value));
+                }
+            }
+        }
+        Resume::from_value(&val)
+    };
+    assert!(with(b"length", -1).is_err());
+    assert!(with(b"mtime", -1).is_err());
+    assert!(with(b"index", 1 << 32).is_err());
+    assert!(with(b"index", -1).is_err());
+    assert!(with(b"index", 2).is_ok());
+}
+
+#[test]
+fn test_validate() {
+    let files = resume().files;
+    let valid = resume().validate(&info(), &files).unwrap();
+    assert_eq!(valid.have.len(), 3);
+    assert_eq!(valid.partial[&1].len(), 2);
+    assert!(valid.partial[&1][1]);
+
+    // the files changed since the resume file was written
+    let touched = vec![FileStamp { modified: 0, ..files[0].clone() }];
+    assert_eq!(resume().validate(&info(), &touched), None);
+
+    // a partial piece that is already finished, or doesn't exist
+    let mut finished = resume();
+    finished.partial = hashmap! { 0 => BitVec::from_bytes(&[0]) };
+    assert_eq!(finished.validate(&info(), &files), None);
+    let mut missing = resume();
+    missing.partial = hashmap! { 3 => BitVec::from_bytes(&[0]) };
+    assert_eq!(missing.validate(&info(), &files), None);
+
+    // a bitfield for a different number of pieces
+    let mut wrong_size = resume();
+    wrong_size.have = BitVec::from_bytes(&[0, 0]);
+    assert_eq!(wrong_size.validate(&info(), &files), None);
+}
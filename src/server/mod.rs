@@ -1,81 +1,1032 @@
 use bit_vec::BitVec;
-use futures::sync::mpsc::{channel, Receiver, Sender};
+use bytes::Bytes;
+use futures::sync::mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender};
 use log::{
+    debug,
     error,
+    info,
     trace,
     warn,
 };
-use crate::metainfo::MetaInfo;
-use crate::peer::Peer;
-use crate::piece::Piece;
+use crate::dht::DhtHandle;
+use crate::choker::{
+    Choker,
+    PeerStats,
+};
+use crate::metadata::UtMetadata;
+use crate::metainfo::{
+    InfoDict,
+    MetaInfo,
+};
+use crate::peer::{
+    extension::{
+        Registry,
+        SwarmPeer,
+    },
+    Peer,
+    PeerChannels,
+    PeerCommand,
+    PeerConfig,
+    PeerEvent,
+};
+use crate::pex::{
+    PEX_INTERVAL,
+    UtPex,
+};
+use crate::picker::PiecePicker;
+use crate::resume::Resume;
+use crate::piece::{
+    blocks_in,
+    BLOCK_SIZE,
+    Piece,
+};
+use crate::storage::{
+    Storage,
+    StorageError,
+};
+use net2::TcpBuilder;
 use replace_with::replace_with;
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
 use std::default::Default;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+    UdpSocket,
+};
 use std::ops::Deref;
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{
+    Duration,
+    Instant,
+};
 use tokio::{
     io::Error,
     net::{
-        tcp::Incoming,
         TcpListener,
+        TcpStream,
     },
     prelude::{
         Async,
         Future,
         future,
+        Sink,
         Stream,
         stream,
     },
+    reactor::Handle,
     spawn,
+    timer::{
+        Delay,
+        Interval,
+    },
 };
 use crate::tracker::{
+    PeerInfo,
+    RetryIn,
     Tracker,
+    TrackerError,
     TrackerResponse,
 };
 
+#[cfg(test)]
+mod test;
+
 /// Type alias for a heap allocated Stream trait object
 type BoxedStream<T> = Box<dyn Stream<Item=T, Error=()> + Send>;
 
+/// The port we listen for peers on
+const PORT: u16 = 6888;
+
+/// How often the choking algorithm runs, and the resume file is saved
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait before retrying after the first failed announce.  Doubles with each failure
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(15);
+/// The longest we will wait between retries
+const MAX_ANNOUNCE_RETRY: Duration = Duration::from_secs(30 * 60);
+/// How soon we may announce again to find more peers, if the tracker doesn't give a min interval
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The most peers we remember to connect to later, once there is room
+const MAX_CANDIDATES: usize = 1000;
+/// How often we look for peers in the DHT, and announce ourselves there
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How long we wait for the tracker to hear that we are stopping before leaving anyway
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we leave a peer alone after we failed to reach it, or it misbehaved
+const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+
+/// Tunable settings for a Server
+pub struct Config {
+    // The directory the torrent's files are written to
+    pub download_dir: PathBuf,
+    // The most peers we will be connected to at once, inbound and outbound combined
+    pub max_connections: usize,
+    // The most block requests each peer will have in flight at once
+    pub max_requests: usize,
+    // How many peers we upload to at once, not counting the optimistic unchoke
+    pub unchoke_slots: usize,
+    // Whether to keep running and uploading once the download is complete
+    pub seed: bool,
+    // The address to have the tracker give out, if peers can't reach us at the one it sees
+    pub announce_ip: Option<IpAddr>,
+    // A DHT node to find peers through, as well as the tracker
+    pub dht: Option<DhtHandle>,
+    // Resolves when we should tell the tracker we are stopping, and finish
+    pub shutdown: Option<Box<dyn Future<Item=(), Error=()> + Send>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            download_dir: PathBuf::from("."),
+            max_connections: 50,
+            max_requests: 16,
+            unchoke_slots: 4,
+            seed: false,
+            announce_ip: None,
+            dht: None,
+            shutdown: None,
+        }
+    }
+}
+
+/// The server's side of the channels to a running peer task
+struct PeerHandle {
+    commands: UnboundedSender<PeerCommand>,
+    // Pieces sent here are downloaded by the peer
+    pieces: Sender<Piece>,
+    // The pieces the peer has told us it has
+    bitfield: BitVec,
+    // The pieces the peer is currently downloading for us
+    assigned: HashSet<u32>,
+    // Whether the peer is letting us download
+    unchoked: bool,
+    // Pieces the peer lets us download even while it is choking us
+    allowed_fast: HashSet<u32>,
+    // Whether the peer wants to download from us
+    interested: bool,
+    // Whether we are letting the peer download
+    am_unchoking: bool,
+    // Bytes transferred with the peer since the last choking round
+    downloaded: u64,
+    uploaded: u64,
+    // Where the peer accepts connections, if we know
+    listen_address: Option<SocketAddr>,
+    // We connected to the peer, rather than it to us
+    initiated: bool,
+    // The id the peer gave in its handshake, once it has arrived
+    peer_id: Option<[u8; 20]>,
+}
+
+impl PeerHandle {
+    /// Takes back the pieces the peer can't download while it is choking us, telling the peer and
+    /// the picker.  Returns true if there were any
+    fn release_pieces(&mut self, picker: &mut PiecePicker) -> bool {
+        let allowed_fast = &self.allowed_fast;
+        let released: Vec<u32> = self.assigned.iter()
+            .filter(|index| !allowed_fast.contains(index))
+            .cloned()
+            .collect();
+        for &index in &released {
+            trace!("Releasing piece {} from a choking peer", index);
+            self.assigned.remove(&index);
+            picker.abandon(index);
+            let _ = self.commands.unbounded_send(PeerCommand::Release(index));
+        }
+        !released.is_empty()
+    }
+}
+
+/// Passes a block one peer delivered on to everyone else downloading its piece, so they can cancel
+/// their requests for it
+fn share_block(peers: &HashMap<SocketAddr, PeerHandle>, from: &SocketAddr, index: u32, begin: u32, block: &Bytes) {
+    for (address, handle) in peers.iter() {
+        if address != from && handle.assigned.contains(&index) {
+            let _ = handle.commands.unbounded_send(PeerCommand::Block(index, begin, block.clone()));
+        }
+    }
+}
+
+/// Peers we won't connect to again for a while
+struct Banned {
+    // When each address may be dialed again
+    until: HashMap<SocketAddr, Instant>,
+}
+
+impl Banned {
+    fn new() -> Self {
+        Banned { until: HashMap::new() }
+    }
+
+    /// Keeps us from dialing the address for the next BAN_DURATION, and forgets expired bans
+    fn insert(&mut self, address: SocketAddr, now: Instant) {
+        self.until.retain(|_, until| *until > now);
+        self.until.insert(address, now + BAN_DURATION);
+    }
+
+    fn contains(&self, address: &SocketAddr, now: Instant) -> bool {
+        self.until.get(address).filter(|until| **until > now).is_some()
+    }
+}
 
 /// This is the server that will listen for and spawn peer connections, manage the tracker, and
 /// write pieces to the file.  This is "main" for a client
 pub struct Server {
     peer_id: [u8; 20],
     info_hash: [u8; 20],
+    info: InfoDict,
+    // The encoded info dictionary, for peers that ask for it with ut_metadata
+    metadata: Arc<Vec<u8>>,
     uploaded: u64,
-    uploaded_stream: BoxedStream<u32>,
+    uploaded_stream: BoxedStream<(SocketAddr, u32)>,
     downloaded: u64,
-    downloaded_stream: BoxedStream<u32>,
+    downloaded_stream: BoxedStream<(SocketAddr, u32)>,
     left: u64,
-    listener: Incoming,
-    tracker: Tracker,
-    piece_stream: BoxedStream<(Piece, Sender<Piece>, BitVec)>,
+    // Incoming connections on every address family we could listen on
+    listener: Box<dyn Stream<Item=TcpStream, Error=io::Error> + Send>,
+    // None when the torrent has no trackers, so peers only come from the DHT and peer exchange
+    tracker: Option<Tracker>,
+    // Fires when it is time for the next announce.  None while one is in flight
+    announce_timer: Option<Delay>,
+    // Whether the tracker is working on an announce
+    announcing: bool,
+    // When the last announce was sent
+    last_announce: Instant,
+    // How soon after the last announce the tracker lets us announce again
+    min_interval: Duration,
+    // Announces that failed in a row, for backing off
+    announce_failures: u32,
+    // Transfer totals when we announced started, since trackers only want to hear about this session
+    uploaded_at_start: u64,
+    downloaded_at_start: u64,
+    // Set once every piece is downloaded
+    completed: bool,
+    // Set once we have told the tracker we are leaving.  We finish when it answers
+    stopping: bool,
+    // How long we give the tracker to answer once we are stopping
+    stop_timer: Option<Delay>,
+    piece_stream: BoxedStream<(SocketAddr, Piece)>,
+    event_stream: BoxedStream<(SocketAddr, PeerEvent)>,
+    storage: Arc<Storage>,
+    picker: PiecePicker,
+    choker: Choker,
+    choke_timer: Interval,
+    config: Config,
+    // Every peer we currently have a task for
+    peers: HashMap<SocketAddr, PeerHandle>,
+    // Peers we have heard about but not connected to, oldest first
+    candidates: VecDeque<SocketAddr>,
+    // Peers we failed to reach, that misbehaved, or that turned out to be us
+    banned: Banned,
+    // The addresses of this machine, so we don't dial our own listener
+    local_ips: Vec<IpAddr>,
+    // Fires when it is time to tell peers about each other
+    pex_timer: Interval,
+    // Fires when it is time to look for peers in the DHT
+    dht_timer: Interval,
+    // Peers found by the current DHT lookup
+    dht_peers: Option<UnboundedReceiver<SocketAddr>>,
+    // Set once every missing piece is being downloaded
+    endgame: bool,
+    // Which blocks of unfinished pieces have been written to disk
+    partial: HashMap<u32, BitVec>,
+    // Where progress is saved between runs
+    resume_path: PathBuf,
+    // Peer tasks send their address here when they end, and whether they ended in an error
+    disconnected_sender: Sender<(SocketAddr, bool)>,
+    disconnected_receiver: Receiver<(SocketAddr, bool)>,
 }
 
 impl Server {
-    pub fn new(peer_id: [u8; 20], meta: MetaInfo) -> Self {
-        let address = SocketAddr::from_str("0.0.0.0:6888").unwrap();
-        let download_size = meta.info.file_info.size() as u64;
-        let mut tracker = Tracker::new(
-            peer_id.clone(),
-            meta.announce,
-            meta.info_hash.clone(),
-            6888,
-        );
+    pub fn new(peer_id: [u8; 20], meta: MetaInfo, config: Config) -> Self {
+        let tiers = meta.tiers();
+        let mut tracker = if tiers.is_empty() {
+            info!("The torrent has no trackers, finding peers through the DHT and peer exchange");
+            None
+        } else {
+            Some(Tracker::new(
+                peer_id,
+                tiers,
+                meta.info_hash,
+                PORT,
+            ))
+        };
+        let mut local_ips: Vec<IpAddr> = local_ipv4().into_iter().collect();
+        if let Some(address) = local_ipv6() {
+            debug!("Announcing IPv6 address {}", address);
+            if let Some(tracker) = tracker.as_mut() {
+                tracker.set_ipv6(address);
+            }
+            local_ips.push(IpAddr::V6(address));
+        }
+        if let Some(address) = config.announce_ip {
+            if let Some(tracker) = tracker.as_mut() {
+                tracker.set_ip(address);
+            }
+            local_ips.push(address);
+        }
+        if let Some(tracker) = tracker.as_mut() {
+            // there's no use hearing about more peers than we can connect to
+            tracker.set_num_want(config.max_connections as u32);
+        }
         let info_hash = meta.info_hash;
-        tracker.start(download_size);
+        let info = meta.info;
+        let metadata = Arc::new(meta.info_bytes);
+        let storage = Arc::new(Storage::new(&config.download_dir, &info).expect("Failed to create download files"));
+        // Pick up wherever a previous run left off, only hashing everything if we can't trust the
+        // resume file
+        let hex_hash: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        let resume_path = config.download_dir.join(format!(".{}.resume", hex_hash));
+        let resume = storage.stamps().ok()
+            .and_then(|stamps| Resume::load(&resume_path).and_then(|r| r.validate(&info, &stamps)));
+        let (have, partial, uploaded, downloaded) = match resume {
+            Some(resume) => (resume.have, resume.partial, resume.uploaded, resume.downloaded),
+            None => {
+                info!("No usable resume file, checking existing data");
+                (storage.recheck(&info), HashMap::new(), 0, 0)
+            }
+        };
+        let left = (0..info.num_pieces())
+            .filter(|&i| !have[i])
+            .map(|i| info.piece_size(i) as u64)
+            .sum();
+        info!("Already have {} of {} pieces, {} bytes left", have.iter().filter(|h| *h).count(), have.len(), left);
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.start(left);
+        }
+        let (disconnected_sender, disconnected_receiver) = channel(10);
+        let picker = PiecePicker::new(have);
         Server {
             peer_id,
             info_hash,
-            uploaded: 0,
+            info,
+            metadata,
+            uploaded,
             uploaded_stream: Box::new(stream::empty()),
-            downloaded: 0,
+            downloaded,
             downloaded_stream: Box::new(stream::empty()),
-            left: download_size,
-            listener: TcpListener::bind(&address).expect("Failed to open TCP listener").incoming(),
+            left,
+            listener: listen(PORT).expect("Failed to open TCP listener"),
+            announcing: tracker.is_some(),
             tracker,
+            announce_timer: None,
+            last_announce: Instant::now(),
+            min_interval: DEFAULT_MIN_INTERVAL,
+            announce_failures: 0,
+            uploaded_at_start: uploaded,
+            downloaded_at_start: downloaded,
+            completed: left == 0,
+            stopping: false,
+            stop_timer: None,
             piece_stream: Box::new(stream::empty()),
+            event_stream: Box::new(stream::empty()),
+            storage,
+            picker,
+            choker: Choker::new(config.unchoke_slots),
+            choke_timer: Interval::new_interval(CHOKE_INTERVAL),
+            config,
+            peers: HashMap::new(),
+            candidates: VecDeque::new(),
+            banned: Banned::new(),
+            local_ips,
+            pex_timer: Interval::new_interval(PEX_INTERVAL),
+            // The first lookup starts straight away, alongside the first announce
+            dht_timer: Interval::new(Instant::now(), DHT_INTERVAL),
+            dht_peers: None,
+            endgame: false,
+            partial,
+            resume_path,
+            disconnected_sender,
+            disconnected_receiver,
+        }
+    }
+
+    /// Hooks a new peer's channels into the server's streams and spawns the peer task once the
+    /// connection is established.  The task reports its address on the disconnected channel when
+    /// it ends, along with whether it failed
+    fn start_peer<F>(&mut self, address: SocketAddr, connection: F, initiates: bool)
+        where F: Future<Item=TcpStream, Error=()> + Send + 'static {
+        let (up_sender, up_receiver) = channel(10);
+        let (down_sender, down_receiver) = channel(10);
+        let (piece_sender, piece_receiver) = channel(10);
+        let (event_sender, event_receiver) = unbounded();
+        let (command_sender, command_receiver) = unbounded();
+        let (new_piece_sender, new_piece_receiver) = channel(10);
+
+        replace_with(&mut self.uploaded_stream,
+                     /* default, in case replacement panics */ || Box::new(stream::empty()),
+                     |s| Box::new(s.select(up_receiver.map(move |u| (address, u)))));
+        replace_with(&mut self.downloaded_stream,
+                     || Box::new(stream::empty()),
+                     |s| Box::new(s.select(down_receiver.map(move |d| (address, d)))));
+        replace_with(&mut self.piece_stream,
+                     || Box::new(stream::empty()),
+                     |s| Box::new(s.select(piece_receiver.map(move |p| (address, p)))));
+        replace_with(&mut self.event_stream,
+                     || Box::new(stream::empty()),
+                     |s| Box::new(s.select(event_receiver.map(move |e| (address, e)))));
+
+        self.peers.insert(address, PeerHandle {
+            commands: command_sender,
+            pieces: new_piece_sender,
+            bitfield: BitVec::from_elem(self.info.num_pieces(), false),
+            assigned: HashSet::new(),
+            unchoked: false,
+            allowed_fast: HashSet::new(),
+            interested: false,
+            am_unchoking: false,
+            downloaded: 0,
+            uploaded: 0,
+            listen_address: if initiates { Some(address) } else { None },
+            initiated: initiates,
+            peer_id: None,
+        });
+
+        let storage = self.storage.clone();
+        let mut extensions = Registry::new(Some(PORT));
+        extensions.register(Box::new(UtMetadata::new(self.metadata.clone())));
+        // Private torrents only get their peers from the tracker
+        if !self.info.private {
+            extensions.register(Box::new(UtPex::new()));
+        }
+        let channels = PeerChannels {
+            uploaded: up_sender,
+            downloaded: down_sender,
+            finished_pieces: piece_sender,
+            pieces: new_piece_receiver,
+            events: event_sender,
+            commands: command_receiver,
+        };
+        let config = PeerConfig {
+            extensions,
+            max_requests: self.config.max_requests,
+            initiates,
+        };
+        let our_pieces = self.picker.have().clone();
+        let info_hash = self.info_hash;
+        let peer_id = self.peer_id;
+        let disconnected_sender = self.disconnected_sender.clone();
+        let peer = connection.and_then(move |conn| Peer::new(conn,
+                                                             channels,
+                                                             config,
+                                                             storage,
+                                                             our_pieces,
+                                                             info_hash,
+                                                             peer_id));
+        spawn(peer.then(move |result| {
+            disconnected_sender.send((address, result.is_err())).then(|_| Ok(()))
+        }));
+    }
+
+    /// Forgets about a peer whose task has ended, freeing up any pieces it was downloading
+    fn remove_peer(&mut self, address: &SocketAddr) {
+        if let Some(handle) = self.peers.remove(address) {
+            self.picker.remove_peer(&handle.bitfield);
+            for index in handle.assigned {
+                self.picker.abandon(index);
+            }
+            let addresses: Vec<SocketAddr> = self.peers.keys().cloned().collect();
+            for address in addresses {
+                self.assign_pieces(&address);
+            }
+            if !self.stopping {
+                self.connect_to_candidates();
+            }
+            if self.peers.is_empty() {
+                self.announce_early();
+            }
+        }
+    }
+
+    /// Bytes transferred since we announced started, which is what trackers want to hear about
+    fn session_stats(&self) -> (u64, u64) {
+        (self.uploaded - self.uploaded_at_start, self.downloaded - self.downloaded_at_start)
+    }
+
+    /// Sends the regular announce once the interval is up, or retries the last one if it failed
+    fn reannounce(&mut self) {
+        let (uploaded, downloaded) = self.session_stats();
+        let tracker = match self.tracker.as_mut() {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        if self.announce_failures > 0 {
+            tracker.retry(self.left, uploaded, downloaded);
+        } else {
+            tracker.refresh(self.left, uploaded, downloaded);
+        }
+        self.announce_sent();
+    }
+
+    /// Notes that an announce went out.  The next one is scheduled when it is answered
+    fn announce_sent(&mut self) {
+        self.announcing = true;
+        self.last_announce = Instant::now();
+        self.announce_timer = None;
+    }
+
+    /// Asks for more peers as soon as the tracker allows, because we ran out
+    fn announce_early(&mut self) {
+        if self.tracker.is_none() || self.announcing || self.announce_failures > 0 || self.stopping {
+            return;
+        }
+        let earliest = self.last_announce + self.min_interval;
+        let sooner = match &self.announce_timer {
+            Some(timer) => earliest < timer.deadline(),
+            None => true,
+        };
+        if sooner {
+            debug!("Out of peers, announcing early");
+            self.announce_timer = Some(Delay::new(earliest));
+        }
+    }
+
+    /// Connects to the peers an announce found, and schedules the next announce.  Failures are
+    /// retried with exponential backoff
+    fn handle_announce(&mut self, result: Result<TrackerResponse, TrackerError>) {
+        self.announcing = false;
+        let resp = match result {
+            Ok(TrackerResponse::Success(resp)) => resp,
+            Ok(TrackerResponse::Warning(msg, resp)) => {
+                warn!("The tracker responeded with a warning: {}", msg);
+                resp
+            }
+            Ok(TrackerResponse::Failure(msg, retry_in)) => {
+                error!("The tracker refused our announce: {}", msg);
+                self.announce_failed(retry_in);
+                return;
+            }
+            Err(e) => {
+                error!("Something went wrong in making a request to the tracker: {:?}", e);
+                self.announce_failed(None);
+                return;
+            }
+        };
+        trace!("tracker response: {:?}", resp);
+        self.announce_failures = 0;
+        self.min_interval = resp.min_interval.map_or(DEFAULT_MIN_INTERVAL, |secs| Duration::from_secs(secs as u64));
+        self.announce_timer = Some(Delay::new(Instant::now() + Duration::from_secs(resp.interval as u64)));
+        self.connect_to_peers(&resp.peers);
+    }
+
+    /// Schedules a retry of a failed announce, when the tracker asks for one or with exponential
+    /// backoff otherwise
+    fn announce_failed(&mut self, retry_in: Option<RetryIn>) {
+        let backoff = match retry_in {
+            Some(RetryIn::Never) => {
+                warn!("The tracker asked us not to announce again");
+                self.announce_failures += 1;
+                self.announce_timer = None;
+                return;
+            }
+            Some(RetryIn::Minutes(minutes)) => Duration::from_secs(u64::from(minutes) * 60),
+            None => Duration::min(ANNOUNCE_RETRY * 2u32.pow(u32::min(self.announce_failures, 16)), MAX_ANNOUNCE_RETRY),
+        };
+        self.announce_failures += 1;
+        debug!("Retrying the announce in {:?}", backoff);
+        self.announce_timer = Some(Delay::new(Instant::now() + backoff));
+    }
+
+    /// Drives the tracker: sends completed when the download finishes, re-announces on schedule,
+    /// and handles responses.  Returns true once we have told the tracker we are stopping and it
+    /// answered
+    fn poll_tracker(&mut self) -> bool {
+        if self.left == 0 && !self.completed {
+            info!("Download complete");
+            self.completed = true;
+            self.save_resume();
+            let (uploaded, downloaded) = self.session_stats();
+            if let Some(tracker) = self.tracker.as_mut() {
+                tracker.finish(0, uploaded, downloaded);
+                self.announce_sent();
+            }
+        }
+        if self.tracker.is_none() {
+            // Without a tracker there is nobody to say goodbye to
+            return self.stopping || (self.completed && !self.config.seed);
+        }
+        let timed_out = matches!(self.stop_timer.as_mut().map(Future::poll), Some(Ok(Async::Ready(()))) | Some(Err(_)));
+        if timed_out {
+            warn!("The tracker did not answer our stopped announce in time");
+            return true;
+        }
+        loop {
+            let fired = match self.announce_timer.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(()))) => true,
+                Some(Err(e)) => {
+                    error!("Announce timer failed: {}", e);
+                    true
+                }
+                _ => false,
+            };
+            if fired {
+                self.reannounce();
+            }
+
+            let result = match self.tracker.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(resp))) => Ok(resp),
+                Some(Err(e)) => Err(e),
+                _ => return false,
+            };
+            if self.stopping {
+                trace!("Tracker answered our stopped announce: {:?}", result);
+                return true;
+            }
+            self.handle_announce(result);
+            if self.completed && !self.config.seed {
+                // Nothing left to do, so say goodbye
+                self.stop();
+            }
+        }
+    }
+
+    /// Tells the tracker we are leaving.  We finish once it answers, or gives up trying
+    fn stop(&mut self) {
+        if self.stopping {
+            return;
+        }
+        let (uploaded, downloaded) = self.session_stats();
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.cancel(self.left, uploaded, downloaded);
+            self.announce_sent();
+        }
+        self.stopping = true;
+        // A tracker that isn't there would otherwise keep us waiting through every retry
+        self.stop_timer = Some(Delay::new(Instant::now() + STOP_TIMEOUT));
+    }
+
+    /// Updates the swarm's piece availability and the peer's state from a peer event
+    fn handle_event(&mut self, address: SocketAddr, event: PeerEvent) {
+        let handle = match self.peers.get_mut(&address) {
+            Some(handle) => handle,
+            None => return,
+        };
+        match event {
+            PeerEvent::Handshake(peer_id) => {
+                // A client we are already connected to, most often one that dialed us while we
+                // dialed it.  The connection we have keeps going
+                if self.peers.values().any(|other| other.peer_id == Some(peer_id)) {
+                    debug!("Already connected to the peer at {}, closing the connection", address);
+                    self.remove_peer(&address);
+                } else if let Some(handle) = self.peers.get_mut(&address) {
+                    handle.peer_id = Some(peer_id);
+                }
+                return;
+            }
+            PeerEvent::Bitfield(bitfield) => {
+                self.picker.remove_peer(&handle.bitfield);
+                self.picker.add_peer(&bitfield);
+                handle.bitfield = bitfield;
+            }
+            PeerEvent::Have(index) => {
+                if !handle.bitfield.get(index as usize).unwrap_or(true) {
+                    handle.bitfield.set(index as usize, true);
+                    self.picker.peer_have(index);
+                }
+            }
+            PeerEvent::Choked => {
+                handle.unchoked = false;
+                // Someone else may get the pieces done while this peer refuses us
+                if handle.release_pieces(&mut self.picker) {
+                    let addresses: Vec<SocketAddr> = self.peers.keys().cloned().collect();
+                    for address in addresses {
+                        self.assign_pieces(&address);
+                    }
+                }
+                return;
+            }
+            PeerEvent::Unchoked => handle.unchoked = true,
+            PeerEvent::Interested => handle.interested = true,
+            PeerEvent::NotInterested => handle.interested = false,
+            PeerEvent::AllowedFast(index) => {
+                handle.allowed_fast.insert(index);
+            }
+            PeerEvent::Block(index, begin, block) => {
+                // Every block is saved for resuming, but only in endgame is anyone else
+                // downloading the same piece
+                self.store_block(index, begin, &block);
+                if self.endgame {
+                    share_block(&self.peers, &address, index, begin, &block);
+                }
+                return;
+            }
+            PeerEvent::ListenPort(port) => {
+                handle.listen_address = Some(SocketAddr::new(address.ip(), port));
+                return;
+            }
+            PeerEvent::Peers(peers) => {
+                trace!("{} told us about {} peers", address, peers.len());
+                self.add_candidates(peers);
+                self.connect_to_candidates();
+                return;
+            }
+        }
+        self.assign_pieces(&address);
+    }
+
+    /// Gives a peer enough pieces to keep its request pipeline full, out of the pieces it is
+    /// letting us download
+    fn assign_pieces(&mut self, address: &SocketAddr) {
+        let handle = match self.peers.get_mut(address) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let available = if handle.unchoked {
+            handle.bitfield.clone()
+        } else {
+            // A choking peer still serves the pieces it allowed us with the Fast extension
+            let mut allowed = BitVec::from_elem(handle.bitfield.len(), false);
+            for index in &handle.allowed_fast {
+                if handle.bitfield.get(*index as usize).unwrap_or(false) {
+                    allowed.set(*index as usize, true);
+                }
+            }
+            allowed
+        };
+        if available.none() {
+            return;
+        }
+        // One more piece than the pipeline can hold, so it does not drain at the end of a piece
+        let blocks_per_piece = usize::max(1, self.info.piece_length.div_ceil(BLOCK_SIZE as usize));
+        let wanted = self.config.max_requests / blocks_per_piece + 1;
+        let mut entered_endgame = false;
+        while handle.assigned.len() < wanted {
+            let index = match self.picker.pick(&available) {
+                Some(index) => index,
+                None if self.picker.in_endgame() => {
+                    entered_endgame = true;
+                    match self.picker.pick_endgame(&available, &handle.assigned) {
+                        Some(index) => index,
+                        None => break,
+                    }
+                }
+                None => break,
+            };
+            let hash = self.info.piece_hash(index as usize).expect("Missing piece hash");
+            let size = self.info.piece_size(index as usize) as u32;
+            // Pick up where an earlier download of the piece left off
+            let piece = match self.partial.get(&index) {
+                Some(blocks) => match self.storage.read_block(index, 0, size) {
+                    Ok(data) => Piece::from_blocks(index, data, hash, blocks),
+                    Err(_) => Piece::new(index, size, hash),
+                },
+                None => Piece::new(index, size, hash),
+            };
+            if handle.pieces.try_send(piece).is_err() {
+                self.picker.abandon(index);
+                break;
+            }
+            trace!("Assigned piece {} to {}", index, address);
+            handle.assigned.insert(index);
+        }
+        if entered_endgame && !self.endgame {
+            debug!("Entering endgame");
+            self.endgame = true;
+        }
+    }
+
+    /// Writes a block of an unfinished piece to disk, so it is not lost if we stop before the
+    /// piece is done
+    fn store_block(&mut self, index: u32, begin: u32, block: &[u8]) {
+        if self.picker.have().get(index as usize).unwrap_or(true) {
+            return;
+        }
+        if let Err(e) = self.storage.write_block(index, begin, block) {
+            error!("Failed to write block of piece {}: {}", index, e);
+            return;
+        }
+        let num_blocks = blocks_in(self.info.piece_size(index as usize) as u32);
+        self.partial.entry(index)
+            .or_insert_with(|| BitVec::from_elem(num_blocks, false))
+            .set((begin / BLOCK_SIZE) as usize, true);
+    }
+
+    /// Records our progress so the next run can skip rechecking
+    fn save_resume(&self) {
+        let files = match self.storage.stamps() {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Failed to save resume file: {}", e);
+                return;
+            }
+        };
+        let resume = Resume {
+            have: self.picker.have().clone(),
+            files,
+            partial: self.partial.clone(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+        };
+        if let Err(e) = resume.save(&self.resume_path) {
+            warn!("Failed to save resume file: {}", e);
+        }
+    }
+
+    /// Verifies and writes a finished piece, then lets every peer know we have it.  A piece that
+    /// fails goes back to the picker to be downloaded again
+    fn write_piece(&mut self, piece: &mut Piece) {
+        let index = piece.index();
+        // Either way, the blocks on disk are no longer a partial piece worth resuming
+        self.partial.remove(&index);
+        match self.storage.write_piece(piece) {
+            Ok(()) => {
+                debug!("Wrote piece {}", index);
+                self.left -= piece.data().len() as u64;
+                self.picker.complete(index);
+                self.broadcast(|| PeerCommand::Have(index));
+                // Anyone else downloading it in endgame drops it when they get the Have
+                let mut freed = Vec::new();
+                for (address, handle) in self.peers.iter_mut() {
+                    if handle.assigned.remove(&index) {
+                        freed.push(*address);
+                    }
+                }
+                for address in freed {
+                    self.assign_pieces(&address);
+                }
+            }
+            Err(e) => {
+                match e {
+                    StorageError::HashMismatch(_) => warn!("Piece {} failed verification", index),
+                    e => error!("Failed to write piece {}: {}", index, e),
+                }
+                self.picker.abandon(index);
+            }
+        }
+    }
+
+    /// Runs a round of the choking algorithm.  Peers are ranked by how fast they send to us, or
+    /// once we are seeding, by how fast they take from us
+    fn rechoke(&mut self) {
+        let seeding = self.picker.have().all();
+        let stats: Vec<PeerStats> = self.peers.iter()
+            .map(|(address, handle)| PeerStats {
+                address: *address,
+                interested: handle.interested,
+                rate: if seeding { handle.uploaded } else { handle.downloaded },
+            })
+            .collect();
+        let unchoke = self.choker.rechoke(&stats);
+        for (address, handle) in self.peers.iter_mut() {
+            handle.downloaded = 0;
+            handle.uploaded = 0;
+            let should_unchoke = unchoke.contains(address);
+            if should_unchoke != handle.am_unchoking {
+                trace!("{} {}", if should_unchoke { "Unchoking" } else { "Choking" }, address);
+                handle.am_unchoking = should_unchoke;
+                let _ = handle.commands.unbounded_send(if should_unchoke {
+                    PeerCommand::Unchoke
+                } else {
+                    PeerCommand::Choke
+                });
+            }
+        }
+    }
+
+    /// Sends a command to every connected peer
+    fn broadcast<F: Fn() -> PeerCommand>(&self, command: F) {
+        for handle in self.peers.values() {
+            // A failed send means the peer is shutting down, and will be removed shortly
+            let _ = handle.commands.unbounded_send(command());
+        }
+    }
+
+    /// Tells every peer about the others that accept connections, so ut_pex can pass them on
+    fn share_peers(&self) {
+        let swarm: Vec<(SocketAddr, SwarmPeer)> = self.peers.iter()
+            .filter_map(|(address, handle)| handle.listen_address.map(|listen_address| (*address, SwarmPeer {
+                address: listen_address,
+                seed: handle.bitfield.all(),
+                connectable: handle.initiated,
+            })))
+            .collect();
+        for (address, handle) in self.peers.iter() {
+            let others = swarm.iter()
+                .filter(|(other, _)| other != address)
+                .map(|(_, peer)| peer.clone())
+                .collect();
+            let _ = handle.commands.unbounded_send(PeerCommand::Swarm(others));
+        }
+    }
+
+    /// Remembers peers to connect to once there is room, skipping ones we already know of, are
+    /// avoiding, or that are us
+    fn add_candidates<I: IntoIterator<Item=SocketAddr>>(&mut self, addresses: I) {
+        let now = Instant::now();
+        for address in addresses {
+            if self.candidates.len() >= MAX_CANDIDATES {
+                trace!("Too many candidate peers, forgetting the rest");
+                break;
+            }
+            if self.banned.contains(&address, now) || is_own_address(&address, &self.local_ips) {
+                trace!("Skipping peer {}", address);
+                continue;
+            }
+            if !self.peers.contains_key(&address) && !self.candidates.contains(&address) {
+                self.candidates.push_back(address);
+            }
+        }
+    }
+
+    /// Keeps us from dialing a peer whose task failed again for a while, whether we couldn't reach
+    /// it, it broke the protocol, or it was us
+    fn ban_peer(&mut self, address: SocketAddr) {
+        debug!("Not connecting to peer {} again for a while", address);
+        let now = Instant::now();
+        if let Some(listen_address) = self.peers.get(&address).and_then(|handle| handle.listen_address) {
+            self.banned.insert(listen_address, now);
+        }
+        self.banned.insert(address, now);
+    }
+
+    /// Dials candidate peers until the connection limit is reached
+    fn connect_to_candidates(&mut self) {
+        while self.peers.len() < self.config.max_connections {
+            let address = match self.candidates.pop_front() {
+                Some(address) => address,
+                None => return,
+            };
+            // It may have connected to us, or been banned, while it was waiting
+            if self.peers.contains_key(&address) || self.banned.contains(&address, Instant::now()) {
+                continue;
+            }
+            debug!("Connecting to peer {}", address);
+            let connection = TcpStream::connect(&address)
+                .map_err(move |e| debug!("Failed to connect to peer {}: {}", address, e));
+            self.start_peer(address, connection, true);
+        }
+    }
+
+    /// Dials the peers from a tracker response, and keeps the rest for when there is room
+    fn connect_to_peers(&mut self, peers: &[PeerInfo]) {
+        let peer_id = self.peer_id;
+        self.add_candidates(peers.iter()
+            .filter(|peer_info| peer_info.peer_id != Some(peer_id))
+            .map(|peer_info| peer_info.address));
+        self.connect_to_candidates();
+    }
+}
+
+/// Listens on both IPv4 and IPv6.  Only fails if neither can be bound, since plenty of hosts only
+/// have one of the two
+fn listen(port: u16) -> io::Result<Box<dyn Stream<Item=TcpStream, Error=io::Error> + Send>> {
+    let v4 = TcpBuilder::new_v4()
+        .and_then(|builder| builder.reuse_address(true)?
+            .bind((Ipv4Addr::UNSPECIFIED, port))?
+            .listen(1024));
+    // v6 only, otherwise it may claim the IPv4 port as well and the two collide
+    let v6 = TcpBuilder::new_v6()
+        .and_then(|builder| builder.only_v6(true)?
+            .reuse_address(true)?
+            .bind((Ipv6Addr::UNSPECIFIED, port))?
+            .listen(1024));
+    let incoming = |listener| TcpListener::from_std(listener, &Handle::default()).map(TcpListener::incoming);
+    match (v4, v6) {
+        (Ok(v4), Ok(v6)) => Ok(Box::new(incoming(v4)?.select(incoming(v6)?))),
+        (Ok(v4), Err(e)) => {
+            warn!("Failed to listen on IPv6: {}", e);
+            Ok(Box::new(incoming(v4)?))
         }
+        (Err(e), Ok(v6)) => {
+            warn!("Failed to listen on IPv4: {}", e);
+            Ok(Box::new(incoming(v6)?))
+        }
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+/// Finds the IPv4 address we connect out from, the same way as local_ipv6
+fn local_ipv4() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Whether dialing the address would reach our own listener
+fn is_own_address(address: &SocketAddr, local_ips: &[IpAddr]) -> bool {
+    let ip = address.ip();
+    address.port() == PORT && (ip.is_loopback() || ip.is_unspecified() || local_ips.contains(&ip))
+}
+
+/// Finds the IPv6 address other peers could reach us on, if we have one.  Connecting a UDP socket
+/// doesn't send anything, it just makes the OS pick the address it would route from
+fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(address) if !address.is_loopback() && !address.is_unspecified()
+            // link local addresses are useless to anyone not on our network segment
+            && address.segments()[0] & 0xffc0 != 0xfe80 => Some(address),
+        _ => None,
     }
 }
 
@@ -84,52 +1035,31 @@ impl Future for Server {
     type Error = ();
 
     /// This is the main event loop for the client.  It returns Ok(Ready(())) Only when the download
-    /// is complete.
+    /// is complete and the tracker knows we are leaving, unless we are seeding.
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
         trace!("Start Loop");
-        // check on the tracker response
-        match self.tracker.poll() {
-            Err(e) => {
-                error!("Something went wrong in making a request to the tracker: {:?}", e);
-                return Err(());
-            }
-            Ok(Async::Ready(TrackerResponse::Failure(msg))) => error!("The tracker responded with an error: {}", msg),
-            Ok(Async::Ready(TrackerResponse::Warning(msg, resp))) => {
-                warn!("The tracker responeded with a warning: {}", msg);
-                // do something with the response
-                trace!("tracker response: {:?}", resp)
+        // forget about peers whose tasks have ended
+        while let Ok(Async::Ready(Some((address, failed)))) = self.disconnected_receiver.poll() {
+            trace!("Peer {} disconnected", address);
+            if failed {
+                self.ban_peer(address);
             }
-            Ok(Async::Ready(TrackerResponse::Success(resp))) => {
-                // do something with the response
-                trace!("tracker response: {:?}", resp)
-            }
-            _ => () // not ready
-        };
+            self.remove_peer(&address);
+        }
+
         // poll for new connections, spin up new peer tasks
         loop {
             match self.listener.poll() {
                 Ok(Async::Ready(Some(conn))) => {
-                    let (up_sender, up_receiver) = channel(10);
-                    let (down_sender, down_receiver) = channel(10);
-                    let (piece_sender, piece_receiver) = channel(10);
-
-                    replace_with(&mut self.uploaded_stream,
-                                 /* default, in case replacement panics */ || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(up_receiver)));
-                    replace_with(&mut self.downloaded_stream,
-                                 || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(down_receiver)));
-                    replace_with(&mut self.piece_stream,
-                                 || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(piece_receiver)));
-                    let peer = Peer::new(conn,
-                                         up_sender,
-                                         down_sender,
-                                         piece_sender,
-                                         self.info_hash.clone(),
-                                         self.peer_id.clone(),
-                                         false);
-                    spawn(peer);
+                    let address = match conn.peer_addr() {
+                        Ok(address) => address,
+                        Err(_) => continue,
+                    };
+                    if self.peers.len() >= self.config.max_connections || self.peers.contains_key(&address) {
+                        trace!("Refusing connection from {}", address);
+                        continue;
+                    }
+                    self.start_peer(address, future::ok(conn), false);
                 }
                 Err(e) => {
                     error!("TCP Listener closed unexpectedly with error: {}", e);
@@ -140,33 +1070,115 @@ impl Future for Server {
         }
 
         // get uploaded/downloaded statistic updates
-        loop {
-            match self.uploaded_stream.poll() {
-                Ok(Async::Ready(Some(update))) => self.uploaded += update as u64,
-                _ => break,
+        while let Ok(Async::Ready(Some((address, update)))) = self.uploaded_stream.poll() {
+            self.uploaded += update as u64;
+            if let Some(handle) = self.peers.get_mut(&address) {
+                handle.uploaded += update as u64;
             }
         }
-        loop {
-            match self.downloaded_stream.poll() {
-                Ok(Async::Ready(Some(update))) => self.downloaded += update as u64,
-                _ => break,
+        while let Ok(Async::Ready(Some((address, update)))) = self.downloaded_stream.poll() {
+            self.downloaded += update as u64;
+            if let Some(handle) = self.peers.get_mut(&address) {
+                handle.downloaded += update as u64;
             }
         }
 
+        // Keep track of what each peer has
+        while let Ok(Async::Ready(Some((address, event)))) = self.event_stream.poll() {
+            self.handle_event(address, event);
+        }
+
         // Get finished pieces and request new pieces
+        while let Ok(Async::Ready(Some((address, mut finished_piece)))) = self.piece_stream.poll() {
+            let index = finished_piece.index();
+            if let Some(handle) = self.peers.get_mut(&address) {
+                handle.assigned.remove(&index);
+            }
+            // During endgame another peer may have finished it first
+            if !self.picker.have().get(index as usize).unwrap_or(true) {
+                self.write_piece(&mut finished_piece);
+            }
+            self.assign_pieces(&address);
+        }
+
+        // Decide who we upload to
         loop {
-            match self.piece_stream.poll() {
-                Ok(Async::Ready(Some((_finished_piece, _new_piece_sender, _availible_pieces)))) => {
-                    // TODO verify and write off the finished piece and either kill the peer or give
-                    // them a new piece
+            match self.choke_timer.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    self.rechoke();
+                    self.save_resume();
                 }
-                _ => break
+                Err(e) => {
+                    error!("Choke timer failed: {}", e);
+                    break;
+                }
+                _ => break,
             }
         }
 
-        // This future only finishes normally when the download is complete
-        if self.left == 0 {
+        // Tell peers about each other
+        if !self.info.private {
+            loop {
+                match self.pex_timer.poll() {
+                    Ok(Async::Ready(Some(_))) => self.share_peers(),
+                    Err(e) => {
+                        error!("Peer exchange timer failed: {}", e);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        // Look for peers in the DHT.  Private torrents only get their peers from the tracker
+        if let (false, Some(dht)) = (self.info.private, &self.config.dht) {
+            loop {
+                match self.dht_timer.poll() {
+                    Ok(Async::Ready(Some(_))) => {
+                        debug!("Looking for peers in the DHT");
+                        self.dht_peers = Some(dht.get_peers(self.info_hash, Some(PORT)));
+                    }
+                    Err(e) => {
+                        error!("DHT timer failed: {}", e);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        let mut found = Vec::new();
+        while let Some(dht_peers) = &mut self.dht_peers {
+            match dht_peers.poll() {
+                Ok(Async::Ready(Some(address))) => found.push(address),
+                Ok(Async::Ready(None)) | Err(_) => self.dht_peers = None,
+                Ok(Async::NotReady) => break,
+            }
+        }
+        if !found.is_empty() {
+            trace!("The DHT found {} peers", found.len());
+            self.add_candidates(found);
+            self.connect_to_candidates();
+        }
+
+        let shutdown = match self.config.shutdown.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(())) => {
+                // Nothing can ask us to shut down any more
+                self.config.shutdown = None;
+                false
+            }
+            _ => false,
+        };
+        if shutdown {
+            info!("Shutting down");
+            self.config.shutdown = None;
+            self.stop();
+        }
+
+        // Last, so announces started by anything above get polled
+        if self.poll_tracker() {
             trace!("Finished");
+            self.save_resume();
             Ok(Async::Ready(()))
         } else {
             trace!("Did a loop");
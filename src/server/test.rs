@@ -0,0 +1,101 @@
+use maplit::hashset;
+use super::*;
+
+fn peer_handle(bitfield: BitVec) -> (PeerHandle, UnboundedReceiver<PeerCommand>) {
+    let (commands, command_receiver) = unbounded();
+    let (pieces, _) = channel(10);
+    let handle = PeerHandle {
+        commands,
+        pieces,
+        bitfield,
+        assigned: HashSet::new(),
+        unchoked: true,
+        allowed_fast: HashSet::new(),
+        interested: false,
+        am_unchoking: false,
+        downloaded: 0,
+        uploaded: 0,
+        listen_address: None,
+        initiated: true,
+        peer_id: None,
+    };
+    (handle, command_receiver)
+}
+
+#[test]
+fn test_choking_peer_releases_pieces() {
+    let all = BitVec::from_elem(2, true);
+    let mut picker = PiecePicker::new(BitVec::from_elem(2, false));
+    picker.add_peer(&all);
+    picker.add_peer(&all);
+    let (mut choking, commands) = peer_handle(all.clone());
+    while let Some(index) = picker.pick(&choking.bitfield) {
+        choking.assigned.insert(index);
+    }
+    choking.allowed_fast.insert(1);
+    // the other peer has nothing to do while the first holds every piece
+    assert_eq!(picker.pick(&all), None);
+
+    // once it chokes us, the piece it won't send while choked goes to the other peer
+    assert!(choking.release_pieces(&mut picker));
+    assert_eq!(choking.assigned, hashset! { 1 });
+    assert_eq!(picker.pick(&all), Some(0));
+    match commands.wait().next() {
+        Some(Ok(PeerCommand::Release(0))) => (),
+        _ => panic!("expected the peer to be told to stop downloading piece 0"),
+    }
+    assert!(!choking.release_pieces(&mut picker));
+}
+
+#[test]
+fn test_banned_expires() {
+    let address: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let now = Instant::now();
+    let mut banned = Banned::new();
+    banned.insert(address, now);
+    assert!(banned.contains(&address, now));
+    assert!(!banned.contains(&other, now));
+    assert!(!banned.contains(&address, now + BAN_DURATION));
+
+    // expired bans are forgotten as new ones come in
+    banned.insert(other, now + BAN_DURATION);
+    assert!(!banned.until.contains_key(&address));
+}
+
+#[test]
+fn test_is_own_address() {
+    let local_ips = vec!["192.168.1.5".parse().unwrap()];
+    assert!(is_own_address(&SocketAddr::from(([127, 0, 0, 1], PORT)), &local_ips));
+    assert!(is_own_address(&SocketAddr::from(([0, 0, 0, 0], PORT)), &local_ips));
+    assert!(is_own_address(&SocketAddr::from(([192, 168, 1, 5], PORT)), &local_ips));
+    assert!(!is_own_address(&SocketAddr::from(([192, 168, 1, 5], PORT + 1)), &local_ips));
+    assert!(!is_own_address(&SocketAddr::from(([192, 168, 1, 6], PORT)), &local_ips));
+}
+
+#[test]
+fn test_share_block() {
+    let from: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let idle: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+    let mut peers = HashMap::new();
+    let mut receivers = HashMap::new();
+    for &address in &[from, other, idle] {
+        let (mut handle, commands) = peer_handle(BitVec::from_elem(1, true));
+        if address != idle {
+            handle.assigned.insert(0);
+        }
+        peers.insert(address, handle);
+        receivers.insert(address, commands);
+    }
+
+    share_block(&peers, &from, 0, BLOCK_SIZE, &Bytes::from(vec![7; 10]));
+    // only the other peer downloading the piece hears about it, not the sender or the idle peer
+    drop(peers);
+    match receivers.remove(&other).unwrap().wait().collect::<Vec<_>>().as_slice() {
+        [Ok(PeerCommand::Block(0, BLOCK_SIZE, block))] => assert_eq!(block, &vec![7; 10]),
+        _ => panic!("expected the block to be passed on"),
+    }
+    assert_eq!(receivers.remove(&from).unwrap().wait().count(), 0);
+    assert_eq!(receivers.remove(&idle).unwrap().wait().count(), 0);
+}
@@ -0,0 +1,300 @@
+//! storage maps pieces onto the files described by the metainfo and reads/writes them on disk
+use crate::metainfo::{FileInfo, InfoDict};
+use crate::piece::Piece;
+use bit_vec::BitVec;
+use std::error::Error;
+use std::fmt;
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+#[cfg(test)]
+mod test;
+
+/// A file on disk, and where it lives in the torrent's contiguous byte stream
+#[derive(Debug, PartialEq, Clone)]
+struct StorageFile {
+    // Location of the file on disk
+    path: PathBuf,
+    // Offset of the first byte of this file in the torrent
+    offset: u64,
+    // Size of the file in bytes
+    length: u64,
+    // Whether the file was already on disk when the storage was opened.  Fresh files can't hold
+    // any data worth checking
+    existed: bool,
+}
+
+/// The size and modification time of a file, used to tell whether it changed between runs
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileStamp {
+    pub length: u64,
+    // Nanoseconds since the unix epoch
+    pub modified: u64,
+}
+
+/// A section of a single file that a range of the torrent's bytes falls into
+#[derive(Debug, PartialEq)]
+struct Span {
+    // Index into Storage::files
+    file: usize,
+    // Offset into the file
+    file_offset: u64,
+    // Number of bytes in this span
+    length: usize,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// The piece did not match its hash
+    HashMismatch(u32),
+    /// The piece index or byte range does not exist in this torrent
+    OutOfBounds(u32),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "Reading or writing a file failed: {}", e),
+            StorageError::HashMismatch(index) => write!(f, "Piece {} did not match its hash", index),
+            StorageError::OutOfBounds(index) => write!(f, "Piece {} is out of bounds", index),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// The on disk layout of a torrent
+pub struct Storage {
+    files: Vec<StorageFile>,
+    // Each file's handle, opened once and kept for as long as the storage, in the same order as
+    // files
+    handles: Vec<Mutex<Option<File>>>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl Storage {
+    /// Lays out the torrent's files under base_dir, creating any missing directories and files.
+    /// Existing files are left alone so their data can be reused
+    pub fn new(base_dir: &Path, info: &InfoDict) -> io::Result<Self> {
+        let mut storage = Storage::layout(base_dir, info);
+        for (file, handle) in storage.files.iter_mut().zip(&storage.handles) {
+            file.existed = file.path.is_file();
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Never truncate, the data already there is what recheck and resume build on
+            let f = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file.path)?;
+            if f.metadata()?.len() != file.length {
+                f.set_len(file.length)?;
+            }
+            *handle.lock().unwrap() = Some(f);
+        }
+        Ok(storage)
+    }
+
+    /// Computes where each file lives without touching the disk
+    fn layout(base_dir: &Path, info: &InfoDict) -> Self {
+        let mut files = Vec::new();
+        let mut offset = 0;
+        match &info.file_info {
+            FileInfo::Single(file) => {
+                files.push(StorageFile {
+                    path: base_dir.join(&file.file_name),
+                    offset,
+                    length: file.length as u64,
+                    existed: false,
+                });
+                offset += file.length as u64;
+            }
+            FileInfo::Multi(multi) => {
+                let root = base_dir.join(&multi.root_dir_name);
+                for file in &multi.files {
+                    let path = file.file_name.split('/').fold(root.clone(), |p, c| p.join(c));
+                    files.push(StorageFile {
+                        path,
+                        offset,
+                        length: file.length as u64,
+                        existed: false,
+                    });
+                    offset += file.length as u64;
+                }
+            }
+        }
+
+        Storage {
+            handles: files.iter().map(|_| Mutex::new(None)).collect(),
+            files,
+            piece_length: info.piece_length as u64,
+            total_length: offset,
+        }
+    }
+
+    /// Hashes whatever data is already on disk, returning which pieces are complete
+    pub fn recheck(&self, info: &InfoDict) -> BitVec {
+        let mut have = BitVec::from_elem(info.num_pieces(), false);
+        for index in 0..info.num_pieces() {
+            let offset = index as u64 * self.piece_length;
+            let size = info.piece_size(index);
+            let worth_checking = match self.spans(offset, size as u64) {
+                Ok(spans) => spans.iter().any(|span| self.files[span.file].existed),
+                Err(_) => false,
+            };
+            if !worth_checking {
+                continue;
+            }
+            let hash = match info.piece_hash(index) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if let Ok(data) = self.read(offset, size) {
+                have.set(index, Piece::from_data(index as u32, data, hash).verify());
+            }
+        }
+        have
+    }
+
+    /// Verifies a finished piece, and writes it to disk if it is correct
+    pub fn write_piece(&self, piece: &mut Piece) -> Result<(), StorageError> {
+        if !piece.verify() {
+            return Err(StorageError::HashMismatch(piece.index()));
+        }
+        let offset = piece.index() as u64 * self.piece_length;
+        self.write(offset, piece.data())
+            .map_err(|e| map_bounds(e, piece.index()))
+    }
+
+    /// Writes a block of a piece that has not been verified yet, so it survives a restart
+    pub fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        if begin as u64 + data.len() as u64 > self.piece_length {
+            return Err(StorageError::OutOfBounds(index));
+        }
+        let offset = index as u64 * self.piece_length + begin as u64;
+        self.write(offset, data)
+            .map_err(|e| map_bounds(e, index))
+    }
+
+    /// Reads length bytes of a piece starting at begin
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        if begin as u64 + length as u64 > self.piece_length {
+            return Err(StorageError::OutOfBounds(index));
+        }
+        let offset = index as u64 * self.piece_length + begin as u64;
+        self.read(offset, length as usize)
+            .map_err(|e| map_bounds(e, index))
+    }
+
+    /// The current size and modification time of every file, in torrent order
+    pub fn stamps(&self) -> io::Result<Vec<FileStamp>> {
+        self.files.iter()
+            .map(|file| {
+                let metadata = fs::metadata(&file.path)?;
+                let modified = metadata.modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(FileStamp {
+                    length: metadata.len(),
+                    modified: modified.as_secs() * 1_000_000_000 + modified.subsec_nanos() as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// Writes bytes into the torrent starting at offset, spanning files as necessary
+    fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        for span in self.spans(offset, data.len() as u64)? {
+            self.with_file(span.file, |f| {
+                f.seek(SeekFrom::Start(span.file_offset))?;
+                f.write_all(&data[written..written + span.length])
+            })?;
+            written += span.length;
+        }
+        Ok(())
+    }
+
+    /// Reads length bytes of the torrent starting at offset, spanning files as necessary
+    fn read(&self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        let mut read = 0;
+        for span in self.spans(offset, length as u64)? {
+            self.with_file(span.file, |f| {
+                f.seek(SeekFrom::Start(span.file_offset))?;
+                f.read_exact(&mut data[read..read + span.length])
+            })?;
+            read += span.length;
+        }
+        Ok(data)
+    }
+
+    /// Runs op on the handle of the file at index, opening it first if this is its first use
+    fn with_file<T>(&self, index: usize, op: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+        let mut handle = self.handles[index].lock().unwrap();
+        if handle.is_none() {
+            *handle = Some(OpenOptions::new().read(true).write(true).open(&self.files[index].path)?);
+        }
+        op(handle.as_mut().expect("file was opened above"))
+    }
+
+    /// Splits a range of the torrent into the sections of each file it covers
+    fn spans(&self, offset: u64, length: u64) -> io::Result<Vec<Span>> {
+        if offset + length > self.total_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "range is past the end of the torrent"));
+        }
+        let end = offset + length;
+        Ok(self.files.iter()
+            .enumerate()
+            // zero length files never hold any data
+            .filter(|(_, f)| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|(i, f)| {
+                let start = u64::max(offset, f.offset);
+                let stop = u64::min(end, f.offset + f.length);
+                Span {
+                    file: i,
+                    file_offset: start - f.offset,
+                    length: (stop - start) as usize,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Range errors from spans become OutOfBounds, everything else is a real io error
+fn map_bounds(e: io::Error, index: u32) -> StorageError {
+    if e.kind() == io::ErrorKind::InvalidInput {
+        StorageError::OutOfBounds(index)
+    } else {
+        StorageError::Io(e)
+    }
+}
@@ -0,0 +1,145 @@
+use crate::metainfo::{sha1_hash, MultiFile, SingleFile};
+use crate::testutil::temp_dir;
+use super::*;
+
+fn multi_info() -> InfoDict {
+    InfoDict {
+        piece_length: 8,
+        pieces: vec![String::new(); 3],
+        private: false,
+        file_info: FileInfo::Multi(MultiFile {
+            root_dir_name: "root".to_string(),
+            files: vec![
+                SingleFile { file_name: "a".to_string(), length: 5, md5sum: None },
+                SingleFile { file_name: "empty".to_string(), length: 0, md5sum: None },
+                SingleFile { file_name: "dir/b".to_string(), length: 7, md5sum: None },
+                SingleFile { file_name: "dir/c".to_string(), length: 8, md5sum: None },
+            ],
+        }),
+    }
+}
+
+#[test]
+fn test_spans_cross_file_boundaries() {
+    let storage = Storage::layout(Path::new("/"), &multi_info());
+
+    // piece 0 covers all of a and the first 3 bytes of b, skipping the empty file
+    assert_eq!(storage.spans(0, 8).unwrap(), vec![
+        Span { file: 0, file_offset: 0, length: 5 },
+        Span { file: 2, file_offset: 0, length: 3 },
+    ]);
+    // piece 1 covers the rest of b and the first 4 bytes of c
+    assert_eq!(storage.spans(8, 8).unwrap(), vec![
+        Span { file: 2, file_offset: 3, length: 4 },
+        Span { file: 3, file_offset: 0, length: 4 },
+    ]);
+    // the last piece is short
+    assert_eq!(storage.spans(16, 4).unwrap(), vec![
+        Span { file: 3, file_offset: 4, length: 4 },
+    ]);
+    assert!(storage.spans(16, 8).is_err());
+}
+
+#[test]
+fn test_new_creates_layout() {
+    let dir = temp_dir("storage");
+    Storage::new(&dir, &multi_info()).unwrap();
+
+    assert_eq!(fs::metadata(dir.join("root/a")).unwrap().len(), 5);
+    assert_eq!(fs::metadata(dir.join("root/empty")).unwrap().len(), 0);
+    assert_eq!(fs::metadata(dir.join("root/dir/b")).unwrap().len(), 7);
+    assert_eq!(fs::metadata(dir.join("root/dir/c")).unwrap().len(), 8);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_write_piece() {
+    let dir = temp_dir("storage");
+    let storage = Storage::new(&dir, &multi_info()).unwrap();
+
+    let data: Vec<u8> = (0..8).collect();
+    let mut piece = Piece::from_data(1, data.clone(), sha1_hash(&data));
+    storage.write_piece(&mut piece).unwrap();
+
+    assert_eq!(fs::read(dir.join("root/dir/b")).unwrap(), vec![0, 0, 0, 0, 1, 2, 3]);
+    assert_eq!(fs::read(dir.join("root/dir/c")).unwrap(), vec![4, 5, 6, 7, 0, 0, 0, 0]);
+    assert_eq!(storage.read_block(1, 2, 4).unwrap(), vec![2, 3, 4, 5]);
+
+    // pieces that fail verification are never written
+    let mut bad = Piece::from_data(0, vec![9; 8], sha1_hash(&data));
+    match storage.write_piece(&mut bad) {
+        Err(StorageError::HashMismatch(0)) => (),
+        other => panic!("expected a hash mismatch, got {:?}", other),
+    }
+    assert_eq!(fs::read(dir.join("root/a")).unwrap(), vec![0; 5]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_single_file() {
+    let dir = temp_dir("storage");
+    let info = InfoDict {
+        piece_length: 4,
+        pieces: vec![String::new(); 2],
+        private: false,
+        file_info: FileInfo::Single(SingleFile { file_name: "file".to_string(), length: 6, md5sum: None }),
+    };
+    let storage = Storage::new(&dir, &info).unwrap();
+
+    let mut piece = Piece::from_data(1, vec![1, 2], sha1_hash(&[1, 2]));
+    storage.write_piece(&mut piece).unwrap();
+    assert_eq!(fs::read(dir.join("file")).unwrap(), vec![0, 0, 0, 0, 1, 2]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_recheck() {
+    let dir = temp_dir("storage");
+    let first: Vec<u8> = (0..8).collect();
+    let second: Vec<u8> = (8..16).collect();
+    let last: Vec<u8> = vec![1, 2, 3, 4];
+    let mut info = multi_info();
+    info.pieces = [&first, &second, &last].iter()
+        .map(|data| sha1_hash(data).iter().map(|b| format!("{:02x}", b)).collect())
+        .collect();
+
+    // nothing is checked for a fresh download
+    let storage = Storage::new(&dir, &info).unwrap();
+    assert_eq!(storage.recheck(&info), BitVec::from_elem(3, false));
+    storage.write_piece(&mut Piece::from_data(0, first.clone(), sha1_hash(&first))).unwrap();
+    storage.write_piece(&mut Piece::from_data(2, last.clone(), sha1_hash(&last))).unwrap();
+
+    // reopening finds the pieces that were written
+    let storage = Storage::new(&dir, &info).unwrap();
+    assert_eq!(storage.recheck(&info), BitVec::from_bytes(&[0b1010_0000]).iter().take(3).collect());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_keeps_files_open() {
+    let dir = temp_dir("storage");
+    let info = InfoDict {
+        piece_length: 4,
+        pieces: vec![String::new()],
+        private: false,
+        file_info: FileInfo::Single(SingleFile { file_name: "file".to_string(), length: 4, md5sum: None }),
+    };
+    let storage = Storage::new(&dir, &info).unwrap();
+
+    // the handle opened with the storage still reads and writes once the path is gone
+    fs::remove_file(dir.join("file")).unwrap();
+    storage.write_block(0, 0, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(storage.read_block(0, 1, 2).unwrap(), vec![2, 3]);
+
+    // a file that can't be opened reports why
+    let storage = Storage::layout(&dir, &info);
+    let e = storage.read_block(0, 0, 4).unwrap_err();
+    assert!(e.source().is_some());
+    assert!(e.to_string().starts_with("Reading or writing a file failed: "));
+
+    fs::remove_dir_all(dir).unwrap();
+}
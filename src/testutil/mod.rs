@@ -0,0 +1,12 @@
+//! testutil holds helpers shared by the tests of several modules
+use rand::prelude::*;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Makes a fresh, empty directory to put files in.  name says which tests it belongs to
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("boosttorrent-{}-{}", name, thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
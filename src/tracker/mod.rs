@@ -1,47 +1,114 @@
-use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::boostencode::{FromValue, Value};
+use futures_cpupool::CpuPool;
 use hyper;
 use hyper::{
     Client,
     http::uri::InvalidUri,
     StatusCode,
 };
-use log::trace;
-use maplit::hashmap;
+use log::{
+    debug,
+    trace,
+};
+use std::collections::HashMap;
 use percent_encoding::{
+    EncodeSet,
     percent_encode,
-    QUERY_ENCODE_SET,
 };
+use rand::prelude::*;
 use std::fmt;
+use std::io;
 use std::net::{
     IpAddr,
+    Ipv6Addr,
     SocketAddr,
+    ToSocketAddrs,
+};
+use std::sync::{
+    Arc,
+    Mutex,
 };
+use std::time::Duration;
 use tokio::prelude::{
     Async,
     Future,
     future::{
         empty,
         err,
+        lazy,
         ok,
+        result,
     },
     Stream,
 };
 
 #[cfg(test)]
 mod test;
+mod udp;
+
+/// How long to wait for a UDP tracker to answer before the first retransmission
+const UDP_TIMEOUT: Duration = Duration::from_secs(15);
+/// The longest a tracker's retry in can make us wait, in minutes
+const MAX_RETRY_IN: i64 = 7 * 24 * 60;
+
+/// Escapes every byte except the unreserved characters of RFC 3986.  Binary values like the info
+/// hash have to be encoded this way, since trackers don't agree on anything looser
+#[derive(Clone, Copy)]
+struct UnreservedEncodeSet;
+
+impl EncodeSet for UnreservedEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        !(byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+    }
+}
+
+/// Percent encodes bytes for use in a query string
+fn url_encode(bytes: &[u8]) -> String {
+    percent_encode(bytes, UnreservedEncodeSet).to_string()
+}
+
+/// Appends query parameters to a uri, which may already have a query string of its own
+fn append_query(uri: &mut String, params: &[(&str, String)]) {
+    for (key, value) in params {
+        if !uri.ends_with('?') && !uri.ends_with('&') {
+            uri.push(if uri.contains('?') { '&' } else { '?' });
+        }
+        uri.push_str(key);
+        uri.push('=');
+        uri.push_str(value);
+    }
+}
 
 
 pub struct Tracker {
     // The 20 byte unique identifier for this instance of the client
     peer_id: [u8; 20],
-    // The uri of the tracker
-    tracker_uri: String,
+    // Tiers of tracker uris, tried in order.  Within a tier, the last tracker that worked is first
+    tiers: Vec<Vec<String>>,
+    // The (tier, index) of the tracker currently being used
+    current: (usize, usize),
+    // The event and statistics of the announce in flight, to resend to the next tracker if this one
+    // fails.  Kept after every tracker has failed, so the event can be retried
+    last_announce: Option<(Option<Event>, u64, u64, u64)>,
     // The SHA1 hash of the value of the info key in the torrent file
     info_hash: [u8; 20],
     // The port we will be listening on for peer connections
     port: u16,
     // A string the client should send on subsequent announcements
     tracker_id: Option<String>,
+    // Our IPv6 address, so IPv6 peers can find us even when we announce over IPv4
+    ipv6: Option<Ipv6Addr>,
+    // The address to tell the tracker to give out, if not the one our requests come from
+    ip: Option<IpAddr>,
+    // How many peers to ask for.  The tracker picks if this is None
+    num_want: Option<u32>,
+    // A random number that stays the same for the whole session, so the tracker can recognise us if
+    // our address changes
+    key: u32,
+    // The connection id of a UDP tracker, which can be reused for a minute
+    udp_connection: udp::ConnectionCache,
+    // Looks up UDP tracker hostnames, so the blocking lookup stays off the reactor
+    resolver: CpuPool,
     // The shared state of the client
     // A future of the must recent tracker request
     request: Box<dyn Future<Item=TrackerResponse, Error=TrackerError> + Send>,
@@ -71,35 +138,83 @@ pub struct TrackerSuccessResponse {
     pub peers: Vec<PeerInfo>,
 }
 
+/// When a tracker that refused an announce wants to hear from us again, as in BEP 31
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RetryIn {
+    Minutes(u32),
+    Never,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum TrackerResponse {
-    Failure(String),
+    // The tracker refused the announce, saying why
+    Failure(String, Option<RetryIn>),
     Warning(String, TrackerSuccessResponse),
     Success(TrackerSuccessResponse),
 }
 
 
+/// Swarm statistics for one torrent
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScrapeInfo {
+    // Number of seeders
+    pub complete: u32,
+    // Number of times the download has been completed
+    pub downloaded: u32,
+    // Number of leechers
+    pub incomplete: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScrapeResponse {
+    // Statistics for each torrent, by info hash
+    pub files: HashMap<[u8; 20], ScrapeInfo>,
+}
+
 #[derive(Debug, derive_error::Error)]
 pub enum TrackerError {
     /// The uri we wanted to request was somehow invalid
     InvalidURI(InvalidUri),
     /// Could not connect to the tracker
     ConnectionError(hyper::Error),
-    /// The tracker returned a non 200 status code
-    #[error(non_std, no_from)]
-    ResponseError(u16),
-    /// The response body could not be bdecoded
-    DecodeError(DecodeError),
+    /// The tracker returned a non 200 status code.  Holds the status and the body
+    #[error(msg_embedded, non_std, no_from)]
+    ResponseError(String),
+    /// The response body could not be bdecoded.  Holds the error and the body
+    #[error(msg_embedded, non_std, no_from)]
+    DecodeError(String),
     /// The contents of the response are not correct
-    InvalidResponse,
+    #[error(msg_embedded, non_std, no_from)]
+    InvalidResponse(String),
+    /// Could not talk to a UDP tracker
+    Io(io::Error),
+    /// A UDP tracker never answered
+    Timeout,
+    /// The tracker refused the request
+    #[error(msg_embedded, non_std, no_from)]
+    Failure(String),
+    /// The tracker's announce url doesn't have a scrape url
+    ScrapeUnsupported,
 }
 
+#[derive(Debug, Clone, Copy)]
 enum Event {
     Started,
     Stopped,
     Completed,
 }
 
+impl Event {
+    /// How the event is written in a UDP announce
+    fn udp_id(&self) -> u32 {
+        match self {
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match self {
@@ -138,7 +253,7 @@ impl FromValue for PeerInfo {
     }
 }
 
-impl FromValue for TrackerResponse {
+impl FromValue for ScrapeResponse {
     type Error = String;
 
     fn from_value(val: &Value) -> Result<Self, Self::Error> {
@@ -148,8 +263,50 @@ impl FromValue for TrackerResponse {
             return Err(msg.bstring_utf8().unwrap_or("unknown failure reason".to_string()));
         };
 
-        let warning_msg = map.get("warning message".as_bytes()).and_then(Value::bstring_utf8);
+        let files = map.get("files".as_bytes()).and_then(Value::dict)
+            .ok_or("Missing key: files".to_string())?
+            .iter()
+            .map(|(info_hash, stats)| {
+                if info_hash.len() != 20 {
+                    return Err("Info hash is not 20 bytes".to_string());
+                }
+                let mut hash = [0; 20];
+                hash.copy_from_slice(info_hash);
+                let stats = stats.dict().ok_or("File stats not a dictionary".to_string())?;
+                let count = |key: &str| stats.get(key.as_bytes()).and_then(Value::integer)
+                    .map(|i| *i as u32)
+                    .ok_or(format!("Missing key: {}", key));
+                Ok((hash, ScrapeInfo {
+                    complete: count("complete")?,
+                    downloaded: count("downloaded")?,
+                    incomplete: count("incomplete")?,
+                }))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+
+        Ok(ScrapeResponse { files })
+    }
+}
 
+impl FromValue for TrackerResponse {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Not a dictionary".to_string())?;
+
+        if let Some(msg) = failure_reason(val) {
+            let retry_in = match map.get("retry in".as_bytes()) {
+                // A wait that is over before it starts means nothing, so the usual backoff applies
+                Some(Value::Integer(minutes)) if *minutes > 0 => Some(RetryIn::Minutes(i64::min(*minutes, MAX_RETRY_IN) as u32)),
+                Some(Value::BString(never)) if never.as_slice() == b"never" => Some(RetryIn::Never),
+                _ => None,
+            };
+            return Ok(TrackerResponse::Failure(msg, retry_in));
+        };
+
+        let warning_msg = map.get("warning message".as_bytes())
+            .and_then(Value::bstring)
+            .map(|msg| String::from_utf8_lossy(msg).into_owned());
 
         let interval = map.get("interval".as_bytes()).and_then(Value::integer)
             .map(|i| *i as u32)
@@ -168,27 +325,23 @@ impl FromValue for TrackerResponse {
             .map(|i| *i as u32)
             .ok_or("Missing key: incomplete".to_string())?;
 
-        let peers = match map.get("peers".as_bytes()).ok_or("Missing key: peers".to_string())? {
+        let mut peers = match map.get("peers".as_bytes()) {
             // Dictionary model
-            Value::List(peers) => peers.iter()
+            Some(Value::List(peers)) => peers.iter()
                 .map(PeerInfo::from_value)
                 .collect::<Result<Vec<_>, _>>()?,
             // Binary model
-            Value::BString(peers) => peers.chunks(6)
-                .map(|peer_slice| {
-                    // port is in big endian.  multiply instead of bitshift so you can't mess up endianness
-                    let port = (peer_slice[0] as u16 * 256) + peer_slice[1] as u16;
-                    let mut ip_bytes: [u8; 4] = [0; 4];
-                    ip_bytes.copy_from_slice(&peer_slice[..4]);
-                    let ip: IpAddr = ip_bytes.into();
-                    PeerInfo {
-                        peer_id: None,
-                        address: (ip, port).into(),
-                    }
-                })
-                .collect(),
-            _ => return Err("peers is not in the correct form".to_owned())
+            Some(Value::BString(peers)) => parse_compact_peers(peers)?,
+            Some(_) => return Err("peers is not in the correct form".to_owned()),
+            None => Vec::new(),
         };
+        // IPv6 peers only come in the binary model
+        match map.get("peers6".as_bytes()) {
+            Some(Value::BString(peers6)) => peers.append(&mut parse_compact_peers6(peers6)?),
+            Some(_) => return Err("peers6 is not in the correct form".to_owned()),
+            None if !map.contains_key("peers".as_bytes()) => return Err("Missing key: peers".to_string()),
+            None => (),
+        }
 
         let res = TrackerSuccessResponse {
             interval,
@@ -206,112 +359,380 @@ impl FromValue for TrackerResponse {
     }
 }
 
+/// The failure reason of a bencoded tracker response, if the tracker refused the request
+fn failure_reason(val: &Value) -> Option<String> {
+    val.dict()?
+        .get("failure reason".as_bytes())
+        .map(|msg| match msg.bstring() {
+            Some(msg) => String::from_utf8_lossy(msg).into_owned(),
+            None => "unknown failure reason".to_string(),
+        })
+}
+
+/// The start of a response body, for error messages.  Trackers that go wrong tend to send HTML
+fn body_text(body: &[u8]) -> String {
+    const MAX_LENGTH: usize = 200;
+    let text = String::from_utf8_lossy(body);
+    match text.char_indices().nth(MAX_LENGTH) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.into_owned(),
+    }
+}
+
+/// Makes an HTTP GET request to a tracker and bdecodes the response
+fn http_get(req_uri: String) -> impl Future<Item=Value, Error=TrackerError> {
+    let client = Client::new();
+    let uri = match hyper::http::HttpTryFrom::try_from(&req_uri) {
+        Ok(uri) => ok(uri),
+        Err(e) => err(TrackerError::InvalidURI(e))
+    };
+    // Start the tracker query future
+    uri.and_then(move |uri| {
+        client.get(uri).map_err(|e| TrackerError::ConnectionError(e))
+    }).and_then(|get_response| {
+        let status = get_response.status();
+        get_response.into_body()
+            .map(|chunk| {
+                Vec::from(&*chunk)
+            }).concat2()
+            .map_err(|e| TrackerError::ConnectionError(e))
+            .map(move |body| (status, body))
+    }).and_then(|(status, body)| {
+        match Value::decode(&body) {
+            Ok(val) => {
+                trace!("response: {:?}", val);
+                // Some trackers send their failure reason with an error status
+                if status == StatusCode::OK || failure_reason(&val).is_some() {
+                    return Ok(val);
+                }
+            }
+            Err(e) if status == StatusCode::OK => {
+                return Err(TrackerError::DecodeError(format!("{}: {}", e, body_text(&body))));
+            }
+            Err(_) => (),
+        }
+        Err(TrackerError::ResponseError(format!("{}: {}", status, body_text(&body))))
+    })
+}
+
+/// Derives the scrape url from an announce url by convention: the last path segment has to start
+/// with "announce", which is replaced with "scrape".  Trackers that don't follow this can't be
+/// scraped
+pub fn scrape_uri(announce_uri: &str) -> Option<String> {
+    let slash = announce_uri.rfind('/')?;
+    let (base, last) = announce_uri.split_at(slash + 1);
+    if !last.starts_with("announce") {
+        return None;
+    }
+    Some(format!("{}scrape{}", base, &last["announce".len()..]))
+}
+
+/// Parses the compact peer list format: 4 bytes of IPv4 address followed by a 2 byte port for each
+/// peer, all in network byte order
+pub fn parse_compact_peers(peers: &[u8]) -> Result<Vec<PeerInfo>, String> {
+    parse_compact(peers, 4)
+}
+
+/// Parses the compact IPv6 peer list format from BEP 7, which is the same but with 16 byte addresses
+pub fn parse_compact_peers6(peers: &[u8]) -> Result<Vec<PeerInfo>, String> {
+    parse_compact(peers, 16)
+}
+
+/// Writes an address in the compact format, which is 6 bytes for IPv4 and 18 for IPv6
+pub fn compact_peer(address: &SocketAddr) -> Vec<u8> {
+    let mut res = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    res.extend_from_slice(&address.port().to_be_bytes());
+    res
+}
+
+fn parse_compact(peers: &[u8], ip_length: usize) -> Result<Vec<PeerInfo>, String> {
+    let chunks = peers.chunks_exact(ip_length + 2);
+    if !chunks.remainder().is_empty() {
+        return Err(format!("Compact peer list length {} is not a multiple of {}", peers.len(), ip_length + 2));
+    }
+    Ok(chunks
+        .map(|peer_slice| {
+            let ip: IpAddr = if ip_length == 4 {
+                let mut ip_bytes: [u8; 4] = [0; 4];
+                ip_bytes.copy_from_slice(&peer_slice[..4]);
+                ip_bytes.into()
+            } else {
+                let mut ip_bytes: [u8; 16] = [0; 16];
+                ip_bytes.copy_from_slice(&peer_slice[..16]);
+                ip_bytes.into()
+            };
+            // port is in big endian.  multiply instead of bitshift so you can't mess up endianness
+            let port = (peer_slice[ip_length] as u16 * 256) + peer_slice[ip_length + 1] as u16;
+            PeerInfo {
+                peer_id: None,
+                address: (ip, port).into(),
+            }
+        })
+        .collect())
+}
+
+/// Looks up a host's first address on the resolver's thread.  IP literals are answered right away
+fn resolve(resolver: &CpuPool, host: String, port: u16) -> Box<dyn Future<Item=SocketAddr, Error=TrackerError> + Send> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Box::new(ok(SocketAddr::new(ip, port)));
+    }
+    Box::new(resolver.spawn_fn(move || {
+        (host.as_str(), port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracker host has no addresses").into())
+    }))
+}
+
 impl Tracker {
-    /// Create a new Tracker
+    /// Create a new Tracker from tiers of tracker uris, as in BEP 12.  Trackers in the same tier
+    /// are tried in a random order
     pub fn new(
         peer_id: [u8; 20],
-        tracker_uri: String,
+        mut tiers: Vec<Vec<String>>,
         info_hash: [u8; 20],
         port: u16) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in tiers.iter_mut() {
+            thread_rng().shuffle(tier);
+        }
         Tracker {
             peer_id,
-            tracker_uri,
+            tiers,
+            current: (0, 0),
+            last_announce: None,
             info_hash,
             port,
             tracker_id: None,
-            request: Box::new(err(TrackerError::InvalidResponse)),
+            ipv6: None,
+            ip: None,
+            num_want: None,
+            key: thread_rng().gen(),
+            udp_connection: Arc::new(Mutex::new(None)),
+            resolver: CpuPool::new(1),
+            request: Box::new(err(TrackerError::InvalidResponse("nothing announced yet".to_string()))),
         }
     }
 
-    /// Tell the tracker that you are starting your download
-    pub fn start(&mut self, download_size: u64) {
-        self.request = Box::new(self.announce(Some(Event::Started), download_size, 0, 0))
+    /// Sets the IPv6 address to include in announcements
+    pub fn set_ipv6(&mut self, address: Ipv6Addr) {
+        self.ipv6 = Some(address);
+    }
+
+    /// Sets the address the tracker should give to other peers, instead of the one it sees
+    pub fn set_ip(&mut self, address: IpAddr) {
+        self.ip = Some(address);
+    }
+
+    /// Sets how many peers to ask for in each announce
+    pub fn set_num_want(&mut self, num_want: u32) {
+        self.num_want = Some(num_want);
+    }
+
+    /// Tell the tracker that you are starting your download, with left bytes still to get
+    pub fn start(&mut self, left: u64) {
+        self.send(Some(Event::Started), left, 0, 0)
     }
 
     /// Tell the tracker that you are stopping your download without finishing.
     pub fn cancel(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(Some(Event::Stopped), left, uploaded, downloaded))
+        self.send(Some(Event::Stopped), left, uploaded, downloaded)
     }
 
     /// Tell the tracker that you have completed the download
     pub fn finish(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(Some(Event::Completed), left, uploaded, downloaded))
+        self.send(Some(Event::Completed), left, uploaded, downloaded)
     }
 
     /// Update the tracker on your download status, and get more peers
     pub fn refresh(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(None, left, uploaded, downloaded))
-    }
-
-    fn announce(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> impl Future<Item=TrackerResponse, Error=TrackerError> {
-        // build the tracker query string
-        let mut req_uri = self.tracker_uri.clone();
-        let encoded_info_hash = percent_encode(&self.info_hash, QUERY_ENCODE_SET).to_string();
-        let encoded_peer_id = percent_encode(&self.peer_id, QUERY_ENCODE_SET).to_string();
-        let query = hashmap! {
-            "info_hash" => encoded_info_hash,
-            "peer_id" => encoded_peer_id,
-            "port" => self.port.to_string(),
-            "uploaded" => uploaded.to_string(),
-            "downloaded" => downloaded.to_string(),
-            "left" => left.to_string(),
-            "compact" => 0.to_string(),
-        };
-        req_uri.push('?');
-        query.iter().fold(&mut req_uri, |s, (k, v)| {
-            s.push_str(k);
-            s.push('=');
-            s.push_str(&v);
-            s.push('&');
-            s
+        self.send(None, left, uploaded, downloaded)
+    }
+
+    /// Try again after every tracker failed the last announce.  Its event is sent again, since the
+    /// trackers never heard it
+    pub fn retry(&mut self, left: u64, uploaded: u64, downloaded: u64) {
+        let event = self.last_announce.and_then(|(event, _, _, _)| event);
+        self.send(event, left, uploaded, downloaded)
+    }
+
+    /// Starts an announce, beginning with the first tracker of the first tier
+    fn send(&mut self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) {
+        self.switch_tracker((0, 0));
+        self.last_announce = Some((event, left, uploaded, downloaded));
+        self.request = self.announce(event, left, uploaded, downloaded)
+    }
+
+    /// The uri of the tracker currently being used.  Empty if there are no trackers at all
+    fn tracker_uri(&self) -> &str {
+        self.tiers.get(self.current.0)
+            .and_then(|tier| tier.get(self.current.1))
+            .map_or("", |uri| uri.as_str())
+    }
+
+    /// Moves on to the tracker at position.  Anything we were told by the old one doesn't apply
+    fn switch_tracker(&mut self, position: (usize, usize)) {
+        if position != self.current {
+            self.current = position;
+            self.tracker_id = None;
+            self.udp_connection = Arc::new(Mutex::new(None));
+        }
+    }
+
+    /// The tracker after the current one: the rest of its tier, then the tiers after it
+    fn next_tracker(&self) -> Option<(usize, usize)> {
+        let (tier, index) = self.current;
+        if index + 1 < self.tiers.get(tier).map_or(0, Vec::len) {
+            Some((tier, index + 1))
+        } else if tier + 1 < self.tiers.len() {
+            Some((tier + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// Moves the current tracker to the front of its tier, since it works
+    fn promote_tracker(&mut self) {
+        let (tier, index) = self.current;
+        let uri = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, uri);
+        self.current = (tier, 0);
+    }
+
+    /// Sends an announce using whichever protocol the tracker's uri calls for
+    fn announce(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> Box<dyn Future<Item=TrackerResponse, Error=TrackerError> + Send> {
+        if self.tracker_uri().starts_with("udp://") {
+            Box::new(self.udp_announce(event, left, uploaded, downloaded))
+        } else {
+            Box::new(self.http_announce(event, left, uploaded, downloaded))
+        }
+    }
+
+    fn udp_announce(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> impl Future<Item=TrackerResponse, Error=TrackerError> {
+        let announce = udp::Request::Announce(udp::Announce {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            downloaded,
+            left,
+            uploaded,
+            event: event.map_or(0, |e| e.udp_id()),
+            // only IPv4 addresses fit, and only IPv4 trackers use it
+            ip: match self.ip {
+                Some(IpAddr::V4(ip)) => ip.into(),
+                _ => 0,
+            },
+            key: self.key,
+            num_want: self.num_want(event).map_or(-1, |n| n as i32),
+            port: self.port,
         });
+        self.udp_request(announce).and_then(|response| match response {
+            udp::Response::Announce(response) => Ok(response),
+            udp::Response::Error(msg) => Ok(TrackerResponse::Failure(msg, None)),
+            udp::Response::Scrape(_) => Err(TrackerError::InvalidResponse("scrape response to an announce".to_string())),
+        })
+    }
+
+    /// Resolves the UDP tracker's address and sends it a request.  While other trackers remain to
+    /// fall back on, an unresponsive one is given up on after a few retransmissions
+    fn udp_request(&self, request: udp::Request) -> impl Future<Item=udp::Response, Error=TrackerError> {
+        let retries = if self.next_tracker().is_some() { udp::FALLBACK_RETRIES } else { udp::MAX_RETRIES };
+        let cache = self.udp_connection.clone();
+        let resolver = self.resolver.clone();
+        let uri = self.tracker_uri().to_string();
+        lazy(move || {
+            let uri: hyper::Uri = hyper::http::HttpTryFrom::try_from(uri.as_str())?;
+            // IPv6 literals keep their brackets in the uri
+            let host = uri.host().ok_or_else(|| TrackerError::InvalidResponse("tracker uri has no host".to_string()))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let port = uri.port_u16().ok_or_else(|| TrackerError::InvalidResponse("tracker uri has no port".to_string()))?;
+            Ok::<_, TrackerError>(resolve(&resolver, host, port))
+        })
+            .flatten()
+            .and_then(move |address| result(udp::UdpRequest::new(address, cache, request, UDP_TIMEOUT, retries)).flatten())
+    }
+
+    /// How many peers to ask for.  Stopping, we don't want any
+    fn num_want(&self, event: Option<Event>) -> Option<u32> {
         match event {
-            Some(e) => {
-                req_uri.push_str("event");
-                req_uri.push('=');
-                req_uri.push_str(&e.to_string());
-                req_uri.push('&')
-            }
-            None => ()
+            Some(Event::Stopped) => Some(0),
+            _ => self.num_want,
         }
-        match &self.tracker_id {
-            Some(id) => {
-                req_uri.push_str("trackerid");
-                req_uri.push('=');
-                req_uri.push_str(&percent_encode(id.as_bytes(), QUERY_ENCODE_SET).to_string());
-                req_uri.push('&')
-            }
-            None => ()
+    }
+
+    /// Builds the uri for an HTTP announce to the current tracker
+    fn announce_uri(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> String {
+        let mut query = vec![
+            ("info_hash", url_encode(&self.info_hash)),
+            ("peer_id", url_encode(&self.peer_id)),
+            ("port", self.port.to_string()),
+            ("uploaded", uploaded.to_string()),
+            ("downloaded", downloaded.to_string()),
+            ("left", left.to_string()),
+            ("compact", 1.to_string()),
+            ("no_peer_id", 1.to_string()),
+            // we don't speak message stream encryption
+            ("supportcrypto", 0.to_string()),
+            ("key", format!("{:08x}", self.key)),
+        ];
+        if let Some(e) = event {
+            query.push(("event", e.to_string()));
         }
-        let _ = req_uri.pop();
-        let client = Client::new();
-        let uri = match hyper::http::HttpTryFrom::try_from(&req_uri) {
-            Ok(uri) => ok(uri),
-            Err(e) => err(TrackerError::InvalidURI(e))
-        };
-        // Start the tracker query future
-        uri.and_then(move |uri| {
-            client.get(uri).map_err(|e| TrackerError::ConnectionError(e))
-        }).and_then(|get_response| {
-            if get_response.status() == StatusCode::OK {
-                Ok(get_response.into_body())
-            } else {
-                Err(TrackerError::ResponseError(get_response.status().as_u16()))
-            }
-        }).and_then(|body| {
-            body.map(|chunk| {
-                Vec::from(&*chunk)
-            }).concat2()
-                .map_err(|e| TrackerError::ConnectionError(e))
-        }).and_then(|resp_bytes| {
-            Value::decode(&resp_bytes).map_err(|e| TrackerError::DecodeError(e))
-        }).and_then(|val| {
-            trace!("response: {:?}", val);
+        if let Some(num_want) = self.num_want(event) {
+            query.push(("numwant", num_want.to_string()));
+        }
+        if let Some(address) = &self.ip {
+            query.push(("ip", url_encode(address.to_string().as_bytes())));
+        }
+        if let Some(address) = &self.ipv6 {
+            query.push(("ipv6", url_encode(address.to_string().as_bytes())));
+        }
+        if let Some(id) = &self.tracker_id {
+            query.push(("trackerid", url_encode(id.as_bytes())));
+        }
+        let mut req_uri = self.tracker_uri().to_string();
+        append_query(&mut req_uri, &query);
+        req_uri
+    }
+
+    fn http_announce(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> impl Future<Item=TrackerResponse, Error=TrackerError> {
+        http_get(self.announce_uri(event, left, uploaded, downloaded)).and_then(|val| {
             TrackerResponse::from_value(&val)
-                .map_err(|_| TrackerError::InvalidResponse)
+                .map_err(TrackerError::InvalidResponse)
         })
     }
 
+    /// Asks the tracker for the swarm statistics of each torrent, without announcing
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Box<dyn Future<Item=ScrapeResponse, Error=TrackerError> + Send> {
+        if self.tracker_uri().starts_with("udp://") {
+            let request = udp::Request::Scrape(info_hashes.to_vec());
+            return Box::new(self.udp_request(request).and_then(|response| match response {
+                udp::Response::Scrape(response) => Ok(response),
+                udp::Response::Error(msg) => Err(TrackerError::Failure(msg)),
+                udp::Response::Announce(_) => Err(TrackerError::InvalidResponse("announce response to a scrape".to_string())),
+            }));
+        }
+
+        let mut req_uri = match scrape_uri(self.tracker_uri()) {
+            Some(uri) => uri,
+            None => return Box::new(err(TrackerError::ScrapeUnsupported)),
+        };
+        let query: Vec<_> = info_hashes.iter()
+            .map(|info_hash| ("info_hash", url_encode(info_hash)))
+            .collect();
+        append_query(&mut req_uri, &query);
+        Box::new(http_get(req_uri).and_then(|val| {
+            if let Some(msg) = failure_reason(&val) {
+                return Err(TrackerError::Failure(msg));
+            }
+            ScrapeResponse::from_value(&val)
+                .map_err(TrackerError::InvalidResponse)
+        }))
+    }
+
     /// Updates the tracker id based on a tracker response
     fn update_tracker_id(&mut self, response: &TrackerResponse) {
         match response {
@@ -331,15 +752,42 @@ impl Future for Tracker {
     type Error = TrackerError;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let poll = self.request.poll();
-        // if ready, update the tracker id to the response value, and set it up so that subsequent
-        // polls will return not ready
-        if let Ok(Async::Ready(res)) = poll {
-            self.update_tracker_id(&res);
-            self.request = Box::new(empty());
-            Ok(Async::Ready(res))
-        } else {
-            poll
+        loop {
+            match self.request.poll() {
+                // if ready, update the tracker id to the response value, and set it up so that
+                // subsequent polls will return not ready
+                Ok(Async::Ready(res)) => {
+                    if let TrackerResponse::Failure(msg, _) = &res {
+                        // a refusal is only final once every tracker has refused
+                        if let (Some(next), Some((event, left, uploaded, downloaded))) = (self.next_tracker(), self.last_announce) {
+                            debug!("Tracker {} refused the announce: {}", self.tracker_uri(), msg);
+                            self.switch_tracker(next);
+                            self.request = self.announce(event, left, uploaded, downloaded);
+                            continue;
+                        }
+                        // the refused announce is kept so it can be retried
+                    } else {
+                        self.last_announce = None;
+                        self.promote_tracker();
+                        self.update_tracker_id(&res);
+                    }
+                    self.request = Box::new(empty());
+                    return Ok(Async::Ready(res));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // fall back to the next tracker, and only give up once every one has failed
+                Err(e) => match (self.next_tracker(), self.last_announce) {
+                    (Some(next), Some((event, left, uploaded, downloaded))) => {
+                        debug!("Tracker {} failed: {}", self.tracker_uri(), e);
+                        self.switch_tracker(next);
+                        self.request = self.announce(event, left, uploaded, downloaded);
+                    }
+                    _ => {
+                        self.request = Box::new(empty());
+                        return Err(e);
+                    }
+                }
+            }
         }
     }
 }
\ No newline at end of file
@@ -6,11 +6,13 @@ use hyper::{
     server::Server,
 };
 use maplit::hashmap;
-use std::net::IpAddr;
+use byteorder::{ByteOrder, NetworkEndian};
+use std::net::{IpAddr, UdpSocket};
 use std::sync::{
     Arc,
     RwLock,
 };
+use std::thread::{self, JoinHandle};
 use super::*;
 use tokio;
 
@@ -48,7 +50,7 @@ fn test_announce() {
 
     let mut tracker = Tracker::new(
         [0; 20],
-        "http://localhost:8888".to_owned(),
+        vec![vec!["http://localhost:8888".to_owned()]],
         [0; 20],
         8888);
     tracker.start(1000);
@@ -69,4 +71,373 @@ fn test_announce() {
     ));
 
     runtime.shutdown_now();
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_compact_peers() {
+    let response = |peers: Vec<u8>| Value::Dict(hashmap! {
+        Vec::from("interval") => Value::Integer(10),
+        Vec::from("complete") => Value::Integer(1),
+        Vec::from("incomplete") => Value::Integer(2),
+        Vec::from("peers") => Value::BString(peers),
+    });
+
+    let parsed = TrackerResponse::from_value(&response(vec![10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80]));
+    assert_eq!(parsed, Ok(TrackerResponse::Success(TrackerSuccessResponse {
+        interval: 10,
+        min_interval: None,
+        tracker_id: None,
+        complete: 1,
+        incomplete: 2,
+        peers: vec![
+            PeerInfo { peer_id: None, address: ([10, 0, 0, 1], 6881).into() },
+            PeerInfo { peer_id: None, address: ([192, 168, 1, 2], 80).into() },
+        ],
+    })));
+
+    // a peer that is cut off part way through
+    assert!(TrackerResponse::from_value(&response(vec![10, 0, 0, 1, 0x1a, 0xe1, 192, 168])).is_err());
+}
+
+#[test]
+fn test_failure_response() {
+    let failure = |retry_in: Option<Value>| {
+        let mut map = hashmap! { Vec::from("failure reason") => Value::BString(Vec::from("unregistered torrent")) };
+        if let Some(retry_in) = retry_in {
+            map.insert(Vec::from("retry in"), retry_in);
+        }
+        TrackerResponse::from_value(&Value::Dict(map))
+    };
+    assert_eq!(failure(None), Ok(TrackerResponse::Failure("unregistered torrent".to_string(), None)));
+    assert_eq!(failure(Some(Value::Integer(30))),
+               Ok(TrackerResponse::Failure("unregistered torrent".to_string(), Some(RetryIn::Minutes(30)))));
+    assert_eq!(failure(Some(Value::BString(Vec::from("never")))),
+               Ok(TrackerResponse::Failure("unregistered torrent".to_string(), Some(RetryIn::Never))));
+    assert_eq!(failure(Some(Value::Integer(0))), Ok(TrackerResponse::Failure("unregistered torrent".to_string(), None)));
+    assert_eq!(failure(Some(Value::Integer(-5))), Ok(TrackerResponse::Failure("unregistered torrent".to_string(), None)));
+    assert_eq!(failure(Some(Value::Integer(1 << 40))),
+               Ok(TrackerResponse::Failure("unregistered torrent".to_string(), Some(RetryIn::Minutes(MAX_RETRY_IN as u32)))));
+
+    // the message doesn't have to be valid UTF-8 to be shown
+    let latin1 = Value::Dict(hashmap! { Vec::from("failure reason") => Value::BString(vec![b'n', 0xe9, b'e']) });
+    assert_eq!(TrackerResponse::from_value(&latin1), Ok(TrackerResponse::Failure("n\u{fffd}e".to_string(), None)));
+}
+
+#[test]
+fn test_body_text() {
+    assert_eq!(body_text(b"<html>Not Found</html>"), "<html>Not Found</html>");
+    let long = body_text(&[b'x'; 500]);
+    assert_eq!(long.len(), 203);
+    assert!(long.ends_with("..."));
+}
+
+#[test]
+fn test_compact_peers6() {
+    let mut peers6 = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1];
+    let response = |peers6: Vec<u8>| Value::Dict(hashmap! {
+        Vec::from("interval") => Value::Integer(10),
+        Vec::from("complete") => Value::Integer(1),
+        Vec::from("incomplete") => Value::Integer(2),
+        Vec::from("peers") => Value::BString(vec![10, 0, 0, 1, 0, 80]),
+        Vec::from("peers6") => Value::BString(peers6),
+    });
+
+    let peers = match TrackerResponse::from_value(&response(peers6.clone())) {
+        Ok(TrackerResponse::Success(resp)) => resp.peers,
+        other => panic!("expected a successful response, got {:?}", other),
+    };
+    assert_eq!(peers, vec![
+        PeerInfo { peer_id: None, address: ([10, 0, 0, 1], 80).into() },
+        PeerInfo { peer_id: None, address: ("2001:db8::1".parse::<IpAddr>().unwrap(), 6881).into() },
+    ]);
+
+    peers6.pop();
+    assert!(TrackerResponse::from_value(&response(peers6)).is_err());
+}
+
+/// A UDP tracker stand-in that answers requests with handler, skipping those it returns None for.
+/// Hands back every request it received once it has seen count of them
+fn udp_tracker<F>(count: usize, mut handler: F) -> (SocketAddr, JoinHandle<Vec<Vec<u8>>>)
+    where F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        let mut buf = [0; 2048];
+        while requests.len() < count {
+            let (length, from) = socket.recv_from(&mut buf).unwrap();
+            let request = buf[..length].to_vec();
+            if let Some(response) = handler(&request) {
+                socket.send_to(&response, from).unwrap();
+            }
+            requests.push(request);
+        }
+        requests
+    });
+    (address, handle)
+}
+
+/// Echoes the transaction id of a request after the action
+fn udp_response(action: u32, request: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut response = vec![0; 4];
+    NetworkEndian::write_u32(&mut response, action);
+    response.extend_from_slice(&request[12..16]);
+    response.extend_from_slice(body);
+    response
+}
+
+#[test]
+fn test_udp_announce() {
+    let (address, handle) = udp_tracker(3, |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            0 => Some(udp_response(0, request, &[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1])),
+            1 => Some(udp_response(1, request, &[0, 0, 7, 8, 0, 0, 0, 2, 0, 0, 0, 3, 10, 0, 0, 1, 0x1a, 0xe1])),
+            _ => None,
+        }
+    });
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let mut tracker = Tracker::new([2; 20], vec![vec![format!("udp://{}/announce", address)]], [1; 20], 6881);
+    tracker.set_num_want(30);
+
+    tracker.start(1000);
+    let response = runtime.block_on(&mut tracker).expect("start should not return error");
+    assert_eq!(response, TrackerResponse::Success(TrackerSuccessResponse {
+        interval: 1800,
+        min_interval: None,
+        tracker_id: None,
+        complete: 3,
+        incomplete: 2,
+        peers: vec![PeerInfo { peer_id: None, address: ([10, 0, 0, 1], 6881).into() }],
+    }));
+    // the connection id is reused for the next announce
+    tracker.refresh(0, 0, 1000);
+    runtime.block_on(&mut tracker).expect("refresh should not return error");
+
+    let requests = handle.join().unwrap();
+    assert_eq!(NetworkEndian::read_u64(&requests[0][0..8]), 0x417_2710_1980);
+    assert_eq!(NetworkEndian::read_u32(&requests[0][8..12]), 0);
+    let start = &requests[1];
+    assert_eq!(start.len(), 98);
+    assert_eq!(NetworkEndian::read_u64(&start[0..8]), 0xdead_beef_0000_0001);
+    assert_eq!(&start[16..36], &[1; 20]);
+    assert_eq!(&start[36..56], &[2; 20]);
+    assert_eq!(NetworkEndian::read_u64(&start[64..72]), 1000);
+    assert_eq!(NetworkEndian::read_u32(&start[80..84]), 2);
+    assert_eq!(NetworkEndian::read_u32(&start[88..92]), tracker.key);
+    assert_eq!(NetworkEndian::read_i32(&start[92..96]), 30);
+    assert_eq!(NetworkEndian::read_u16(&start[96..98]), 6881);
+    let refresh = &requests[2];
+    assert_eq!(NetworkEndian::read_u32(&refresh[8..12]), 1);
+    assert_eq!(NetworkEndian::read_u64(&refresh[56..64]), 1000);
+    assert_eq!(NetworkEndian::read_u32(&refresh[80..84]), 0);
+}
+
+#[test]
+fn test_udp_retransmit_and_error() {
+    let mut connects = 0;
+    let (address, handle) = udp_tracker(3, move |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            // drop the first connect on the floor
+            0 if connects == 0 => {
+                connects += 1;
+                None
+            }
+            0 => Some(udp_response(0, request, &[0, 0, 0, 0, 0, 0, 0, 9])),
+            _ => Some(udp_response(3, request, b"torrent not registered")),
+        }
+    });
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let announce = udp::Request::Announce(udp::Announce {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        downloaded: 0,
+        left: 0,
+        uploaded: 0,
+        event: 0,
+        ip: 0,
+        key: 0,
+        num_want: -1,
+        port: 6881,
+    });
+    let request = udp::UdpRequest::new(address, Arc::new(Mutex::new(None)), announce, Duration::from_millis(50), udp::MAX_RETRIES).unwrap();
+
+    let response = runtime.block_on(request).expect("announce should not return error");
+    assert_eq!(response, udp::Response::Error("torrent not registered".to_string()));
+    let requests = handle.join().unwrap();
+    // the retransmitted connect uses a fresh transaction id
+    assert_ne!(&requests[0][12..16], &requests[1][12..16]);
+}
+
+#[test]
+fn test_udp_gives_up_after_retries() {
+    // a tracker that never answers
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = silent.local_addr().unwrap();
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let request = udp::UdpRequest::new(address, Arc::new(Mutex::new(None)), udp::Request::Scrape(vec![[1; 20]]), Duration::from_millis(20), 1).unwrap();
+
+    match runtime.block_on(request) {
+        Err(TrackerError::Timeout) => {}
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    silent.set_nonblocking(true).unwrap();
+    let mut buf = [0; 1024];
+    let mut sent = 0;
+    while silent.recv_from(&mut buf).is_ok() {
+        sent += 1;
+    }
+    // the first connect and one retransmission
+    assert_eq!(sent, 2);
+}
+
+#[test]
+fn test_scrape_uri() {
+    assert_eq!(scrape_uri("http://example.com/announce"), Some("http://example.com/scrape".to_string()));
+    assert_eq!(scrape_uri("http://example.com/x/announce.php?key=1"), Some("http://example.com/x/scrape.php?key=1".to_string()));
+    assert_eq!(scrape_uri("http://example.com/a"), None);
+    assert_eq!(scrape_uri("http://example.com/announce/x"), None);
+}
+
+#[test]
+fn test_announce_uri() {
+    let mut info_hash = [b'a'; 20];
+    info_hash[..6].copy_from_slice(&[0x00, b'~', b'.', b' ', b'/', 0xff]);
+    let mut tracker = Tracker::new([b'-'; 20], vec![vec!["http://example.com/announce?passkey=abc".to_string()]], info_hash, 6881);
+    tracker.set_num_want(50);
+    tracker.set_ip([10, 0, 0, 1].into());
+
+    let uri = tracker.announce_uri(Some(Event::Started), 1000, 0, 0);
+    let (base, query) = uri.split_at(uri.find('&').unwrap());
+    assert_eq!(base, "http://example.com/announce?passkey=abc");
+    let params: Vec<&str> = query[1..].split('&').collect();
+    assert_eq!(params[0], "info_hash=%00~.%20%2F%FFaaaaaaaaaaaaaa");
+    assert_eq!(params[1], format!("peer_id={}", "-".repeat(20)));
+    for param in &["compact=1", "no_peer_id=1", "numwant=50", "event=started", "ip=10.0.0.1", "left=1000"] {
+        assert!(params.contains(param), "missing {}", param);
+    }
+    let key = format!("key={:08x}", tracker.key);
+    assert!(params.contains(&key.as_str()));
+
+    // stopping, we don't want any peers
+    let uri = tracker.announce_uri(Some(Event::Stopped), 1000, 0, 0);
+    assert!(uri.contains("&numwant=0"));
+
+    let tracker = Tracker::new([0; 20], vec![vec!["http://example.com/announce".to_string()]], [0; 20], 6881);
+    assert!(tracker.announce_uri(None, 0, 0, 0).starts_with("http://example.com/announce?info_hash="));
+}
+
+#[test]
+fn test_scrape_response() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("files") => Value::Dict(hashmap! {
+            vec![7; 20] => Value::Dict(hashmap! {
+                Vec::from("complete") => Value::Integer(5),
+                Vec::from("downloaded") => Value::Integer(50),
+                Vec::from("incomplete") => Value::Integer(10),
+            }),
+        }),
+    });
+    assert_eq!(ScrapeResponse::from_value(&val), Ok(ScrapeResponse {
+        files: hashmap! { [7; 20] => ScrapeInfo { complete: 5, downloaded: 50, incomplete: 10 } },
+    }));
+
+    let bad_hash = Value::Dict(hashmap! {
+        Vec::from("files") => Value::Dict(hashmap! { vec![7; 3] => Value::Dict(HashMap::new()) }),
+    });
+    assert!(ScrapeResponse::from_value(&bad_hash).is_err());
+}
+
+#[test]
+fn test_udp_scrape() {
+    let (address, handle) = udp_tracker(2, |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            0 => Some(udp_response(0, request, &[0, 0, 0, 0, 0, 0, 0, 1])),
+            2 => Some(udp_response(2, request, &[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0, 6])),
+            _ => None,
+        }
+    });
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let tracker = Tracker::new([2; 20], vec![vec![format!("udp://{}", address)]], [1; 20], 6881);
+
+    let response = runtime.block_on(tracker.scrape(&[[1; 20], [9; 20]])).expect("scrape should not return error");
+    assert_eq!(response, ScrapeResponse {
+        files: hashmap! {
+            [1; 20] => ScrapeInfo { complete: 1, downloaded: 2, incomplete: 3 },
+            [9; 20] => ScrapeInfo { complete: 4, downloaded: 5, incomplete: 6 },
+        },
+    });
+    let requests = handle.join().unwrap();
+    assert_eq!(&requests[1][16..36], &[1; 20]);
+    assert_eq!(&requests[1][36..56], &[9; 20]);
+}
+
+#[test]
+fn test_tracker_tiers() {
+    let (address, handle) = udp_tracker(2, |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            0 => Some(udp_response(0, request, &[0, 0, 0, 0, 0, 0, 0, 1])),
+            1 => Some(udp_response(1, request, &[0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0])),
+            _ => None,
+        }
+    });
+    let working = format!("udp://{}", address);
+    // nothing listens on port 1, so these fail straight away
+    let broken = "http://127.0.0.1:1/announce".to_string();
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let mut tracker = Tracker::new([2; 20], vec![
+        vec![broken.clone()],
+        vec![broken.clone(), working.clone()],
+    ], [1; 20], 6881);
+
+    tracker.start(1000);
+    runtime.block_on(&mut tracker).expect("the second tier should answer");
+    handle.join().unwrap();
+    // the tracker that answered is tried first within its tier from now on
+    assert_eq!(tracker.tiers[1], vec![working, broken]);
+}
+
+#[test]
+fn test_tracker_refusal_falls_back() {
+    let (refusing, refusing_handle) = udp_tracker(4, |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            0 => Some(udp_response(0, request, &[0, 0, 0, 0, 0, 0, 0, 1])),
+            _ => Some(udp_response(3, request, b"torrent not registered")),
+        }
+    });
+    let (working, working_handle) = udp_tracker(2, |request| {
+        match NetworkEndian::read_u32(&request[8..12]) {
+            0 => Some(udp_response(0, request, &[0, 0, 0, 0, 0, 0, 0, 2])),
+            1 => Some(udp_response(1, request, &[0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0])),
+            _ => None,
+        }
+    });
+    let refusing = format!("udp://{}", refusing);
+    let working = format!("udp://{}", working);
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let mut tracker = Tracker::new([2; 20], vec![vec![refusing.clone()], vec![working.clone()]], [1; 20], 6881);
+
+    // a tracker refusing us is a reason to ask the next one
+    tracker.start(1000);
+    match runtime.block_on(&mut tracker) {
+        Ok(TrackerResponse::Success(_)) => (),
+        other => panic!("expected the second tier to answer, got {:?}", other),
+    }
+    working_handle.join().unwrap();
+    assert_eq!(tracker.current, (1, 0));
+
+    // but once every tracker has refused, the refusal is what we get
+    let mut tracker = Tracker::new([2; 20], vec![vec![refusing]], [1; 20], 6881);
+    tracker.start(1000);
+    assert_eq!(runtime.block_on(&mut tracker).unwrap(),
+               TrackerResponse::Failure("torrent not registered".to_string(), None));
+    refusing_handle.join().unwrap();
+}
+
+#[test]
+fn test_resolve() {
+    let resolver = CpuPool::new(1);
+    assert_eq!(resolve(&resolver, "10.0.0.1".to_string(), 80).wait().unwrap(), ([10, 0, 0, 1], 80).into());
+    assert_eq!(resolve(&resolver, "::1".to_string(), 80).wait().unwrap(), "[::1]:80".parse().unwrap());
+    // hostnames are looked up on the resolver's thread
+    assert!(resolve(&resolver, "localhost".to_string(), 80).wait().unwrap().ip().is_loopback());
+}
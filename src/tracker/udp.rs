@@ -0,0 +1,271 @@
+//! The UDP tracker protocol from BEP 15
+use byteorder::{ByteOrder, NetworkEndian};
+use bytes::{BufMut, BytesMut};
+use futures::try_ready;
+use rand::prelude::*;
+use std::collections::HashMap;
+use std::net::{
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use super::{
+    parse_compact_peers,
+    parse_compact_peers6,
+    ScrapeInfo,
+    ScrapeResponse,
+    TrackerError,
+    TrackerResponse,
+    TrackerSuccessResponse,
+};
+use tokio::net::UdpSocket;
+use tokio::prelude::{
+    Async,
+    Future,
+};
+use tokio::timer::Delay;
+
+/// Magic number that starts every connect request
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+/// How long a connection id may be used for after it was handed out
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Requests are retransmitted this many times before giving up, the full schedule in the spec
+pub const MAX_RETRIES: u32 = 8;
+/// Retransmissions before giving up when another tracker could be asked instead.  The full
+/// schedule waits over an hour
+pub const FALLBACK_RETRIES: u32 = 2;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection id and when it stops being valid.  Shared between announces to the same tracker
+pub(super) type ConnectionCache = Arc<Mutex<Option<(u64, Instant)>>>;
+
+/// The contents of an announce request
+pub(super) struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    // 0: none, 1: completed, 2: started, 3: stopped
+    pub event: u32,
+    // IPv4 address for the tracker to hand out, or 0 to use the one the packet came from
+    pub ip: u32,
+    pub key: u32,
+    pub num_want: i32,
+    pub port: u16,
+}
+
+/// What to ask the tracker once we are connected
+pub(super) enum Request {
+    Announce(Announce),
+    Scrape(Vec<[u8; 20]>),
+}
+
+/// What the tracker answered with
+#[derive(Debug, PartialEq)]
+pub(super) enum Response {
+    Announce(TrackerResponse),
+    Scrape(ScrapeResponse),
+    /// The tracker refused the request, with a message saying why
+    Error(String),
+}
+
+enum State {
+    Connecting,
+    Requesting(u64),
+}
+
+/// A single request to a UDP tracker, connecting first if we don't have a valid connection id
+pub(super) struct UdpRequest {
+    socket: UdpSocket,
+    tracker: SocketAddr,
+    cache: ConnectionCache,
+    request: Request,
+    state: State,
+    transaction_id: u32,
+    // Whether the request for the current state has been sent
+    sent: bool,
+    // Retransmissions so far.  Each waits 2^attempt times longer than the first
+    attempt: u32,
+    // Retransmissions allowed before giving up
+    retries: u32,
+    timeout: Duration,
+    deadline: Delay,
+}
+
+impl UdpRequest {
+    /// Starts a request to the tracker at address.  timeout is how long to wait for the first
+    /// response, 15 seconds in the spec, and retries how many times to retransmit before giving up
+    pub fn new(tracker: SocketAddr, cache: ConnectionCache, request: Request, timeout: Duration, retries: u32) -> Result<Self, TrackerError> {
+        let local: SocketAddr = match tracker {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(&local)?;
+        let state = match *cache.lock().unwrap() {
+            Some((id, expires)) if expires > Instant::now() => State::Requesting(id),
+            _ => State::Connecting,
+        };
+        Ok(UdpRequest {
+            socket,
+            tracker,
+            cache,
+            request,
+            state,
+            transaction_id: thread_rng().gen(),
+            sent: false,
+            attempt: 0,
+            retries,
+            timeout,
+            deadline: Delay::new(Instant::now()),
+        })
+    }
+
+    /// The request for the current state
+    fn packet(&self) -> BytesMut {
+        match self.state {
+            State::Connecting => {
+                let mut packet = BytesMut::with_capacity(16);
+                packet.put_u64_be(PROTOCOL_ID);
+                packet.put_u32_be(ACTION_CONNECT);
+                packet.put_u32_be(self.transaction_id);
+                packet
+            }
+            State::Requesting(connection_id) => match &self.request {
+                Request::Announce(announce) => {
+                    let mut packet = BytesMut::with_capacity(98);
+                    packet.put_u64_be(connection_id);
+                    packet.put_u32_be(ACTION_ANNOUNCE);
+                    packet.put_u32_be(self.transaction_id);
+                    packet.put_slice(&announce.info_hash);
+                    packet.put_slice(&announce.peer_id);
+                    packet.put_u64_be(announce.downloaded);
+                    packet.put_u64_be(announce.left);
+                    packet.put_u64_be(announce.uploaded);
+                    packet.put_u32_be(announce.event);
+                    packet.put_u32_be(announce.ip);
+                    packet.put_u32_be(announce.key);
+                    packet.put_i32_be(announce.num_want);
+                    packet.put_u16_be(announce.port);
+                    packet
+                }
+                Request::Scrape(info_hashes) => {
+                    let mut packet = BytesMut::with_capacity(16 + 20 * info_hashes.len());
+                    packet.put_u64_be(connection_id);
+                    packet.put_u32_be(ACTION_SCRAPE);
+                    packet.put_u32_be(self.transaction_id);
+                    for info_hash in info_hashes {
+                        packet.put_slice(info_hash);
+                    }
+                    packet
+                }
+            }
+        }
+    }
+
+    /// Handles a datagram from the tracker.  Returns a response once the request is done
+    fn handle_response(&mut self, response: &[u8]) -> Result<Option<Response>, TrackerError> {
+        if response.len() < 8 || NetworkEndian::read_u32(&response[4..8]) != self.transaction_id {
+            // a late answer to an earlier attempt, or not from a tracker at all
+            return Ok(None);
+        }
+        match (NetworkEndian::read_u32(&response[0..4]), &self.state, &self.request) {
+            (ACTION_ERROR, _, _) => {
+                let msg = String::from_utf8_lossy(&response[8..]).into_owned();
+                Ok(Some(Response::Error(msg)))
+            }
+            (ACTION_CONNECT, State::Connecting, _) if response.len() >= 16 => {
+                let connection_id = NetworkEndian::read_u64(&response[8..16]);
+                *self.cache.lock().unwrap() = Some((connection_id, Instant::now() + CONNECTION_ID_LIFETIME));
+                self.state = State::Requesting(connection_id);
+                self.transaction_id = thread_rng().gen();
+                self.sent = false;
+                Ok(None)
+            }
+            (ACTION_ANNOUNCE, State::Requesting(_), Request::Announce(_)) if response.len() >= 20 => {
+                let peers = match self.tracker {
+                    SocketAddr::V4(_) => parse_compact_peers(&response[20..]),
+                    SocketAddr::V6(_) => parse_compact_peers6(&response[20..]),
+                }.map_err(TrackerError::InvalidResponse)?;
+                Ok(Some(Response::Announce(TrackerResponse::Success(TrackerSuccessResponse {
+                    interval: NetworkEndian::read_u32(&response[8..12]),
+                    min_interval: None,
+                    tracker_id: None,
+                    incomplete: NetworkEndian::read_u32(&response[12..16]),
+                    complete: NetworkEndian::read_u32(&response[16..20]),
+                    peers,
+                }))))
+            }
+            // Counts come back in the same order the hashes were asked for
+            (ACTION_SCRAPE, State::Requesting(_), Request::Scrape(info_hashes))
+                if response.len() == 8 + 12 * info_hashes.len() => {
+                let files = info_hashes.iter()
+                    .zip(response[8..].chunks(12))
+                    .map(|(info_hash, counts)| (*info_hash, ScrapeInfo {
+                        complete: NetworkEndian::read_u32(&counts[0..4]),
+                        downloaded: NetworkEndian::read_u32(&counts[4..8]),
+                        incomplete: NetworkEndian::read_u32(&counts[8..12]),
+                    }))
+                    .collect::<HashMap<_, _>>();
+                Ok(Some(Response::Scrape(ScrapeResponse { files })))
+            }
+            _ => Err(TrackerError::InvalidResponse("unexpected UDP tracker response".to_string())),
+        }
+    }
+}
+
+impl Future for UdpRequest {
+    type Item = Response;
+    type Error = TrackerError;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            if !self.sent {
+                let packet = self.packet();
+                try_ready!(self.socket.poll_send_to(&packet, &self.tracker));
+                self.sent = true;
+                self.deadline = Delay::new(Instant::now() + self.timeout * 2u32.pow(self.attempt));
+            }
+
+            let mut buf = [0; 2048];
+            if let Async::Ready((length, from)) = self.socket.poll_recv_from(&mut buf)? {
+                if from == self.tracker {
+                    if let Some(response) = self.handle_response(&buf[..length])? {
+                        return Ok(Async::Ready(response));
+                    }
+                }
+                continue;
+            }
+
+            try_ready!(self.deadline.poll().map_err(|_| TrackerError::Timeout));
+            self.attempt += 1;
+            if self.attempt > self.retries {
+                return Err(TrackerError::Timeout);
+            }
+            // The connection id may have run out while we were waiting
+            if let State::Requesting(_) = self.state {
+                let expired = match *self.cache.lock().unwrap() {
+                    Some((_, expires)) => expires <= Instant::now(),
+                    None => true,
+                };
+                if expired {
+                    self.state = State::Connecting;
+                }
+            }
+            self.transaction_id = thread_rng().gen();
+            self.sent = false;
+        }
+    }
+}